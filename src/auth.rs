@@ -0,0 +1,132 @@
+// Stand-in for an external authentication service, used to stop clients
+// from submitting more than one report to a collection.
+//
+// Before submitting, a client asks the service for a token. The service
+// authenticates the client (here, by name only) and hands out at most one
+// token per client and collection. A token binds a fresh random nonce to
+// the collection under a key that the service shares with both servers.
+// The client uses the nonce as its ClientId, so a server that sees the
+// same nonce twice knows that the second report is a replay, and a client
+// that wants to submit again needs a second token, which it cannot get.
+//
+// The tag is AES CBC-MAC over the two blocks (nonce, collection). Since
+// every message has the same length, this is a secure MAC.
+
+use crate::collect::ClientId;
+
+use aes::block_cipher::{generic_array::GenericArray, BlockCipher, NewBlockCipher};
+use aes::Aes128;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// XXX Stand-in for the key that the authentication service shares with
+// the servers.
+pub const STAND_IN_KEY: [u8; 16] = [2u8; 16];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    pub id: ClientId,
+    tag: [u8; 16],
+}
+
+fn compute_tag(aes: &Aes128, collection: u64, id: ClientId) -> [u8; 16] {
+    let mut block = GenericArray::clone_from_slice(&id.0.to_le_bytes());
+    aes.encrypt_block(&mut block);
+
+    for (b, c) in block.iter_mut().zip(collection.to_le_bytes().iter()) {
+        *b ^= c;
+    }
+    aes.encrypt_block(&mut block);
+
+    let mut tag = [0u8; 16];
+    tag.copy_from_slice(&block);
+    tag
+}
+
+pub struct Authority {
+    aes: Aes128,
+    issued: Mutex<HashSet<(u64, String)>>,
+}
+
+impl Authority {
+    pub fn new(key: &[u8; 16]) -> Authority {
+        Authority {
+            aes: Aes128::new(GenericArray::from_slice(key)),
+            issued: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Returns a token for this client to submit to the collection, or
+    // None if the client already got one.
+    pub fn issue(&self, collection: u64, client: &str) -> Option<Token> {
+        let mut issued = self.issued.lock().unwrap();
+        if !issued.insert((collection, client.to_string())) {
+            return None;
+        }
+
+        let id = ClientId::random();
+        Some(Token {
+            id,
+            tag: compute_tag(&self.aes, collection, id),
+        })
+    }
+}
+
+// Checks tokens for a single collection.
+pub struct Verifier {
+    aes: Aes128,
+    collection: u64,
+}
+
+impl Verifier {
+    pub fn new(key: &[u8; 16], collection: u64) -> Verifier {
+        Verifier {
+            aes: Aes128::new(GenericArray::from_slice(key)),
+            collection,
+        }
+    }
+
+    pub fn verify(&self, token: &Token) -> bool {
+        let tag = compute_tag(&self.aes, self.collection, token.id);
+
+        // Compare in constant time.
+        let diff = tag
+            .iter()
+            .zip(token.tag.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        diff == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_token_per_client() {
+        let auth = Authority::new(&STAND_IN_KEY);
+        assert!(auth.issue(1, "alice").is_some());
+        assert!(auth.issue(1, "alice").is_none());
+        assert!(auth.issue(1, "bob").is_some());
+        assert!(auth.issue(2, "alice").is_some());
+    }
+
+    #[test]
+    fn verify() {
+        let auth = Authority::new(&STAND_IN_KEY);
+        let token = auth.issue(7, "alice").unwrap();
+
+        assert!(Verifier::new(&STAND_IN_KEY, 7).verify(&token));
+        assert!(!Verifier::new(&STAND_IN_KEY, 8).verify(&token));
+        assert!(!Verifier::new(&[3u8; 16], 7).verify(&token));
+
+        let mut forged = token;
+        forged.id = ClientId::random();
+        assert!(!Verifier::new(&STAND_IN_KEY, 7).verify(&forged));
+
+        let mut forged = token;
+        forged.tag[0] ^= 1;
+        assert!(!Verifier::new(&STAND_IN_KEY, 7).verify(&forged));
+    }
+}
@@ -0,0 +1,85 @@
+// Stand-in for a trusted dealer, which runs the offline phase: it
+// generates Beaver triples and sends each server its shares, MACed under
+// the secret in the leader's config. It must not collude with either
+// server or with the leader.
+
+use counttree::{
+    config,
+    config::FieldType,
+    fastfield::FE,
+    mpc, rpc,
+    rpc::{AddTriplesRequest, Payload, RpcField, TripleCountRequest},
+    FieldElm127, FieldElm255,
+};
+
+use futures::try_join;
+use std::io;
+
+// Number of triples to send per RPC.
+const BATCH_SIZE: u64 = 10000;
+
+async fn deal<F: RpcField>(
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    key: &mpc::DealerKey,
+    field: FieldType,
+    n: u64,
+) -> io::Result<()> {
+    let key = key.for_field(field);
+
+    // Continue numbering from wherever earlier runs left off.
+    let response0 = client0.triple_count(TripleCountRequest { field });
+    let response1 = client1.triple_count(TripleCountRequest { field });
+    let (count0, count1) = try_join!(response0, response1)?;
+    assert_eq!(
+        count0.dealt, count1.dealt,
+        "Servers hold different numbers of {:?} triples",
+        field
+    );
+
+    let mut first = count0.dealt;
+    let end = first + n;
+    while first < end {
+        let this_batch = std::cmp::min(BATCH_SIZE, end - first);
+        let [t0, t1] = mpc::deal_triples::<F>(&key, first, this_batch as usize);
+
+        let req0 = AddTriplesRequest { field, first, triples: Payload::encode(&t0) };
+        let req1 = AddTriplesRequest { field, first, triples: Payload::encode(&t1) };
+        let response0 = client0.add_triples(req0);
+        let response1 = client1.add_triples(req1);
+        try_join!(response0, response1)?;
+
+        first += this_batch;
+    }
+
+    println!(
+        "Dealt {:?} {:?} triples, servers now have {:?} available",
+        n,
+        field,
+        count0.available + n
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    config::init_logging();
+    let (cfg, nreqs) = config::get_args("Dealer", &[config::Section::Leader], true);
+    let lcfg = cfg.leader();
+
+    let policy = lcfg.retry_policy();
+    let client0 = rpc::Client::connect(lcfg.servers[0], policy, lcfg.max_message_len()).await?;
+    let client1 = rpc::Client::connect(lcfg.servers[1], policy, lcfg.max_message_len()).await?;
+
+    // Enough triples for one collection of nreqs clients.
+    let key = lcfg.dealer_key();
+    for (field, n) in mpc::triples_needed(cfg.fields(), cfg.data_len, nreqs) {
+        match field {
+            FieldType::FE62 => deal::<FE>(&client0, &client1, &key, field, n).await?,
+            FieldType::M127 => deal::<FieldElm127>(&client0, &client1, &key, field, n).await?,
+            FieldType::P255 => deal::<FieldElm255>(&client0, &client1, &key, field, n).await?,
+        }
+    }
+
+    Ok(())
+}
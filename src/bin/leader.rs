@@ -1,8 +1,11 @@
 use counttree::{
-    FieldElm,
-    collect, config, fastfield, mpc,
+    FieldElm127,
+    FieldElm255,
+    auth, collect, config, envelope, fastfield, input, metrics, mpc, prg, report, rpc, sim,
+    config::FieldType,
     rpc::{
-        AddKeysRequest, FinalSharesRequest, ResetRequest, 
+        AddKeysRequest, FinalSharesRequest, KeyIdsRequest, Payload, PublicKeyRequest,
+        CrawlKeys, ReconcileRequest, ResetRequest, RpcField, Tag, TripleCountRequest,
         TreeInitRequest,
         TreeCrawlRequest, 
         TreeCrawlLastRequest, 
@@ -10,6 +13,9 @@ use counttree::{
         TreeOutSharesLastRequest, 
         TreePruneRequest, 
         TreePruneLastRequest, 
+        TreeRecountRequest,
+        TreeRecountLastRequest,
+        TreeRejectRequest,
         TreeSketchFrontierRequest,
         TreeSketchFrontierLastRequest,
     },
@@ -18,47 +24,24 @@ use counttree::{
 
 use std::time::Instant;
 
-use futures::try_join;
+use futures::{stream, try_join, StreamExt, TryStreamExt};
+use std::collections::{BTreeSet, HashSet};
 use std::io;
+use std::ops::Range;
+use std::sync::Arc;
 
-use rand::Rng;
 use rayon::prelude::*;
-use tarpc::{
-    client,
-    context,
-    serde_transport::tcp,
-    tokio_serde::formats::Bincode,
-    //server::{self, Channel},
-};
-
-use rand::distributions::Alphanumeric;
-
-use std::time::{Duration, SystemTime};
 
-type SketchKey = sketch::SketchDPFKey<fastfield::FE,FieldElm>;
-
-fn long_context() -> context::Context {
-    let mut ctx = context::current();
-
-    // Increase timeout to one hour
-    ctx.deadline = SystemTime::now() + Duration::from_secs(1000000);
-    ctx
-}
-
-fn sample_string(len: usize) -> String {
-    let mut rng = rand::thread_rng();
-    std::iter::repeat(())
-        .map(|()| rng.sample(Alphanumeric))
-        .take(len / 8)
-        .collect()
-}
-
-fn generate_keys(cfg: &config::Config) -> (Vec<SketchKey>, Vec<SketchKey>) {
-    let (keys0, keys1): (Vec<SketchKey>, Vec<SketchKey>) = rayon::iter::repeat(0)
-        .take(cfg.num_sites)
-        .map(|_| {
-            let data_string = sample_string(cfg.data_len);
-            let keys = sketch::SketchDPFKey::gen_from_str(&data_string);
+// One pair of keys for each distinct string in the population.
+#[allow(clippy::type_complexity)]
+fn generate_keys<T: RpcField, U: RpcField>(
+    pop: &input::Population,
+) -> (Vec<sketch::SketchDPFKey<T, U>>, Vec<sketch::SketchDPFKey<T, U>>) {
+    let (keys0, keys1): (Vec<sketch::SketchDPFKey<T, U>>, Vec<sketch::SketchDPFKey<T, U>>) = pop
+        .sites
+        .par_iter()
+        .map(|site| {
+            let keys = sketch::SketchDPFKey::gen_from_bits(site);
 
             // XXX remove these clones
             (keys[0].clone(), keys[1].clone())
@@ -71,264 +54,645 @@ fn generate_keys(cfg: &config::Config) -> (Vec<SketchKey>, Vec<SketchKey>) {
     (keys0, keys1)
 }
 
+// Names the calls of one crawl of the collection, and of the key upload
+// that comes before it, if any.
+#[derive(Clone, Copy, Debug)]
+struct Round {
+    collection: u64,
+    round: u64,
+}
+
+impl Round {
+    fn tag(self, level: usize, batch: u64) -> Tag {
+        Tag::new(self.collection, level, batch).in_round(self.round)
+    }
+}
+
 async fn reset_servers(
-    client0: &mut counttree::CollectorClient,
-    client1: &mut counttree::CollectorClient,
+    cfg: &config::Config,
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    collection: u64,
 ) -> io::Result<()> {
-    let req = ResetRequest {};
-    let response0 = client0.reset(long_context(), req.clone());
-    let response1 = client1.reset(long_context(), req);
-    try_join!(response0, response1).unwrap();
+    let req = ResetRequest { fields: cfg.fields(), collection };
+    let response0 = client0.reset(req.clone());
+    let response1 = client1.reset(req);
+    try_join!(response0, response1)?;
 
     Ok(())
 }
 
-async fn tree_init(
-    client0: &mut counttree::CollectorClient,
-    client1: &mut counttree::CollectorClient,
-) -> io::Result<()> {
-    let req = TreeInitRequest {};
-    let response0 = client0.tree_init(long_context(), req.clone());
-    let response1 = client1.tree_init(long_context(), req);
-    try_join!(response0, response1).unwrap();
+// Has each server drop the keys that the other one never received, and
+// close an epoch with the keys left. Returns the number of them.
+async fn reconcile(
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    round: Round,
+) -> io::Result<usize> {
+    let tag = round.tag(0, 0);
+    let response0 = client0.key_ids(KeyIdsRequest { tag });
+    let response1 = client1.key_ids(KeyIdsRequest { tag });
+    let (ids0, ids1) = try_join!(response0, response1)?;
+
+    let set0: HashSet<_> = ids0.iter().collect();
+    let set1: HashSet<_> = ids1.iter().collect();
+    let drop0: Vec<_> = ids0.iter().filter(|id| !set1.contains(id)).copied().collect();
+    let drop1: Vec<_> = ids1.iter().filter(|id| !set0.contains(id)).copied().collect();
+    println!(
+        "Reconcile: dropping {:?} keys from server 0, {:?} from server 1",
+        drop0.len(),
+        drop1.len()
+    );
 
-    Ok(())
+    let response0 = client0.reconcile(ReconcileRequest { tag, drop: drop0 });
+    let response1 = client1.reconcile(ReconcileRequest { tag, drop: drop1 });
+    let (n0, n1) = try_join!(response0, response1)?;
+    if n0 != n1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The servers disagree on the keys reconciled, {:?} and {:?}", n0, n1),
+        ));
+    }
+
+    Ok(n0)
 }
 
-async fn add_keys(
+// Checks that the dealer has given both servers enough Beaver triples to
+// check the sketches of nreqs clients.
+async fn check_triples(
     cfg: &config::Config,
-    client0: counttree::CollectorClient,
-    client1: counttree::CollectorClient,
-    keys0: &[sketch::SketchDPFKey<fastfield::FE,FieldElm>],
-    keys1: &[sketch::SketchDPFKey<fastfield::FE,FieldElm>],
+    client0: &rpc::Client,
+    client1: &rpc::Client,
     nreqs: usize,
 ) -> io::Result<()> {
-    use rand::distributions::Distribution;
-    let mut rng = rand::thread_rng();
-    let zipf = zipf::ZipfDistribution::new(cfg.num_sites, cfg.zipf_exponent).unwrap();
+    for (field, needed) in mpc::triples_needed(cfg.fields(), cfg.data_len, nreqs) {
+        let response0 = client0.triple_count(TripleCountRequest { field });
+        let response1 = client1.triple_count(TripleCountRequest { field });
+        let (count0, count1) = try_join!(response0, response1)?;
+
+        let available = std::cmp::min(count0.available, count1.available);
+        if available < needed {
+            return Err(io::Error::other(format!(
+                "Servers have {} {:?} triples but need {}; run the dealer first",
+                available, field, needed
+            )));
+        }
+    }
 
-    let mut addkey0 = Vec::with_capacity(nreqs);
-    let mut addkey1 = Vec::with_capacity(nreqs);
+    Ok(())
+}
 
-    for _j in 0..nreqs {
-        let sample = zipf.sample(&mut rng) - 1;
-        addkey0.push(keys0[sample].clone());
-        addkey1.push(keys1[sample].clone());
+// Starts a crawl over the keys of `epochs`, from the nodes at `prefixes`.
+async fn tree_init(
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    round: Round,
+    epochs: Range<usize>,
+    prefixes: Vec<Vec<bool>>,
+) -> io::Result<CrawlKeys> {
+    let req = TreeInitRequest { tag: round.tag(0, 0), epochs: Some(epochs), prefixes };
+    let response0 = client0.tree_init(req.clone());
+    let response1 = client1.tree_init(req);
+    let (keys0, keys1) = try_join!(response0, response1)?;
+    if keys0 != keys1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The servers disagree on the keys to crawl, {:?} and {:?}", keys0, keys1),
+        ));
     }
 
-    let req0 = AddKeysRequest { keys: addkey0 };
-    let req1 = AddKeysRequest { keys: addkey1 };
+    Ok(keys0)
+}
 
-    let response0 = client0.add_keys(long_context(), req0.clone());
-    let response1 = client1.add_keys(long_context(), req1.clone());
+// Stands in for the clients. Each one takes the keys for its string,
+// gets a token for the collection and encrypts its two key shares to the
+// servers.
+#[allow(clippy::too_many_arguments)]
+fn client_reports<T: RpcField, U: RpcField>(
+    pop: &input::Population,
+    keys0: &[sketch::SketchDPFKey<T, U>],
+    keys1: &[sketch::SketchDPFKey<T, U>],
+    public: &[envelope::PublicKey; 2],
+    authority: &auth::Authority,
+    collection: u64,
+    first: usize,
+    nreqs: usize,
+) -> Vec<envelope::SealedReport> {
+    let mut reports = Vec::with_capacity(nreqs);
+    for j in 0..nreqs {
+        let sample = pop.clients[first + j];
+        let token = authority
+            .issue(collection, &format!("client{}", first + j))
+            .unwrap();
+        let plain0 = bincode::serialize(&keys0[sample]).unwrap();
+        let plain1 = bincode::serialize(&keys1[sample]).unwrap();
+        reports.push(envelope::SealedReport::seal(public, token, [&plain0, &plain1]));
+    }
 
-    try_join!(response0, response1).unwrap();
+    reports
+}
+
+// The relay, which forwards each server its half of every report. It
+// never sees a plaintext key.
+async fn add_keys(
+    client0: rpc::Client,
+    client1: rpc::Client,
+    tag: Tag,
+    reports: Vec<envelope::SealedReport>,
+) -> io::Result<()> {
+    let (reports0, reports1) = reports.into_iter().map(|r| r.split()).unzip();
+
+    let req0 = AddKeysRequest { tag, reports: reports0 };
+    let req1 = AddKeysRequest { tag, reports: reports1 };
+
+    let response0 = client0.add_keys(req0);
+    let response1 = client1.add_keys(req1);
+
+    try_join!(response0, response1)?;
 
     Ok(())
 }
 
-async fn verify_sketches(
-    client0: &mut counttree::CollectorClient,
-    client1: &mut counttree::CollectorClient,
-    level: usize,
+// The clients encrypt to the public keys pinned in their config, never to
+// keys that come over the network. A server that has some other key could
+// not open the uploads, so it is an error.
+async fn public_keys(
+    cfg: &config::Config,
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+) -> io::Result<[envelope::PublicKey; 2]> {
+    let pinned = cfg.client().server_public_keys();
+    let response0 = client0.public_key(PublicKeyRequest {});
+    let response1 = client1.public_key(PublicKeyRequest {});
+    let (pk0, pk1) = try_join!(response0, response1)?;
+
+    for (i, pk) in [pk0, pk1].iter().enumerate() {
+        if *pk != pinned[i] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Server {} has public key {}, not the pinned {}", i, pk, pinned[i]),
+            ));
+        }
+    }
+    Ok(pinned)
+}
+
+// Checks the sketches of keys start..end under the randomness from
+// `seed`. Returns the indices of the keys that failed, and each server's
+// first sketch corrections summed over the keys for which `live` holds.
+async fn verify_sketches<T: RpcField>(
+    client0: rpc::Client,
+    client1: rpc::Client,
+    tag: Tag,
     start: usize,
     end: usize,
-) -> io::Result<Vec<bool>> {
+    seed: prg::PrgSeed,
+    live: &[bool],
+) -> io::Result<(Vec<usize>, [T; 2])> {
     // Cor shares
-    let req = TreeSketchFrontierRequest { level, start, end };
-    let response0 = client0.tree_sketch_frontier(long_context(), req.clone());
-    let response1 = client1.tree_sketch_frontier(long_context(), req);
-    let (cor_shares0, cor_shares1) = try_join!(response0, response1).unwrap();
-    let cor = mpc::ManyMulState::cors(&cor_shares0, &cor_shares1);
+    let req = TreeSketchFrontierRequest { tag, start, end, seed };
+    let response0 = client0.tree_sketch_frontier(req.clone());
+    let response1 = client1.tree_sketch_frontier(req);
+    let (cor_shares0, cor_shares1) = try_join!(response0, response1)?;
+    let (cor_shares0, cor_shares1) = (cor_shares0.decode(), cor_shares1.decode());
+    let masked = [
+        mpc::ManyMulState::<T>::masked_r_x(&cor_shares0, &live[start..end]),
+        mpc::ManyMulState::<T>::masked_r_x(&cor_shares1, &live[start..end]),
+    ];
+    let cor = mpc::ManyMulState::<T>::cors(&cor_shares0, &cor_shares1);
 
     // Out shares
-    let req = TreeOutSharesRequest { cor };
-    let response0 = client0.tree_out_shares(long_context(), req.clone());
-    let response1 = client1.tree_out_shares(long_context(), req);
-    let (out_shares0, out_shares1) = try_join!(response0, response1).unwrap();
+    let req = TreeOutSharesRequest { tag, cor: Payload::encode(&cor) };
+    let response0 = client0.tree_out_shares(req.clone());
+    let response1 = client1.tree_out_shares(req);
+    let (out_shares0, out_shares1) = try_join!(response0, response1)?;
 
-    Ok(mpc::ManyMulState::verify(&out_shares0, &out_shares1))
+    let ok = mpc::ManyMulState::<T>::verify(&out_shares0.decode(), &out_shares1.decode());
+    Ok((failed_keys(start, &ok), masked))
 }
 
-async fn verify_sketches_last(
-    client0: &mut counttree::CollectorClient,
-    client1: &mut counttree::CollectorClient,
+async fn verify_sketches_last<U: RpcField>(
+    client0: rpc::Client,
+    client1: rpc::Client,
+    tag: Tag,
     start: usize,
     end: usize,
-) -> io::Result<Vec<bool>> {
+    seed: prg::PrgSeed,
+    live: &[bool],
+) -> io::Result<(Vec<usize>, [U; 2])> {
     // Cor shares
-    let req = TreeSketchFrontierLastRequest { start, end };
-    let response0 = client0.tree_sketch_frontier_last(long_context(), req.clone());
-    let response1 = client1.tree_sketch_frontier_last(long_context(), req);
-    let (cor_shares0, cor_shares1) = try_join!(response0, response1).unwrap();
-    let cor = mpc::ManyMulState::cors(&cor_shares0, &cor_shares1);
+    let req = TreeSketchFrontierLastRequest { tag, start, end, seed };
+    let response0 = client0.tree_sketch_frontier_last(req.clone());
+    let response1 = client1.tree_sketch_frontier_last(req);
+    let (cor_shares0, cor_shares1) = try_join!(response0, response1)?;
+    let (cor_shares0, cor_shares1) = (cor_shares0.decode(), cor_shares1.decode());
+    let masked = [
+        mpc::ManyMulState::<U>::masked_r_x(&cor_shares0, &live[start..end]),
+        mpc::ManyMulState::<U>::masked_r_x(&cor_shares1, &live[start..end]),
+    ];
+    let cor = mpc::ManyMulState::<U>::cors(&cor_shares0, &cor_shares1);
 
     // Out shares
-    let req = TreeOutSharesLastRequest { cor };
-    let response0 = client0.tree_out_shares_last(long_context(), req.clone());
-    let response1 = client1.tree_out_shares_last(long_context(), req);
-    let (out_shares0, out_shares1) = try_join!(response0, response1).unwrap();
+    let req = TreeOutSharesLastRequest { tag, cor: Payload::encode(&cor) };
+    let response0 = client0.tree_out_shares_last(req.clone());
+    let response1 = client1.tree_out_shares_last(req);
+    let (out_shares0, out_shares1) = try_join!(response0, response1)?;
 
-    Ok(mpc::ManyMulState::verify(&out_shares0, &out_shares1))
+    let ok = mpc::ManyMulState::<U>::verify(&out_shares0.decode(), &out_shares1.decode());
+    Ok((failed_keys(start, &ok), masked))
 }
 
-async fn run_level(
-    cfg: &config::Config,
-    client0: &mut counttree::CollectorClient,
-    client1: &mut counttree::CollectorClient,
+// The indices of the keys in a batch from `start` that failed the check.
+fn failed_keys(start: usize, ok: &[bool]) -> Vec<usize> {
+    ok.iter().enumerate().filter(|(_, ok)| !**ok).map(|(i, _)| start + i).collect()
+}
+
+// Number of sketch batches that the leader keeps in flight at once.
+const SKETCH_BATCHES_IN_FLIGHT: usize = 8;
+
+// Sketches are run in chunks of batch_size, so that several chunks can
+// be in flight at once. Returns the batches as (batch ID, start, end),
+// numbering them from *next_batch.
+fn sketch_batches(nreqs: usize, batch_size: usize, next_batch: &mut u64) -> Vec<(u64, usize, usize)> {
+    let mut out = vec![];
+    for start in (0..nreqs).step_by(batch_size) {
+        let end = std::cmp::min(nreqs, start + batch_size);
+        out.push((*next_batch, start, end));
+        *next_batch += 1;
+    }
+    out
+}
+
+// What the sketch checks of one level found: the keys that failed, and
+// each server's first sketch corrections summed over the live keys.
+struct Sketched<V> {
+    seed: prg::PrgSeed,
+    failed: Vec<usize>,
+    masked: [V; 2],
+}
+
+impl<V: RpcField> Sketched<V> {
+    fn new(seed: prg::PrgSeed) -> Self {
+        Sketched {
+            seed,
+            failed: vec![],
+            masked: [V::zero(), V::zero()],
+        }
+    }
+
+    fn add(&mut self, (failed, masked): (Vec<usize>, [V; 2])) {
+        self.failed.extend(failed);
+        self.masked[0].add(&masked[0]);
+        self.masked[1].add(&masked[1]);
+    }
+
+    // The servers whose counts `shares` at a level of `nodes` nodes do
+    // not match their shares of these sketches.
+    fn misbehaving_servers(
+        &self,
+        key: &mpc::DealerKey,
+        nodes: usize,
+        live: &[bool],
+        shares: &[collect::CountShares<V>; 2],
+    ) -> Vec<usize> {
+        collect::misbehaving_servers(
+            key,
+            &self.seed,
+            nodes,
+            live,
+            [&shares[0], &shares[1]],
+            [&self.masked[0], &self.masked[1]],
+        )
+    }
+}
+
+// Decides what to do about a level whose counts fail a check once the
+// keys that failed a sketch check are gone: stops the run, or under the
+// quarantine policy reports the nodes and lets the crawl go on without
+// them.
+fn judge(cfg: &config::Config, level: usize, suspects: &collect::Suspects) -> io::Result<()> {
+    if suspects.is_empty() {
+        return Ok(());
+    }
+    let msg = format!("Level {:?}: {}: {}", level, suspects, suspects.blame());
+    match cfg.leader().on_misbehaviour {
+        collect::Policy::Abort => Err(io::Error::new(io::ErrorKind::InvalidData, msg)),
+        collect::Policy::Quarantine => {
+            println!("Quarantine {}", msg);
+            Ok(())
+        }
+    }
+}
+
+// Has the servers drop the keys that failed a level's sketch check, so
+// that neither the level's recount nor later levels count them, and
+// marks them dead in `live`. Both servers must then agree on the number
+// of keys still live.
+async fn reject_keys(
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    round: Round,
     level: usize,
-    nreqs: usize,
-    start_time: Instant,
-) -> io::Result<usize> {
-    let threshold64 = core::cmp::max(1, (cfg.threshold * (nreqs as f64)) as u64);
-    let threshold = fastfield::FE::new(threshold64);
+    failed: &[usize],
+    live: &mut [bool],
+    metrics: &metrics::Metrics,
+) -> io::Result<()> {
+    let before = live.iter().filter(|l| **l).count();
+    let req = TreeRejectRequest { tag: round.tag(level, 0), keys: failed.to_vec() };
+    let (live0, live1) = try_join!(client0.tree_reject(req.clone()), client1.tree_reject(req))?;
+    for &i in failed {
+        live[i] = false;
+    }
+    let after = live.iter().filter(|l| **l).count();
+    if live0 != after || live1 != after {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Level {:?}: the servers have {:?} and {:?} live clients, not {:?}",
+                level, live0, live1, after
+            ),
+        ));
+    }
+    metrics.reject("sketch", before - after);
+    println!("Level {:?} rejected={:?} live={:?}", level, before - after, after);
+    Ok(())
+}
 
-    // Tree crawl
+// Checks every sketch for one level. The leader draws the sketches'
+// randomness, `seed`, only once the servers have sent their counts.
+#[allow(clippy::too_many_arguments)]
+async fn verify_level<T: RpcField>(
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    round: Round,
+    level: usize,
+    batches: Vec<(u64, usize, usize)>,
+    live: &[bool],
+    metrics: &metrics::Metrics,
+    start_time: Instant,
+) -> io::Result<Sketched<T>> {
     println!(
-        "TreeCrawlStart {:?} {:?} {:?}",
+        "SketchStart {:?} {:?} {:?}",
         level,
         "-",
         start_time.elapsed().as_secs_f64()
     );
-    let req = TreeCrawlRequest {};
-    let response0 = client0.tree_crawl(long_context(), req.clone());
-    let response1 = client1.tree_crawl(long_context(), req);
-    let (vals0, vals1) = try_join!(response0, response1).unwrap();
+    let sketch_start = Instant::now();
+
+    let seed = prg::PrgSeed::random();
+    let mut sketched = Sketched::new(seed.clone());
+    let checks = batches.into_iter().map(|(batch, start, end)| {
+        let tag = round.tag(level, batch);
+        verify_sketches::<T>(client0.clone(), client1.clone(), tag, start, end, seed.clone(), live)
+    });
+    let mut results = stream::iter(checks).buffer_unordered(SKETCH_BATCHES_IN_FLIGHT);
+    while let Some(out) = results.next().await {
+        sketched.add(out?);
+    }
+    // Keys rejected before fail again, but they no longer count.
+    sketched.failed.retain(|&i| live[i]);
+    metrics.sketch_seconds.observe(sketch_start.elapsed().as_secs_f64());
+
     println!(
-        "TreeCrawlDone {:?} {:?} {:?}",
+        "SketchDone {:?} {:?} {:?} rate={:?}",
         level,
         "-",
-        start_time.elapsed().as_secs_f64()
+        start_time.elapsed().as_secs_f64(),
+        (live.len() as f64) / sketch_start.elapsed().as_secs_f64()
     );
+    Ok(sketched)
+}
 
+#[allow(clippy::too_many_arguments)]
+async fn verify_level_last<U: RpcField>(
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    round: Round,
+    level: usize,
+    batches: Vec<(u64, usize, usize)>,
+    live: &[bool],
+    metrics: &metrics::Metrics,
+    start_time: Instant,
+) -> io::Result<Sketched<U>> {
     println!(
-        "SketchStart {:?} {:?} {:?}",
-        level,
+        "SketchStart last {:?} {:?}",
         "-",
         start_time.elapsed().as_secs_f64()
     );
-
     let sketch_start = Instant::now();
 
-    // Run sketching in chunks of cfg.sketch_batch_size to avoid having huge RPC messages.
-    let mut start = 0;
-    while start < nreqs {
-        let end = std::cmp::min(nreqs, start + cfg.sketch_batch_size);
-        let out = verify_sketches(client0, client1, level, start, end).await?;
-        start += cfg.sketch_batch_size;
-
-        for v in out {
-            assert!(v);
-        }
+    let seed = prg::PrgSeed::random();
+    let mut sketched = Sketched::new(seed.clone());
+    let checks = batches.into_iter().map(|(batch, start, end)| {
+        let tag = round.tag(level, batch);
+        verify_sketches_last::<U>(client0.clone(), client1.clone(), tag, start, end, seed.clone(), live)
+    });
+    let mut results = stream::iter(checks).buffer_unordered(SKETCH_BATCHES_IN_FLIGHT);
+    while let Some(out) = results.next().await {
+        sketched.add(out?);
     }
+    sketched.failed.retain(|&i| live[i]);
+    metrics.sketch_seconds.observe(sketch_start.elapsed().as_secs_f64());
 
     println!(
-        "SketchDone {:?} {:?} {:?} rate={:?}",
-        level,
+        "SketchDone last {:?} {:?} rate={:?}",
         "-",
         start_time.elapsed().as_secs_f64(),
-        (nreqs as f64) / sketch_start.elapsed().as_secs_f64()
+        (live.len() as f64) / sketch_start.elapsed().as_secs_f64()
     );
-
-    assert_eq!(vals0.len(), vals1.len());
-    let keep = collect::KeyCollection::<fastfield::FE,FieldElm>::keep_values(nreqs, &threshold, &vals0, &vals1);
-    //println!("Keep: {:?}", keep);
-    //println!("KeepLen: {:?}", keep.len());
-
-    // Tree prune
-    let req = TreePruneRequest { keep };
-    let response0 = client0.tree_prune(long_context(), req.clone());
-    let response1 = client1.tree_prune(long_context(), req);
-    try_join!(response0, response1).unwrap();
-
-    Ok(vals0.len())
+    Ok(sketched)
 }
 
-async fn run_level_last(
+// Crawls one level of `nodes` nodes, checks its sketches and prunes it.
+// The servers hold one key for each entry of `live`, which says whether
+// it has passed every sketch check so far.
+//
+// Each server's counts must match its shares of the level's sketches.
+// Keys that fail their sketch check are rejected, and the level counted
+// and sketched again without them, until every live key passes; only
+// then is any count opened. A server that shifts its counts can make them
+// match only by spoiling some key's sketch, and each time it does, it
+// loses the key and must try again under a fresh seed. Each round takes
+// fresh triples, so a server that keeps at it stops the collection once
+// they run out. Returns what the crawl revealed, and the number of nodes
+// at the next level.
+#[allow(clippy::too_many_arguments)]
+async fn run_level<T: RpcField>(
     cfg: &config::Config,
-    client0: &mut counttree::CollectorClient,
-    client1: &mut counttree::CollectorClient,
-    nreqs: usize,
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    round: Round,
+    level: usize,
+    nodes: usize,
+    live: &mut [bool],
+    dealer: &mpc::DealerKey,
+    thresholds: &mut sim::Thresholds,
+    next_batch: &mut u64,
+    metrics: &Arc<metrics::Metrics>,
     start_time: Instant,
-) -> io::Result<usize> {
-    let threshold64 = core::cmp::max(1, (cfg.threshold * (nreqs as f64)) as u32);
-    let threshold = FieldElm::from(threshold64);
-
+) -> io::Result<(report::LevelStats, usize)> {
     // Tree crawl
     println!(
-        "TreeCrawlStart last {:?} {:?}",
+        "TreeCrawlStart {:?} {:?} {:?}",
+        level,
         "-",
         start_time.elapsed().as_secs_f64()
     );
-    let req = TreeCrawlLastRequest {};
-    let response0 = client0.tree_crawl_last(long_context(), req.clone());
-    let response1 = client1.tree_crawl_last(long_context(), req);
-    let (vals0, vals1) = try_join!(response0, response1).unwrap();
+    let crawl_start = Instant::now();
+    let tag = round.tag(level, 0);
+    let req = TreeCrawlRequest { tag };
+    let response0 = client0.tree_crawl(req.clone());
+    let response1 = client1.tree_crawl(req);
+    let (vals0, vals1) = try_join!(response0, response1)?;
+    let mut shares: [collect::CountShares<T>; 2] = [vals0.decode(), vals1.decode()];
+    metrics.crawl_seconds.observe(crawl_start.elapsed().as_secs_f64());
+    metrics.set_frontier_size(level, nodes);
     println!(
-        "TreeCrawlDone last {:?} {:?}",
+        "TreeCrawlDone {:?} {:?} {:?}",
+        level,
         "-",
         start_time.elapsed().as_secs_f64()
     );
 
+    let batches = sketch_batches(live.len(), cfg.leader().sketch_batch_size, next_batch);
+    let mut sketched =
+        verify_level::<T>(client0, client1, round, level, batches, live, metrics, start_time).await?;
+    let mut servers = sketched.misbehaving_servers(dealer, nodes, live, &shares);
+    while !sketched.failed.is_empty() {
+        reject_keys(client0, client1, round, level, &sketched.failed, live, metrics).await?;
+        let req = TreeRecountRequest { tag };
+        let (vals0, vals1) = try_join!(client0.tree_recount(req.clone()), client1.tree_recount(req))?;
+        shares = [vals0.decode(), vals1.decode()];
+
+        let batches = sketch_batches(live.len(), cfg.leader().sketch_batch_size, next_batch);
+        sketched =
+            verify_level::<T>(client0, client1, round, level, batches, live, metrics, start_time).await?;
+        servers.extend(sketched.misbehaving_servers(dealer, nodes, live, &shares));
+    }
+    servers.sort_unstable();
+    servers.dedup();
+
+    let nlive = live.iter().filter(|l| **l).count();
+    let (counts, suspects) = collect::open_counts(nlive, nodes, &shares[0].counts, &shares[1].counts, servers);
+    judge(cfg, level, &suspects)?;
+    let keep = thresholds.keep(level, nlive, &counts);
+    //println!("Keep: {:?}", keep);
+    //println!("KeepLen: {:?}", keep.len());
+
+    // Tree prune
+    let stats = report::level_stats(level, &counts, &keep, &suspects);
+    let next = 2 * keep.iter().filter(|k| **k).count();
+    let req = TreePruneRequest { tag, keep };
+    let response0 = client0.tree_prune(req.clone());
+    let response1 = client1.tree_prune(req);
+    try_join!(response0, response1)?;
+
+    Ok((stats, next))
+}
+
+// Like run_level(), for the last level. Returns the counts of the nodes
+// kept, which the final shares must match.
+#[allow(clippy::too_many_arguments)]
+async fn run_level_last<U: RpcField>(
+    cfg: &config::Config,
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    round: Round,
+    nodes: usize,
+    live: &mut [bool],
+    dealer: &mpc::DealerKey,
+    thresholds: &mut sim::Thresholds,
+    next_batch: &mut u64,
+    metrics: &Arc<metrics::Metrics>,
+    start_time: Instant,
+) -> io::Result<(report::LevelStats, Vec<U>)> {
+    // Tree crawl
     println!(
-        "SketchStart last {:?} {:?}",
+        "TreeCrawlStart last {:?} {:?}",
         "-",
         start_time.elapsed().as_secs_f64()
     );
-
-    let sketch_start = Instant::now();
-
-    // Run sketching in chunks of cfg.sketch_batch_size to avoid having huge RPC messages.
-    let mut start = 0;
-    while start < nreqs {
-        let end = std::cmp::min(nreqs, start + cfg.sketch_batch_size_last);
-        let out = verify_sketches_last(client0, client1, start, end).await?;
-        start += cfg.sketch_batch_size_last;
-
-        for v in out {
-            assert!(v);
-        }
-    }
-
+    let crawl_start = Instant::now();
+    let level = cfg.data_len - 1;
+    let tag = round.tag(level, 0);
+    let req = TreeCrawlLastRequest { tag };
+    let response0 = client0.tree_crawl_last(req.clone());
+    let response1 = client1.tree_crawl_last(req);
+    let (vals0, vals1) = try_join!(response0, response1)?;
+    let mut shares: [collect::CountShares<U>; 2] = [vals0.decode(), vals1.decode()];
+    metrics.crawl_seconds.observe(crawl_start.elapsed().as_secs_f64());
+    metrics.set_frontier_size(level, nodes);
     println!(
-        "SketchDone last {:?} {:?} rate={:?}",
+        "TreeCrawlDone last {:?} {:?}",
         "-",
-        start_time.elapsed().as_secs_f64(),
-        (nreqs as f64) / sketch_start.elapsed().as_secs_f64()
+        start_time.elapsed().as_secs_f64()
     );
 
-    assert_eq!(vals0.len(), vals1.len());
-    let keep = collect::KeyCollection::<fastfield::FE,FieldElm>::keep_values_last(nreqs, &threshold, &vals0, &vals1);
+    let batches = sketch_batches(live.len(), cfg.leader().sketch_batch_size_last, next_batch);
+    let mut sketched =
+        verify_level_last::<U>(client0, client1, round, level, batches, live, metrics, start_time).await?;
+    let mut servers = sketched.misbehaving_servers(dealer, nodes, live, &shares);
+    while !sketched.failed.is_empty() {
+        reject_keys(client0, client1, round, level, &sketched.failed, live, metrics).await?;
+        let req = TreeRecountLastRequest { tag };
+        let (vals0, vals1) = try_join!(client0.tree_recount_last(req.clone()), client1.tree_recount_last(req))?;
+        shares = [vals0.decode(), vals1.decode()];
+
+        let batches = sketch_batches(live.len(), cfg.leader().sketch_batch_size_last, next_batch);
+        sketched =
+            verify_level_last::<U>(client0, client1, round, level, batches, live, metrics, start_time).await?;
+        servers.extend(sketched.misbehaving_servers(dealer, nodes, live, &shares));
+    }
+    servers.sort_unstable();
+    servers.dedup();
+
+    let nlive = live.iter().filter(|l| **l).count();
+    let (counts, suspects) = collect::open_counts(nlive, nodes, &shares[0].counts, &shares[1].counts, servers);
+    judge(cfg, level, &suspects)?;
+    let keep = thresholds.keep(level, nlive, &counts);
+    let kept = counts.iter().zip(&keep).filter(|(_, k)| **k).map(|(v, _)| v.clone()).collect();
     //println!("Keep: {:?}", keep);
     //println!("KeepLen: {:?}", keep.len());
 
     // Tree prune
-    let req = TreePruneLastRequest { keep };
-    let response0 = client0.tree_prune_last(long_context(), req.clone());
-    let response1 = client1.tree_prune_last(long_context(), req);
-    try_join!(response0, response1).unwrap();
+    let stats = report::level_stats(level, &counts, &keep, &suspects);
+    let req = TreePruneLastRequest { tag, keep };
+    let response0 = client0.tree_prune_last(req.clone());
+    let response1 = client1.tree_prune_last(req);
+    try_join!(response0, response1)?;
 
-    Ok(vals0.len())
+    Ok((stats, kept))
 }
 
-async fn final_shares(
-    client0: &mut counttree::CollectorClient,
-    client1: &mut counttree::CollectorClient,
-) -> io::Result<()> {
+async fn final_shares<T: RpcField, U: RpcField>(
+    cfg: &config::Config,
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    round: Round,
+    counts: &[U],
+) -> io::Result<Vec<Vec<bool>>> {
     // Final shares
-    let req = FinalSharesRequest {};
-    let response0 = client0.final_shares(long_context(), req.clone());
-    let response1 = client1.final_shares(long_context(), req);
-    try_join!(response0, response1).unwrap();
-
-    /*
-    for res in &collect::KeyCollection::<fastfield::FE,FieldElm>::final_values(&vals0, &vals1) {
-        println!("Path = {:?}", res.path);
-        let s = crate::bits_to_string(&res.path);
-        println!("Value: {:?} = {:?}", s, res.value);
-    }*/
+    let req = FinalSharesRequest { tag: round.tag(cfg.data_len - 1, 0) };
+    let response0 = client0.final_shares(req.clone());
+    let response1 = client1.final_shares(req);
+    let (vals0, vals1) = try_join!(response0, response1)?;
+    let (vals0, vals1): (Vec<collect::Result<U>>, Vec<collect::Result<U>>) = (vals0.decode(), vals1.decode());
+
+    let results = collect::KeyCollection::<T, U>::final_values(&vals0, &vals1);
+    if results.iter().map(|res| &res.value).ne(counts) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The final shares do not match the last level's counts, so a server changed them",
+        ));
+    }
+    for res in &results {
+        match &cfg.client().fields {
+            Some(fields) => println!("Value: {:?} = {:?}", input::decode_tuple(&res.path, fields), res.value),
+            None => println!("Value: {:?} = {:?}", input::decode(&res.path), res.value),
+        }
+    }
 
-    Ok(())
+    Ok(results.into_iter().map(|res| res.path).collect())
 }
 
 #[tokio::main]
@@ -336,23 +700,67 @@ async fn main() -> io::Result<()> {
     println!("Using only one thread!");
     rayon::ThreadPoolBuilder::new().num_threads(1).build_global().unwrap();
 
-    env_logger::init();
-    let (cfg, _, nreqs) = config::get_args("Leader", false, true);
-    debug_assert_eq!(cfg.data_len % 8, 0);
+    config::init_logging();
+    let (cfg, nreqs) =
+        config::get_args("Leader", &[config::Section::Leader, config::Section::Client], true);
+    let lcfg = cfg.leader();
 
     // XXX WARNING: THERE IS NO TLS HERE!!!
-    let mut client0 =
-        counttree::CollectorClient::new(client::Config::default(), 
-                                        tcp::connect(cfg.server0, Bincode::default).await?
-                                        ).spawn();
-    let mut client1 =
-        counttree::CollectorClient::new(client::Config::default(), 
-                                        tcp::connect(cfg.server1, Bincode::default).await?
-                                        ).spawn();
+    let policy = lcfg.retry_policy();
+    let client0 = rpc::Client::connect(lcfg.servers[0], policy, lcfg.max_message_len()).await?;
+    let client1 = rpc::Client::connect(lcfg.servers[1], policy, lcfg.max_message_len()).await?;
+
+    let metrics = Arc::new(metrics::Metrics::new());
+    if let Some(metrics_addr) = lcfg.metrics_listen {
+        let (bound, serving) = metrics::bind(metrics.clone(), &metrics_addr)?;
+        println!("Serving metrics on {:?}", bound);
+        tokio::spawn(serving);
+    }
+
+    let fields = cfg.fields();
+    println!("Using fields {:?}", fields);
+    match (fields.inner, fields.last) {
+        (FieldType::FE62, FieldType::FE62) => {
+            run::<fastfield::FE, fastfield::FE>(&cfg, &client0, &client1, nreqs, &metrics).await
+        }
+        (FieldType::FE62, FieldType::M127) => {
+            run::<fastfield::FE, FieldElm127>(&cfg, &client0, &client1, nreqs, &metrics).await
+        }
+        (FieldType::FE62, FieldType::P255) => {
+            run::<fastfield::FE, FieldElm255>(&cfg, &client0, &client1, nreqs, &metrics).await
+        }
+        (FieldType::M127, FieldType::M127) => {
+            run::<FieldElm127, FieldElm127>(&cfg, &client0, &client1, nreqs, &metrics).await
+        }
+        (FieldType::P255, FieldType::P255) => {
+            run::<FieldElm255, FieldElm255>(&cfg, &client0, &client1, nreqs, &metrics).await
+        }
+        _ => panic!("Unsupported field pair {:?}", fields),
+    }
+}
+
+async fn run<T: RpcField, U: RpcField>(
+    cfg: &config::Config,
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    nreqs: usize,
+    metrics: &Arc<metrics::Metrics>,
+) -> io::Result<()> {
+    let pop = cfg
+        .client()
+        .population(cfg.data_len, nreqs)
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not load client input: {}", e)))?;
+    if pop.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No clients to simulate"));
+    }
+    if pop.len() < nreqs {
+        println!("Input has only {:?} clients", pop.len());
+    }
+    let nreqs = pop.len();
 
     let start = Instant::now();
     println!("Generating keys...");
-    let (keys0, keys1) = generate_keys(&cfg);
+    let (keys0, keys1) = generate_keys::<T, U>(&pop);
     println!("Done.");
     let delta = start.elapsed().as_secs_f64();
     println!(
@@ -362,57 +770,161 @@ async fn main() -> io::Result<()> {
         delta / (keys0.len() as f64)
     );
 
-    reset_servers(&mut client0, &mut client1).await?;
-
-    let mut left_to_go = nreqs;
-    let reqs_in_flight = 1000;
-    while left_to_go > 0 {
-        let mut resps = vec![];
-
-        for _j in 0..reqs_in_flight {
-            let this_batch = std::cmp::min(left_to_go, cfg.addkey_batch_size);
-            left_to_go -= this_batch;
-
-            if this_batch > 0 {
-                resps.push(add_keys(
-                    &cfg,
-                    client0.clone(),
-                    client1.clone(),
-                    &keys0,
-                    &keys1,
-                    this_batch,
-                ));
-            }
+    // Each simulated client gets one token for this collection from the
+    // stand-in authentication service.
+    let collection: u64 = rand::random();
+    let authority = auth::Authority::new(&auth::STAND_IN_KEY);
+    reset_servers(cfg, client0, client1, collection).await?;
+    let dealer = cfg.leader().dealer_key();
+
+    // Each epoch's crawl reads its own keys, and each merge at most every
+    // key, so no run crawls more than nreqs keys per epoch.
+    let epochs = cfg.leader().epochs;
+    check_triples(cfg, client0, client1, nreqs * epochs).await?;
+    let public = public_keys(cfg, client0, client1).await?;
+
+    // The clients arrive in epochs of nearly equal size, in input order.
+    // Epoch e is crawled alone in round 2e, and merged with the epochs
+    // before it in round 2e + 1.
+    let mut merged = vec![];
+    for epoch in 0..epochs {
+        let clients = nreqs * epoch / epochs..nreqs * (epoch + 1) / epochs;
+        let round = Round { collection, round: 2 * epoch as u64 };
+        println!("Epoch {:?}: clients {:?}", epoch, clients);
+
+        // Stream the uploads, keeping a bounded number of batches in
+        // flight. Every key carries its client's ID, so the servers can
+        // pair the keys up however the batches arrive.
+        let reqs_in_flight = 64;
+        let batch_size = cfg.leader().addkey_batch_size;
+        let batches = clients.clone().step_by(batch_size).enumerate().map(|(batch, first)| {
+            let this_batch = std::cmp::min(clients.end - first, batch_size);
+            let reports = client_reports(
+                &pop,
+                &keys0,
+                &keys1,
+                &public,
+                &authority,
+                collection,
+                first,
+                this_batch,
+            );
+            add_keys(client0.clone(), client1.clone(), round.tag(0, batch as u64), reports)
+        });
+        stream::iter(batches)
+            .buffer_unordered(reqs_in_flight)
+            .try_collect::<Vec<()>>()
+            .await?;
+        reconcile(client0, client1, round).await?;
+
+        let window = pop.window(clients.clone());
+        let output =
+            crawl::<T, U>(cfg, client0, client1, round, epoch..epoch + 1, vec![vec![]], &dealer, &window, metrics)
+                .await?;
+        if epoch == 0 {
+            merged = output;
+            continue;
         }
 
-        for r in resps {
-            r.await?;
+        // Only the prefixes of strings that were heavy before, or that
+        // are heavy in this epoch, are crawled again over every epoch so
+        // far. A string that is heavy overall but under neither is missed.
+        let prefixes = merge_prefixes(&[&merged, &output], cfg.leader().merge_level);
+        println!("Epoch {:?}: merging from {:?} prefixes", epoch, prefixes.len());
+        if prefixes.is_empty() {
+            merged = vec![];
+            continue;
         }
+        let round = Round { collection, round: 2 * epoch as u64 + 1 };
+        let window = pop.window(0..clients.end);
+        merged = crawl::<T, U>(cfg, client0, client1, round, 0..epoch + 1, prefixes, &dealer, &window, metrics)
+            .await?;
     }
 
-    tree_init(&mut client0, &mut client1).await?;
+    Ok(())
+}
+
+// The distinct prefixes of `bits` bits of the strings in `outputs`.
+fn merge_prefixes(outputs: &[&[Vec<bool>]], bits: usize) -> Vec<Vec<bool>> {
+    let prefixes: BTreeSet<&[bool]> = outputs.iter().flat_map(|o| o.iter()).map(|path| &path[..bits]).collect();
+    prefixes.into_iter().map(|p| p.to_vec()).collect()
+}
+
+// Crawls the keys of `epochs` from the nodes at `prefixes`, and prints how
+// the output compares to the plaintext answer for `pop`, the clients that
+// sent those keys. Returns the output.
+#[allow(clippy::too_many_arguments)]
+async fn crawl<T: RpcField, U: RpcField>(
+    cfg: &config::Config,
+    client0: &rpc::Client,
+    client1: &rpc::Client,
+    round: Round,
+    epochs: Range<usize>,
+    prefixes: Vec<Vec<bool>>,
+    dealer: &mpc::DealerKey,
+    pop: &input::Population,
+    metrics: &Arc<metrics::Metrics>,
+) -> io::Result<Vec<Vec<bool>>> {
+    let first_level = prefixes[0].len();
+    let mut nodes = 2 * prefixes.len();
+    let keys = tree_init(client0, client1, round, epochs, prefixes).await?;
+    let nreqs = keys.keys;
+    let mut live = vec![true; nreqs];
+    for &i in &keys.dead {
+        live[i] = false;
+    }
 
     let start = Instant::now();
-    for level in 0..cfg.data_len-1 {
-        let active_paths = run_level(&cfg, &mut client0, &mut client1, level, nreqs, start).await?;
+    let mut next_batch = 0;
+    let mut levels = vec![];
+    let mut thresholds = sim::Thresholds::new(cfg.leader().threshold, cfg.crawl_order());
+    // The dealer MACs each field's triples under a key of its own.
+    let fields = cfg.fields();
+    let (dealer, dealer_last) = (dealer.for_field(fields.inner), dealer.for_field(fields.last));
+    for level in first_level..cfg.data_len-1 {
+        let stats;
+        (stats, nodes) = run_level::<T>(
+            cfg, client0, client1, round, level, nodes, &mut live, &dealer, &mut thresholds, &mut next_batch,
+            metrics, start,
+        )
+        .await?;
 
         println!(
             "Level {:?} active_paths={:?} {:?}",
             level,
-            active_paths,
+            stats.frontier,
             start.elapsed().as_secs_f64()
         );
+        levels.push(stats);
     }
 
-    let active_paths = run_level_last(&cfg, &mut client0, &mut client1, nreqs, start).await?;
+    let (stats, counts) =
+        run_level_last::<U>(
+            cfg, client0, client1, round, nodes, &mut live, &dealer_last, &mut thresholds, &mut next_batch, metrics,
+            start,
+        )
+        .await?;
     println!(
         "Level {:?} active_paths={:?} {:?}",
         cfg.data_len,
-        active_paths,
+        stats.frontier,
         start.elapsed().as_secs_f64()
     );
-
-    final_shares(&mut client0, &mut client1).await?;
-
-    Ok(())
+    levels.push(stats);
+
+    let output = final_shares::<T, U>(cfg, client0, client1, round, &counts).await?;
+
+    // The plaintext answer counts every client in the input, under the
+    // threshold that the last level used.
+    let live = live.iter().filter(|l| **l).count();
+    let expected = sim::answer(&pop.strings(), cfg.leader().threshold, live, cfg.crawl_order());
+    let threshold = sim::threshold_count(cfg.leader().threshold, live);
+    let clients = report::Clients {
+        submitted: pop.len(),
+        accepted: nreqs,
+        rejected: nreqs - live,
+    };
+    println!("{}", report::Report::new(levels, clients, &output, pop, &expected, threshold));
+
+    Ok(output)
 }
@@ -1,29 +1,119 @@
+use crate::auth;
 use crate::dpf;
+use crate::mpc;
 use crate::prg;
 use crate::sketch;
 
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+use std::sync::{mpsc, Arc};
+use tracing::{debug, debug_span, trace};
 
 #[derive(Clone)]
 struct TreeNode<T> {
     path: Vec<bool>,
     value: T,
     key_states: Vec<dpf::EvalState>,
-    key_values: Vec<(T, T)>,
+    key_values: Arc<Vec<(T, T)>>,
 }
 
-unsafe impl<T> Send for TreeNode<T> {}
-unsafe impl<T> Sync for TreeNode<T> {}
-
 #[derive(Clone)]
 pub struct KeyCollection<T,U> {
     depth: usize,
     pub keys: Vec<(bool, sketch::SketchDPFKey<T,U>)>,
+    // Where each epoch's keys end in `keys`. Each reconcile() closes an
+    // epoch.
+    epochs: Vec<usize>,
+    // The keys that the crawl in progress reads. Key indices in the
+    // frontier, in sketches and in apply_sketch_results() count from the
+    // start of this range.
+    crawling: Range<usize>,
     frontier: Vec<TreeNode<T>>,
     frontier_last: Vec<TreeNode<U>>,
 
-    rand_stream: prg::PrgStream,
+    // Keys received over the network but not yet reconciled with the
+    // other server.
+    pending: BTreeMap<ClientId, sketch::SketchDPFKey<T,U>>,
+    // Every client ID taken since the collection began, in any epoch.
+    seen: HashSet<ClientId>,
+}
+
+// Identifies one client submission. The client sends the same ID to both
+// servers along with its key shares, so that the servers can pair up the
+// two halves of each key no matter what order they arrive in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ClientId(pub u128);
+
+// Outcome of submitting a report with KeyCollection::add_report().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ingest {
+    Accepted,
+    BadToken,
+    Replay,
+    // The key does not decode, or is for strings of another length.
+    Malformed,
+}
+
+impl ClientId {
+    pub fn random() -> ClientId {
+        ClientId(rand::random())
+    }
+}
+
+// The values at every node of one level's frontier, as they stood after
+// the crawl. The servers can go on sketching a level from its snapshot
+// after the crawl has moved on.
+#[derive(Clone)]
+pub struct FrontierSnapshot<V> {
+    values: Vec<Arc<Vec<(V, V)>>>,
+    nkeys: usize,
+}
+
+impl<V> FrontierSnapshot<V>
+where
+    V: crate::Share + Send + Sync,
+{
+    // Number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.nkeys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nkeys == 0
+    }
+
+    // Sketches keys start..end with the randomness from `seed`, which the
+    // leader draws for each level once the servers have sent their counts.
+    pub fn sketch(&self, start: usize, end: usize, seed: &prg::PrgSeed) -> Vec<sketch::SketchOutput<V>> {
+        let _span = debug_span!("sketch", start, end).entered();
+
+        assert!(start < end);
+        assert!(end <= self.len());
+
+        // sketch_vectors[i][j] = { j'th value expanded from i'th key }
+        let mut sketch_vectors = Vec::with_capacity(end - start);
+        for _ in start..end {
+            sketch_vectors.push(Vec::with_capacity(self.values.len()));
+        }
+
+        for node in &self.values {
+            for (i, vec) in sketch_vectors.iter_mut().enumerate() {
+                vec.push(node[start + i].clone());
+            }
+        }
+
+        let out = sketch_vectors
+            .par_iter()
+            .map(|vec| {
+                sketch::sketch_values(vec, &mut seed.to_rng())
+            })
+            .collect::<Vec<sketch::SketchOutput<V>>>();
+        debug!("sketched frontier");
+
+        out
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +122,38 @@ pub struct Result<T> {
     pub value: T,
 }
 
+// Queue of incoming key batches. Pushing never blocks and takes no lock,
+// so uploads can proceed while the receiving side holds the collection.
+#[derive(Clone)]
+pub struct KeySender<B> {
+    sender: mpsc::Sender<B>,
+}
+
+pub struct KeyReceiver<B> {
+    receiver: mpsc::Receiver<B>,
+}
+
+pub fn key_queue<B>() -> (KeySender<B>, KeyReceiver<B>) {
+    let (sender, receiver) = mpsc::channel();
+    (KeySender { sender }, KeyReceiver { receiver })
+}
+
+impl<B> KeySender<B> {
+    pub fn push(&self, batch: B) {
+        // The receiver lives as long as the server does.
+        self.sender
+            .send(batch)
+            .expect("Key queue receiver dropped");
+    }
+}
+
+impl<B> KeyReceiver<B> {
+    // Returns every batch pushed so far.
+    pub fn drain(&self) -> Vec<B> {
+        self.receiver.try_iter().collect()
+    }
+}
+
 impl<T,U> KeyCollection<T,U>
 where
     T: crate::Share
@@ -47,43 +169,156 @@ where
         + Send
         + Sync,
 {
-    pub fn new(seed: &prg::PrgSeed, depth: usize) -> KeyCollection<T,U> {
+    pub fn new(depth: usize) -> KeyCollection<T,U> {
         KeyCollection::<T,U> {
             depth,
             keys: vec![],
+            epochs: vec![],
+            crawling: 0..0,
             frontier: vec![],
             frontier_last: vec![],
-            rand_stream: seed.to_rng(),
+            pending: BTreeMap::new(),
+            seen: HashSet::new(),
         }
     }
 
     pub fn add_key(&mut self, key: sketch::SketchDPFKey<T,U>) {
-        assert_eq!(key.triples.len(), sketch::TRIPLES_PER_LEVEL * (self.depth-1));
-        assert_eq!(key.triples_last.len(), sketch::TRIPLES_PER_LEVEL); 
+        assert_eq!(key.data_len(), self.depth);
         self.keys.push((true, key));
     }
 
+    // Holds a client's key until reconcile(). Returns false, and keeps
+    // the key already held, if this client ID was seen before in this
+    // collection, even in an earlier epoch or dropped by reconcile().
+    pub fn add_pending(&mut self, id: ClientId, key: sketch::SketchDPFKey<T,U>) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.pending.insert(id, key);
+        true
+    }
+
+    // Holds a client's key until reconcile(), provided that its token is
+    // valid and was not used before in this collection.
+    pub fn add_report(
+        &mut self,
+        verifier: &auth::Verifier,
+        token: &auth::Token,
+        key: sketch::SketchDPFKey<T,U>,
+    ) -> Ingest {
+        if !verifier.verify(token) {
+            return Ingest::BadToken;
+        }
+        self.add_verified(token.id, key)
+    }
+
+    // Like add_report(), for a client whose token was already verified.
+    pub fn add_verified(&mut self, id: ClientId, key: sketch::SketchDPFKey<T,U>) -> Ingest {
+        if key.data_len() != self.depth {
+            return Ingest::Malformed;
+        }
+        if !self.add_pending(id, key) {
+            return Ingest::Replay;
+        }
+        Ingest::Accepted
+    }
+
+    // IDs of all pending keys, in sorted order.
+    pub fn pending_ids(&self) -> Vec<ClientId> {
+        self.pending.keys().copied().collect()
+    }
+
+    // Drops the pending keys with the given IDs, namely those that the
+    // other server never received, and closes an epoch with the pending
+    // keys left, in ID order. Returns the number of keys in the epoch.
+    pub fn reconcile(&mut self, drop: &[ClientId]) -> usize {
+        for id in drop {
+            self.pending.remove(id);
+        }
+        let n = self.add_pending_keys();
+        self.epochs.push(self.keys.len());
+        n
+    }
+
+    // Number of epochs that reconcile() has closed.
+    pub fn epochs(&self) -> usize {
+        self.epochs.len()
+    }
+
+    fn add_pending_keys(&mut self) -> usize {
+        let pending = std::mem::take(&mut self.pending);
+        let n = pending.len();
+        for (_, k) in pending {
+            self.add_key(k);
+        }
+        n
+    }
+
+    // Starts a crawl from the root over every key.
     pub fn tree_init(&mut self) {
+        self.tree_init_at(None, &[vec![]]);
+    }
+
+    // Starts a crawl over the keys of the given epochs, or over every key
+    // if `epochs` is None, from the nodes at `prefixes`. These must all
+    // have the same length, which is the level of the first crawl.
+    pub fn tree_init_at(&mut self, epochs: Option<Range<usize>>, prefixes: &[Vec<bool>]) {
+        // Keys added with add_key() come first, in the order they were
+        // added, followed by the pending keys sorted by client ID. Keys
+        // added since the last reconcile() belong to no epoch yet.
+        self.crawling = match epochs {
+            None => {
+                self.add_pending_keys();
+                0..self.keys.len()
+            }
+            Some(epochs) => {
+                assert!(!epochs.is_empty() && epochs.end <= self.epochs.len(), "Epochs {:?} not closed", epochs);
+                let start = if epochs.start == 0 { 0 } else { self.epochs[epochs.start - 1] };
+                start..self.epochs[epochs.end - 1]
+            }
+        };
+        let _span = debug_span!("init", keys = self.crawling.len(), prefixes = prefixes.len()).entered();
+
         let mut root = TreeNode::<T> {
             path: vec![],
             value: T::zero(),
             key_states: vec![],
-            key_values: vec![],
+            key_values: Arc::new(vec![]),
         };
 
-        for k in &self.keys {
+        for k in self.crawl_keys() {
             root.key_states.push(k.1.eval_init());
-            root.key_values.push((T::zero(), T::zero()));
         }
+        root.key_values = Arc::new(vec![(T::zero(), T::zero()); self.crawling.len()]);
 
-        self.frontier.clear();
+        let level = prefixes.first().map_or(0, |p| p.len());
+        self.frontier = prefixes
+            .iter()
+            .map(|prefix| {
+                assert_eq!(prefix.len(), level, "Prefixes of different lengths");
+                assert!(level < self.depth, "Prefix of {:?} bits is too long", level);
+                let mut node = root.clone();
+                for &dir in prefix {
+                    node = self.make_tree_node(&node, dir);
+                }
+                node
+            })
+            .collect();
         self.frontier_last.clear();
-        self.frontier.push(root);
+    }
+
+    fn crawl_keys(&self) -> &[(bool, sketch::SketchDPFKey<T,U>)] {
+        &self.keys[self.crawling.clone()]
+    }
+
+    // Number of keys that the crawl in progress reads.
+    pub fn crawl_len(&self) -> usize {
+        self.crawling.len()
     }
 
     fn make_tree_node(&self, parent: &TreeNode<T>, dir: bool) -> TreeNode<T> {
-        let (key_states, key_values): (Vec<dpf::EvalState>, Vec<(T, T)>) = self
-            .keys
+        let keys = self.crawl_keys();
+        let (key_states, key_values): (Vec<dpf::EvalState>, Vec<(T, T)>) = keys
             .par_iter()
             .enumerate()
             .map(|(i, key)| {
@@ -92,20 +327,13 @@ where
             })
             .unzip();
 
-        let mut child_val = T::zero();
-        for (i, v) in key_values.iter().enumerate() {
-            // Add in only live values
-            if self.keys[i].0 {
-                child_val.add_lazy(&v.0);
-            }
-        }
-        child_val.reduce();
+        let child_val = count_live(keys, &key_values);
 
         let mut child = TreeNode::<T> {
             path: parent.path.clone(),
             value: child_val,
             key_states,
-            key_values,
+            key_values: Arc::new(key_values),
         };
 
         child.path.push(dir);
@@ -115,8 +343,8 @@ where
     }
 
     fn make_tree_node_last(&self, parent: &TreeNode<T>, dir: bool) -> TreeNode<U> {
-        let (key_states, key_values): (Vec<dpf::EvalState>, Vec<(U, U)>) = self
-            .keys
+        let keys = self.crawl_keys();
+        let (key_states, key_values): (Vec<dpf::EvalState>, Vec<(U, U)>) = keys
             .par_iter()
             .enumerate()
             .map(|(i, key)| {
@@ -125,20 +353,13 @@ where
             })
             .unzip();
 
-        let mut child_val = U::zero();
-        for (i, v) in key_values.iter().enumerate() {
-            // Add in only live values
-            if self.keys[i].0 {
-                child_val.add_lazy(&v.0);
-            }
-        }
-        child_val.reduce();
+        let child_val = count_live(keys, &key_values);
 
         let mut child = TreeNode::<U> {
             path: parent.path.clone(),
             value: child_val,
             key_states,
-            key_values,
+            key_values: Arc::new(key_values),
         };
 
         child.path.push(dir);
@@ -148,7 +369,7 @@ where
     }
 
     pub fn tree_crawl(&mut self) -> Vec<T> {
-        println!("Crawl");
+        let _span = debug_span!("crawl", nodes = self.frontier.len()).entered();
         let next_frontier = self
             .frontier
             .par_iter()
@@ -166,14 +387,14 @@ where
             .iter()
             .map(|node| node.value.clone())
             .collect::<Vec<T>>();
-        println!("...done");
+        debug!(children = values.len(), "crawled");
 
         self.frontier = next_frontier;
         values
     }
 
     pub fn tree_crawl_last(&mut self) -> Vec<U> {
-        println!("Crawl");
+        let _span = debug_span!("crawl_last", nodes = self.frontier.len()).entered();
         let next_frontier = self
             .frontier
             .par_iter()
@@ -191,100 +412,96 @@ where
             .iter()
             .map(|node| node.value.clone())
             .collect::<Vec<U>>();
-        println!("...done");
+        debug!(children = values.len(), "crawled");
 
         self.frontier_last = next_frontier;
         values
     }
 
-    pub fn tree_sketch_frontier(
-        &mut self,
-        start: usize,
-        end: usize,
-    ) -> Vec<sketch::SketchOutput<T>> {
-        println!("Sketching frontier {:?} to {:?}", start, end);
-        // sketch_vectors[i][j] = { j'th value expanded from i'th key }
-
-        assert!(start < end);
-        assert!(end <= self.keys.len());
-
-        let mut sketch_vectors = Vec::with_capacity(end - start);
-        for _ in &self.keys[start..end] {
-            sketch_vectors.push(Vec::with_capacity(self.frontier.len()));
+    // Counts the frontier that tree_crawl() left again, without the keys
+    // that apply_sketch_results() has since dropped. Returns the same
+    // shares as tree_crawl() would have with those keys dead.
+    pub fn tree_recount(&mut self) -> Vec<T> {
+        let keys = &self.keys[self.crawling.clone()];
+        for node in self.frontier.iter_mut() {
+            node.value = count_live(keys, &node.key_values);
         }
+        debug!(nodes = self.frontier.len(), "recounted");
+        self.frontier.iter().map(|node| node.value.clone()).collect()
+    }
 
-        for node in &self.frontier {
-            for (i, vec) in sketch_vectors.iter_mut().enumerate() {
-                vec.push(node.key_values[start + i].clone());
-            }
+    pub fn tree_recount_last(&mut self) -> Vec<U> {
+        let keys = &self.keys[self.crawling.clone()];
+        for node in self.frontier_last.iter_mut() {
+            node.value = count_live(keys, &node.key_values);
         }
+        debug!(nodes = self.frontier_last.len(), "recounted");
+        self.frontier_last.iter().map(|node| node.value.clone()).collect()
+    }
 
-        //use cpuprofiler::PROFILER;
-        //PROFILER.lock().unwrap().start("./sketch.profile").unwrap();
-
-        let out = self
-            .keys[start..end]
-            .par_iter()
-            .enumerate()
-            .map(|(i, k)| {
-                let mut stream = self.rand_stream.clone();
-                k.1.sketch_at(&sketch_vectors[i], &mut stream)
-            })
-            .collect::<Vec<sketch::SketchOutput<T>>>();
+    // Snapshot of the frontier left by the last tree_crawl().
+    pub fn frontier_snapshot(&self) -> FrontierSnapshot<T> {
+        FrontierSnapshot {
+            values: self.frontier.iter().map(|n| n.key_values.clone()).collect(),
+            nkeys: self.crawling.len(),
+        }
+    }
 
-        //PROFILER.lock().unwrap().stop().unwrap();
-        println!("... Done");
+    // Snapshot of the frontier left by tree_crawl_last().
+    pub fn frontier_snapshot_last(&self) -> FrontierSnapshot<U> {
+        FrontierSnapshot {
+            values: self.frontier_last.iter().map(|n| n.key_values.clone()).collect(),
+            nkeys: self.crawling.len(),
+        }
+    }
 
-        out
+    pub fn tree_sketch_frontier(
+        &mut self,
+        start: usize,
+        end: usize,
+        seed: &prg::PrgSeed,
+    ) -> Vec<sketch::SketchOutput<T>> {
+        self.frontier_snapshot().sketch(start, end, seed)
     }
 
     pub fn tree_sketch_frontier_last(
         &mut self,
         start: usize,
         end: usize,
+        seed: &prg::PrgSeed,
     ) -> Vec<sketch::SketchOutput<U>> {
-        println!("Sketching frontier {:?} to {:?}", start, end);
-        // sketch_vectors[i][j] = { j'th value expanded from i'th key }
-
-        assert!(start < end);
-        assert!(end <= self.keys.len());
-
-        let mut sketch_vectors = Vec::with_capacity(end - start);
-        for _ in &self.keys[start..end] {
-            sketch_vectors.push(Vec::with_capacity(self.frontier_last.len()));
-        }
-
-        for node in &self.frontier_last {
-            for (i, vec) in sketch_vectors.iter_mut().enumerate() {
-                vec.push(node.key_values[start + i].clone());
-            }
-        }
+        self.frontier_snapshot_last().sketch(start, end, seed)
+    }
 
-        //use cpuprofiler::PROFILER;
-        //PROFILER.lock().unwrap().start("./sketch.profile").unwrap();
+    // This server's shares of each key's MAC key and its square, for the
+    // sketches of the crawl's inner levels.
+    pub fn mac_keys(&self) -> (Vec<T>, Vec<T>) {
+        self.crawl_keys().iter().map(|k| (k.1.mac_key.clone(), k.1.mac_key2.clone())).unzip()
+    }
 
-        let out = self
-            .keys[start..end]
-            .par_iter()
-            .enumerate()
-            .map(|(i, k)| {
-                let mut stream = self.rand_stream.clone();
-                k.1.sketch_at_last(&sketch_vectors[i], &mut stream)
-            })
-            .collect::<Vec<sketch::SketchOutput<U>>>();
+    pub fn mac_keys_last(&self) -> (Vec<U>, Vec<U>) {
+        self.crawl_keys().iter().map(|k| (k.1.mac_key_last.clone(), k.1.mac_key2_last.clone())).unzip()
+    }
 
-        //PROFILER.lock().unwrap().stop().unwrap();
-        println!("... Done");
+    // Keys in the crawl that have passed every sketch check so far. No
+    // honest count can be larger, and the threshold is a fraction of it.
+    pub fn live_clients(&self) -> usize {
+        self.crawl_keys().iter().filter(|k| k.0).count()
+    }
 
-        out
+    // Whether each key in the crawl is live, and so counted.
+    pub fn live_keys(&self) -> Vec<bool> {
+        self.crawl_keys().iter().map(|k| k.0).collect()
     }
 
+    // Takes one result for each key in the crawl. A key that fails stays
+    // dead in any later crawl that reads it.
     pub fn apply_sketch_results(&mut self, res: &[bool]) {
-        assert_eq!(res.len(), self.keys.len());
+        assert_eq!(res.len(), self.crawling.len());
 
         // Remove invalid keys
-        for (i, alive) in res.iter().enumerate() {
-            self.keys[i].0 &= alive;
+        for (key, alive) in self.keys[self.crawling.clone()].iter_mut().zip(res) {
+            key.0 &= alive;
         }
     }
 
@@ -314,47 +531,17 @@ where
         //println!("Size of frontier: {:?}", self.frontier.len());
     }
 
-    pub fn keep_values(nclients: usize, threshold: &T, vals0: &[T], vals1: &[T]) -> Vec<bool> {
-        assert_eq!(vals0.len(), vals1.len());
-
-        let nclients = T::from(nclients as u32);
-        let mut keep = vec![];
-        for i in 0..vals0.len() {
-            let mut v = T::zero();
-            v.add(&vals0[i]);
-            v.add(&vals1[i]);
-            //println!("-> {:?} {:?} {:?}", v, *threshold, nclients);
-
-            debug_assert!(v <= nclients);
-
-            // Keep nodes that are above threshold
-            keep.push(v >= *threshold);
-        }
-
-        keep
+    // Takes the counts that open_counts() returns, in which suspect
+    // nodes count zero and so are never kept.
+    pub fn keep_values(threshold: &T, counts: &[T]) -> Vec<bool> {
+        // Keep nodes that are above threshold
+        counts.iter().map(|v| *v >= *threshold).collect()
     }
 
-    pub fn keep_values_last(nclients: usize, threshold: &U, vals0: &[U], vals1: &[U]) -> Vec<bool> {
-        assert_eq!(vals0.len(), vals1.len());
-
-        let nclients = U::from(nclients as u32);
-        let mut keep = vec![];
-        for i in 0..vals0.len() {
-            let mut v = U::zero();
-            v.add(&vals0[i]);
-            v.add(&vals1[i]);
-            //println!("-> {:?} {:?} {:?}", v, *threshold, nclients);
-
-            debug_assert!(v <= nclients);
-
-            // Keep nodes that are above threshold
-            keep.push(v >= *threshold);
-        }
-
-        keep
+    pub fn keep_values_last(threshold: &U, counts: &[U]) -> Vec<bool> {
+        counts.iter().map(|v| *v >= *threshold).collect()
     }
 
-
     pub fn final_shares(&self) -> Vec<Result<U>> {
         let mut alive = vec![];
         for n in &self.frontier_last {
@@ -363,8 +550,10 @@ where
                 value: n.value.clone(),
             });
 
-            println!("Final {:?}, value={:?}", n.path, n.value);
+            // Never log the value: it is this server's share of the count.
+            trace!(path = ?n.path, "final share");
         }
+        debug!(paths = alive.len(), "final shares");
 
         alive
     }
@@ -389,3 +578,539 @@ where
         out
     }
 }
+
+// Adds up the values of the live keys at one node into its count.
+fn count_live<K, V>(keys: &[(bool, K)], values: &[(V, V)]) -> V
+where
+    V: crate::Group + Clone,
+{
+    let mut sum = V::zero();
+    for (key, v) in keys.iter().zip(values) {
+        if key.0 {
+            sum.add_lazy(&v.0);
+        }
+    }
+    sum.reduce();
+    sum
+}
+
+/// What the leader does when some counts at a level cannot be honest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Stop the collection, with no output.
+    #[default]
+    Abort,
+    /// Drop the suspect nodes from the crawl and go on with the rest.
+    /// The output is still written, but says that it is incomplete.
+    Quarantine,
+}
+
+/// One server's shares of the counts at each node of a crawl, and of
+/// the sums that the leader checks them against once the level has been
+/// sketched.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CountShares<V> {
+    pub counts: Vec<V>,
+    pub check: mpc::CheckShare<V>,
+}
+
+impl<V> CountShares<V>
+where
+    V: crate::Share,
+{
+    // For counts over the keys for which `live` holds, whose sketches
+    // will use `triples`, numbered from `first`.
+    pub fn new(counts: Vec<V>, first: u64, triples: &[mpc::TripleShare<V>], live: &[bool]) -> CountShares<V> {
+        CountShares {
+            counts,
+            check: mpc::CheckShare::new(first, triples, live),
+        }
+    }
+}
+
+// The servers whose counts at a level of `nodes` nodes do not match their
+// shares of the level's sketches under `seed`. `live` says which keys the
+// counts are over, and masked[b] is the sum of server b's first sketch
+// corrections over them, from ManyMulState::masked_r_x(). An honest
+// server always passes, whatever the clients and the other server do.
+pub fn misbehaving_servers<V>(
+    key: &mpc::DealerKey,
+    seed: &prg::PrgSeed,
+    nodes: usize,
+    live: &[bool],
+    shares: [&CountShares<V>; 2],
+    masked: [&V; 2],
+) -> Vec<usize>
+where
+    V: crate::Share + std::cmp::PartialEq,
+{
+    let weights = sketch::weights::<V>(seed, nodes);
+    (0..2)
+        .filter(|&b| {
+            !key.verify(b, live, &shares[b].check)
+                || !mpc::counts_match(&weights, &shares[b].counts, masked[b], &shares[b].check)
+        })
+        .collect()
+}
+
+/// The nodes of one crawl whose counts cannot be honest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Suspects {
+    /// Nodes whose count is above the number of live clients.
+    pub over_count: Vec<usize>,
+    /// Servers whose counts do not match their shares of the level's
+    /// sketches. If any, every node is suspect.
+    pub servers: Vec<usize>,
+    /// The number of nodes at the level.
+    pub frontier: usize,
+}
+
+impl Suspects {
+    pub fn is_empty(&self) -> bool {
+        self.over_count.is_empty() && self.servers.is_empty()
+    }
+
+    // Every suspect node, in order.
+    pub fn nodes(&self) -> Vec<usize> {
+        if self.servers.is_empty() {
+            self.over_count.clone()
+        } else {
+            (0..self.frontier).collect()
+        }
+    }
+
+    // Who the evidence points at. By the time a level is judged, every
+    // live key has passed its sketch check, so a count past the live
+    // clients names no server.
+    pub fn blame(&self) -> String {
+        match self.servers[..] {
+            [] => "a malformed client key passed the sketch check, or a server colludes with a client".to_string(),
+            [b] => format!("server {}'s counts do not match its shares of the sketches", b),
+            _ => "both servers' counts do not match their shares of the sketches".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Suspects {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.servers.is_empty() {
+            write!(f, "nodes {:?} count more than the live clients", self.over_count)
+        } else {
+            write!(f, "all {} nodes are suspect", self.frontier)
+        }
+    }
+}
+
+// Adds up the two servers' counts at each of `nodes` nodes, and checks
+// them against `nclients`, the number of keys live at the crawl. The
+// leader names in `servers` those whose counts do not match their
+// sketches; if any, every count is suspect, and the servers' counts may
+// not even be `nodes` long. A suspect node's count comes back as zero.
+pub fn open_counts<V>(
+    nclients: usize,
+    nodes: usize,
+    vals0: &[V],
+    vals1: &[V],
+    servers: Vec<usize>,
+) -> (Vec<V>, Suspects)
+where
+    V: crate::Share + std::cmp::PartialOrd + From<u32>,
+{
+    let mut suspects = Suspects {
+        servers,
+        frontier: nodes,
+        ..Suspects::default()
+    };
+    if !suspects.servers.is_empty() {
+        return (vec![V::zero(); nodes], suspects);
+    }
+    assert!(vals0.len() == nodes && vals1.len() == nodes);
+
+    let nclients = V::from(nclients as u32);
+    let mut counts = Vec::with_capacity(vals0.len());
+    for (i, (v0, v1)) in vals0.iter().zip(vals1).enumerate() {
+        let mut v = v0.clone();
+        v.add(v1);
+
+        if v > nclients {
+            suspects.over_count.push(i);
+            counts.push(V::zero());
+        } else {
+            counts.push(v);
+        }
+    }
+    (counts, suspects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fastfield::FE;
+    use crate::{FieldElm127, Group};
+
+    fn assert_send_sync<S: Send + Sync>() {}
+
+    #[test]
+    fn tree_node_send_sync() {
+        assert_send_sync::<TreeNode<FE>>();
+        assert_send_sync::<TreeNode<FieldElm127>>();
+        assert_send_sync::<KeyCollection<FE, FieldElm127>>();
+    }
+
+    #[test]
+    fn key_queue_drain() {
+        let (tx, rx) = key_queue::<&str>();
+        let tx2 = tx.clone();
+        std::thread::spawn(move || tx2.push("b")).join().unwrap();
+        tx.push("a");
+
+        let mut out = rx.drain();
+        out.sort();
+        assert_eq!(out, vec!["a", "b"]);
+        assert!(rx.drain().is_empty());
+    }
+
+    // Two servers' collections.
+    fn collections(strlen: usize) -> (KeyCollection<FE, FieldElm127>, KeyCollection<FE, FieldElm127>) {
+        (KeyCollection::new(strlen), KeyCollection::new(strlen))
+    }
+
+    // Runs the whole crawl over two collections whose keys are already
+    // in place and returns the strings found.
+    fn crawl(
+        col0: &mut KeyCollection<FE, FieldElm127>,
+        col1: &mut KeyCollection<FE, FieldElm127>,
+        strlen: usize,
+        nclients: usize,
+    ) -> Vec<Result<FieldElm127>> {
+        col0.tree_init();
+        col1.tree_init();
+        crawl_from(col0, col1, strlen, nclients, 0)
+    }
+
+    // Runs the rest of a crawl that tree_init_at() started at `level`.
+    fn crawl_from(
+        col0: &mut KeyCollection<FE, FieldElm127>,
+        col1: &mut KeyCollection<FE, FieldElm127>,
+        strlen: usize,
+        nclients: usize,
+        level: usize,
+    ) -> Vec<Result<FieldElm127>> {
+        let threshold = FE::from(2u32);
+        for _level in level..strlen - 1 {
+            let vals0 = col0.tree_crawl();
+            let vals1 = col1.tree_crawl();
+
+            let (counts, suspects) = open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]);
+            assert!(suspects.is_empty());
+            let keep = KeyCollection::<FE, FieldElm127>::keep_values(&threshold, &counts);
+            col0.tree_prune(&keep);
+            col1.tree_prune(&keep);
+        }
+
+        let vals0 = col0.tree_crawl_last();
+        let vals1 = col1.tree_crawl_last();
+        let (counts, suspects) = open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]);
+        assert!(suspects.is_empty());
+        let keep = KeyCollection::<FE, FieldElm127>::keep_values_last(&FieldElm127::from(2), &counts);
+        col0.tree_prune_last(&keep);
+        col1.tree_prune_last(&keep);
+
+        KeyCollection::<FE, FieldElm127>::final_values(
+            &col0.final_shares(),
+            &col1.final_shares(),
+        )
+    }
+
+    // The rayon version we depend on trips Miri's stacked borrows
+    // checks inside its own collect, so this only runs natively.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn crawl_small() {
+        let client_strings = ["a", "a", "b", "a", "c"];
+        let strlen = 8;
+
+        let (mut col0, mut col1) = collections(strlen);
+        for s in &client_strings {
+            let keys = sketch::SketchDPFKey::gen_from_str(s);
+            col0.add_key(keys[0].clone());
+            col1.add_key(keys[1].clone());
+        }
+
+        let res = crawl(&mut col0, &mut col1, strlen, client_strings.len());
+        assert_eq!(res.len(), 1);
+        assert_eq!(crate::bits_to_string(&res[0].path), "a");
+        assert_eq!(res[0].value, FieldElm127::from(3));
+    }
+
+    // A level can be sketched from its snapshot after the collection has
+    // been pruned and crawled past it.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn snapshot_outlives_crawl() {
+        let strlen = 8;
+        let (mut col, _) = collections(strlen);
+        for s in &["a", "b", "c"] {
+            let keys = sketch::SketchDPFKey::gen_from_str(s);
+            col.add_key(keys[0].clone());
+        }
+
+        col.tree_init();
+        col.tree_crawl();
+        let seed = prg::PrgSeed::random();
+        let snapshot = col.frontier_snapshot();
+        let before = col.tree_sketch_frontier(0, 3, &seed);
+
+        col.tree_prune(&[true, false]);
+        col.tree_crawl();
+        assert_ne!(col.tree_sketch_frontier(0, 3, &seed), before);
+        assert_eq!(snapshot.sketch(0, 3, &seed), before);
+        assert_eq!(snapshot.sketch(1, 3, &seed), before[1..].to_vec());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn reconcile_pairs_by_id() {
+        let client_strings = ["a", "a", "b", "a", "b"];
+        let strlen = 8;
+
+        let (mut col0, mut col1) = collections(strlen);
+        let mut shares = vec![];
+        for s in &client_strings {
+            let keys = sketch::SketchDPFKey::gen_from_str(s);
+            shares.push((ClientId::random(), keys));
+        }
+
+        // The servers see the keys in opposite orders, and each one gets
+        // a key that the other never does.
+        for (id, keys) in &shares {
+            assert!(col0.add_pending(*id, keys[0].clone()));
+        }
+        for (id, keys) in shares.iter().rev() {
+            assert!(col1.add_pending(*id, keys[1].clone()));
+        }
+        let b = sketch::SketchDPFKey::gen_from_str("b");
+        let only0 = ClientId::random();
+        let only1 = ClientId::random();
+        col0.add_pending(only0, b[0].clone());
+        col1.add_pending(only1, b[1].clone());
+
+        // A repeated ID keeps the first key.
+        assert!(!col0.add_pending(shares[0].0, b[0].clone()));
+
+        let ids0 = col0.pending_ids();
+        let ids1 = col1.pending_ids();
+        let drop0: Vec<ClientId> = ids0.iter().filter(|id| !ids1.contains(id)).copied().collect();
+        let drop1: Vec<ClientId> = ids1.iter().filter(|id| !ids0.contains(id)).copied().collect();
+        assert_eq!(drop0, vec![only0]);
+        assert_eq!(drop1, vec![only1]);
+
+        assert_eq!(col0.reconcile(&drop0), client_strings.len());
+        assert_eq!(col1.reconcile(&drop1), client_strings.len());
+        assert_eq!(col0.pending_ids(), col1.pending_ids());
+
+        let mut res = crawl(&mut col0, &mut col1, strlen, client_strings.len());
+        res.sort_by_key(|r| crate::bits_to_string(&r.path));
+        assert_eq!(res.len(), 2);
+        assert_eq!(crate::bits_to_string(&res[0].path), "a");
+        assert_eq!(res[0].value, FieldElm127::from(3));
+        assert_eq!(crate::bits_to_string(&res[1].path), "b");
+        assert_eq!(res[1].value, FieldElm127::from(2));
+    }
+
+    fn found(res: &[Result<FieldElm127>]) -> Vec<(String, FieldElm127)> {
+        res.iter().map(|r| (crate::bits_to_string(&r.path), r.value.clone())).collect()
+    }
+
+    // Each reconcile() closes an epoch. An epoch can be crawled alone, or
+    // merged with others starting from prefixes found before.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn epochs() {
+        let strlen = 16;
+        let (mut col0, mut col1) = collections(strlen);
+        for epoch in [["ab", "ab", "cd"], ["ab", "ce", "ce"]] {
+            for s in &epoch {
+                let keys = sketch::SketchDPFKey::gen_from_str(s);
+                let id = ClientId::random();
+                col0.add_pending(id, keys[0].clone());
+                col1.add_pending(id, keys[1].clone());
+            }
+            assert_eq!(col0.reconcile(&[]), 3);
+            assert_eq!(col1.reconcile(&[]), 3);
+        }
+        assert_eq!(col0.epochs(), 2);
+
+        col0.tree_init_at(Some(1..2), &[vec![]]);
+        col1.tree_init_at(Some(1..2), &[vec![]]);
+        assert_eq!(col0.crawl_len(), 3);
+        let res = crawl_from(&mut col0, &mut col1, strlen, 3, 0);
+        assert_eq!(found(&res), [("ce".to_string(), FieldElm127::from(2))]);
+
+        // The merge crawls only below "a" and "c", and counts the strings
+        // there over both epochs.
+        let prefixes = [crate::string_to_bits("a"), crate::string_to_bits("c")];
+        col0.tree_init_at(Some(0..2), &prefixes);
+        col1.tree_init_at(Some(0..2), &prefixes);
+        assert_eq!(col0.crawl_len(), 6);
+        let res = crawl_from(&mut col0, &mut col1, strlen, 6, 8);
+        assert_eq!(
+            found(&res),
+            [("ab".to_string(), FieldElm127::from(3)), ("ce".to_string(), FieldElm127::from(2))]
+        );
+
+        // A key that fails a sketch check stays dead in later crawls.
+        let failed = [true, true, true, false, false, false];
+        col0.apply_sketch_results(&failed);
+        col1.apply_sketch_results(&failed);
+        col0.tree_init_at(Some(0..2), &prefixes);
+        col1.tree_init_at(Some(0..2), &prefixes);
+        assert_eq!(col0.live_clients(), 3);
+        let res = crawl_from(&mut col0, &mut col1, strlen, 3, 8);
+        assert_eq!(found(&res), [("ab".to_string(), FieldElm127::from(2))]);
+    }
+
+    // A token sent again in a later epoch is a replay, so the merge over
+    // both epochs counts its client once.
+    #[test]
+    fn replay_across_epochs() {
+        let strlen = 16;
+        let (mut col0, _) = collections(strlen);
+        let authority = auth::Authority::new(&auth::STAND_IN_KEY);
+        let verifier = auth::Verifier::new(&auth::STAND_IN_KEY, 3);
+        let token = authority.issue(3, "client0").unwrap();
+        let keys = sketch::SketchDPFKey::gen_from_str("ab");
+
+        assert_eq!(col0.add_report(&verifier, &token, keys[0].clone()), Ingest::Accepted);
+        assert_eq!(col0.reconcile(&[]), 1);
+        assert_eq!(col0.add_report(&verifier, &token, keys[0].clone()), Ingest::Replay);
+        assert_eq!(col0.reconcile(&[]), 0);
+
+        col0.tree_init_at(Some(0..2), &[vec![]]);
+        assert_eq!(col0.crawl_len(), 1);
+    }
+
+    // Each server's counts and sums of triple shares for the level last
+    // crawled, and the servers that the leader blames once the level is
+    // sketched under a fresh seed. `tamper` may change the counts first.
+    fn check_level(
+        col0: &KeyCollection<FE, FieldElm127>,
+        col1: &KeyCollection<FE, FieldElm127>,
+        tamper: impl FnOnce(&mut [CountShares<FE>; 2]),
+    ) -> Vec<usize> {
+        let key = mpc::DealerKey::random();
+        let nkeys = col0.crawl_len();
+        let live = col0.live_keys();
+        let triples = mpc::deal_triples::<FE>(&key, 0, sketch::TRIPLES_PER_LEVEL * nkeys);
+        let cols = [col0, col1];
+        let mut shares = [0, 1].map(|b| CountShares::new(cols[b].clone().tree_recount(), 0, &triples[b], &live));
+        let nodes = shares[0].counts.len();
+        tamper(&mut shares);
+
+        let seed = prg::PrgSeed::random();
+        let masked = [0, 1].map(|b| {
+            let (mac_keys, mac_keys2) = cols[b].mac_keys();
+            let sketch = cols[b].frontier_snapshot().sketch(0, nkeys, &seed);
+            let state = mpc::ManyMulState::new(b > 0, &triples[b], &mac_keys, &mac_keys2, &sketch);
+            mpc::ManyMulState::masked_r_x(&state.cor_shares(), &live)
+        });
+        misbehaving_servers(&key, &seed, nodes, &live, [&shares[0], &shares[1]], [&masked[0], &masked[1]])
+    }
+
+    // A server that shifts its share of a count fails the check of its
+    // counts against its shares of the sketches, which names it. It
+    // cannot shift the sum of its triple shares to match either.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn tampered_count_names_server() {
+        let strlen = 8;
+        let (mut col0, mut col1) = collections(strlen);
+        for s in &["a", "b", "a"] {
+            let keys = sketch::SketchDPFKey::gen_from_str(s);
+            col0.add_key(keys[0].clone());
+            col1.add_key(keys[1].clone());
+        }
+        col0.tree_init();
+        col1.tree_init();
+        col0.tree_crawl();
+        col1.tree_crawl();
+
+        assert!(check_level(&col0, &col1, |_| ()).is_empty());
+        let shift = |s: &mut [CountShares<FE>; 2]| s[1].counts[1].add(&FE::from(1u32));
+        assert_eq!(check_level(&col0, &col1, shift), [1]);
+        let shift_both = |s: &mut [CountShares<FE>; 2]| {
+            s[0].counts[0].add(&FE::from(1u32));
+            s[0].check.a.add(&FE::from(1u32));
+        };
+        assert_eq!(check_level(&col0, &col1, shift_both), [0]);
+
+        // However the leader comes to blame a server, every node of the
+        // level is suspect.
+        let (counts, suspects) = open_counts::<FE>(3, 2, &[], &[FE::zero()], vec![1]);
+        assert_eq!(counts, [FE::zero(), FE::zero()]);
+        assert_eq!(suspects.nodes(), [0, 1]);
+        assert_eq!(suspects.to_string(), "all 2 nodes are suspect");
+        assert_eq!(suspects.blame(), "server 1's counts do not match its shares of the sketches");
+    }
+
+    // A client that claims a large weight passes no check on its own
+    // values, but its count gives it away. The servers are not to blame,
+    // since their counts still match their shares of the sketches.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn malformed_keys_are_suspect() {
+        let strlen = 8;
+        let (mut col0, mut col1) = collections(strlen);
+        let heavy = sketch::SketchDPFKey::gen(
+            &crate::string_to_bits("b"),
+            &[FE::from(10u32); 7],
+            &FieldElm127::from(10u32),
+        );
+        for keys in [sketch::SketchDPFKey::gen_from_str("a"), heavy] {
+            col0.add_key(keys[0].clone());
+            col1.add_key(keys[1].clone());
+        }
+        col0.tree_init();
+        col1.tree_init();
+        let (vals0, vals1) = (col0.tree_crawl(), col1.tree_crawl());
+        assert!(check_level(&col0, &col1, |_| ()).is_empty());
+        let (counts, suspects) = open_counts(2, 2, &vals0, &vals1, vec![]);
+        assert_eq!(counts, [FE::zero(), FE::from(1u32)]);
+        assert_eq!(suspects.over_count, [0]);
+        assert_eq!(suspects.nodes(), [0]);
+        assert!(suspects.blame().contains("malformed client key"));
+
+        // Once the heavy key fails its sketch check, it stops counting,
+        // at the level already crawled as at the next.
+        col0.apply_sketch_results(&[true, false]);
+        col1.apply_sketch_results(&[true, false]);
+        assert_eq!(col0.live_clients(), 1);
+        assert_eq!(col0.live_keys(), [true, false]);
+        let (counts, suspects) = open_counts(1, 2, &col0.tree_recount(), &col1.tree_recount(), vec![]);
+        assert_eq!(counts, [FE::zero(), FE::from(1u32)]);
+        assert!(suspects.is_empty());
+        assert!(check_level(&col0, &col1, |_| ()).is_empty());
+        let (_, suspects) = open_counts(1, 4, &col0.tree_crawl(), &col1.tree_crawl(), vec![]);
+        assert!(suspects.is_empty());
+
+        // A key whose halves do not match counts garbage at every node.
+        let (mut col0, mut col1) = collections(strlen);
+        let [k0, _] = sketch::SketchDPFKey::gen_from_str("a");
+        let [_, k1] = sketch::SketchDPFKey::gen_from_str("a");
+        col0.add_key(k0);
+        col1.add_key(k1);
+        col0.tree_init();
+        col1.tree_init();
+        let (_, suspects) = open_counts(1, 2, &col0.tree_crawl(), &col1.tree_crawl(), vec![]);
+        assert_eq!(suspects.nodes(), [0, 1]);
+        assert!(check_level(&col0, &col1, |_| ()).is_empty());
+
+        col0.apply_sketch_results(&[false]);
+        col1.apply_sketch_results(&[false]);
+        let (counts, suspects) = open_counts(0, 2, &col0.tree_recount(), &col1.tree_recount(), vec![]);
+        assert_eq!(counts, [FE::zero(), FE::zero()]);
+        assert!(suspects.is_empty());
+    }
+}
@@ -1,59 +1,554 @@
+use crate::collect;
+use crate::envelope;
+use crate::input;
+use crate::mpc;
+use crate::rpc;
+use crate::sim;
+
 use clap::{App, Arg};
-use serde_json::Value;
-use std::{fs, net::SocketAddr};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io, net::SocketAddr, time::Duration};
+
+/// Prime fields available for the inner and last levels of the tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldType {
+    /// `fastfield::FE`, modulo 2^62 - 2^30 - 1.
+    #[serde(rename = "fe62")]
+    FE62,
+    /// `FieldElm127`, modulo 2^127 - 1.
+    #[serde(rename = "m127")]
+    M127,
+    /// `FieldElm255`, modulo 2^255 - 19.
+    #[serde(rename = "p255")]
+    P255,
+}
+
+/// Fields used at the inner levels and at the last level of the tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldPair {
+    pub inner: FieldType,
+    pub last: FieldType,
+}
+
+impl FieldType {
+    pub fn from_name(name: &str) -> Option<FieldType> {
+        match name {
+            "fe62" => Some(FieldType::FE62),
+            "m127" => Some(FieldType::M127),
+            "p255" => Some(FieldType::P255),
+            _ => None,
+        }
+    }
+
+    /// Approximate bit length of the field modulus.
+    pub fn bits(self) -> usize {
+        match self {
+            FieldType::FE62 => 62,
+            FieldType::M127 => 127,
+            FieldType::P255 => 255,
+        }
+    }
+}
 
+impl FieldPair {
+    /// Pairs that the server and leader know how to dispatch on.
+    pub const SUPPORTED: [FieldPair; 5] = [
+        FieldPair {
+            inner: FieldType::FE62,
+            last: FieldType::FE62,
+        },
+        FieldPair {
+            inner: FieldType::FE62,
+            last: FieldType::M127,
+        },
+        FieldPair {
+            inner: FieldType::FE62,
+            last: FieldType::P255,
+        },
+        FieldPair {
+            inner: FieldType::M127,
+            last: FieldType::M127,
+        },
+        FieldPair {
+            inner: FieldType::P255,
+            last: FieldType::P255,
+        },
+    ];
+
+    pub fn is_supported(&self) -> bool {
+        FieldPair::SUPPORTED.contains(self)
+    }
+}
+
+impl Default for FieldPair {
+    fn default() -> Self {
+        FieldPair {
+            inner: FieldType::FE62,
+            last: FieldType::P255,
+        }
+    }
+}
+
+/// Parameters that every party shares, along with a section for each
+/// role that reads the file. A server's file has a `server` section, and
+/// the file that the leader and dealer read has `leader` and `client`
+/// sections.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub data_len: usize,
+    #[serde(default = "default_inner_field")]
+    pub inner_field: FieldType,
+    #[serde(default = "default_last_field")]
+    pub last_field: FieldType,
+    pub server: Option<ServerConfig>,
+    pub leader: Option<LeaderConfig>,
+    pub client: Option<ClientConfig>,
+}
+
+/// One server's own settings.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Zero-indexed ID of the server.
+    pub id: u16,
+    /// Where to serve RPCs.
+    pub listen: SocketAddr,
+    /// Secret, in hex, that the server derives its long-term HPKE key pair
+    /// from. Clients pin the public key, which the server prints at
+    /// startup.
+    pub hpke_secret: String,
+    /// Where to serve the public HTTP front end for client uploads, if
+    /// anywhere.
+    #[serde(default)]
+    pub http_listen: Option<SocketAddr>,
+    /// Where to serve the HTTP front end for every call, if anywhere. It
+    /// has no authentication, so it must not be reachable by the public.
+    #[serde(default)]
+    pub http_control_listen: Option<SocketAddr>,
+    /// Largest HTTP request body to take, in MiB.
+    #[serde(default = "default_http_max_body_mb")]
+    pub http_max_body_mb: usize,
+    /// Where to serve Prometheus metrics, if anywhere.
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Largest RPC message to take from a peer, in MiB.
+    #[serde(default = "default_max_message_mb")]
+    pub max_message_mb: usize,
+}
+
+/// Settings for the leader, which the dealer also uses to reach the
+/// servers.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LeaderConfig {
+    /// RPC addresses of servers 0 and 1.
+    pub servers: [SocketAddr; 2],
+    pub threshold: f64,
+    #[serde(default = "default_addkey_batch_size")]
     pub addkey_batch_size: usize,
+    #[serde(default = "default_sketch_batch_size")]
     pub sketch_batch_size: usize,
+    #[serde(default = "default_sketch_batch_size_last")]
     pub sketch_batch_size_last: usize,
-    pub num_sites: usize,
-    pub threshold: f64,
-    pub zipf_exponent: f64,
-    pub server0: SocketAddr,
-    pub server1: SocketAddr,
-}
-
-fn parse_ip(v: &Value, error_msg: &str) -> SocketAddr {
-    v.as_str().expect(error_msg).parse().expect(error_msg)
-}
-
-pub fn get_config(filename: &str) -> Config {
-    let json_data = &fs::read_to_string(filename).expect("Cannot open JSON file");
-    let v: Value = serde_json::from_str(json_data).expect("Cannot parse JSON config");
-
-    let data_len: usize = v["data_len"].as_u64().expect("Can't parse data_len") as usize;
-    let addkey_batch_size: usize = v["addkey_batch_size"]
-        .as_u64()
-        .expect("Can't parse addkey_batch_size") as usize;
-    let sketch_batch_size: usize = v["sketch_batch_size"]
-        .as_u64()
-        .expect("Can't parse sketch_batch_size") as usize;
-    let sketch_batch_size_last: usize = v["sketch_batch_size_last"]
-        .as_u64()
-        .expect("Can't parse sketch_batch_size_last") as usize;
-    let num_sites: usize = v["num_sites"].as_u64().expect("Can't parse num_sites") as usize;
-    let threshold = v["threshold"].as_f64().expect("Can't parse threshold");
-    let zipf_exponent = v["zipf_exponent"]
-        .as_f64()
-        .expect("Can't parse zipf_exponent");
-    let server0 = parse_ip(&v["server0"], "Can't parse server0 addr");
-    let server1 = parse_ip(&v["server1"], "Can't parse server1 addr");
-
-    Config {
-        data_len,
-        addkey_batch_size,
-        sketch_batch_size,
-        sketch_batch_size_last,
-        num_sites,
-        threshold,
-        zipf_exponent,
-        server0,
-        server1,
-    }
-}
-
-pub fn get_args(name: &str, get_server_id: bool, get_n_reqs: bool) -> (Config, i8, usize) {
+    /// How long to wait for each RPC, and how many times to try it.
+    #[serde(default = "default_rpc_timeout_secs")]
+    pub rpc_timeout_secs: u64,
+    #[serde(default = "default_rpc_attempts")]
+    pub rpc_attempts: u32,
+    /// Largest RPC reply to take from a server, in MiB.
+    #[serde(default = "default_max_message_mb")]
+    pub max_message_mb: usize,
+    /// Where to serve Prometheus metrics, if anywhere.
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// The secret that the dealer MACs each server's shares of the
+    /// triples under, DEALER_SECRET_LEN bytes in hex. The leader checks
+    /// the servers' counts with it, so the servers must never see it.
+    pub dealer_secret: String,
+    /// What to do when some counts at a level cannot be honest.
+    #[serde(default)]
+    pub on_misbehaviour: collect::Policy,
+    /// Number of epochs that the clients arrive in, each crawled alone
+    /// and then merged with the ones before it.
+    #[serde(default = "default_epochs")]
+    pub epochs: usize,
+    /// Length in bits of the prefixes that a merge crawls again from.
+    #[serde(default = "default_merge_level")]
+    pub merge_level: usize,
+    /// How the crawl settles the fields of client.fields.
+    #[serde(default)]
+    pub field_order: FieldOrder,
+}
+
+/// The order in which the leader settles the fields of each tuple.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldOrder {
+    /// All fields at once, for the heavy tuples.
+    #[default]
+    Joint,
+    /// The heavy values of the first field, and then under each one the
+    /// heavy values of the rest among the clients that hold it.
+    FirstField,
+}
+
+/// How the leader simulates clients: either sampling random strings from
+/// a Zipf distribution, or replaying the strings in a file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    #[serde(default)]
+    pub num_sites: Option<usize>,
+    #[serde(default)]
+    pub zipf_exponent: Option<f64>,
+    #[serde(default)]
+    pub input: Option<input::Source>,
+    /// The fields of each input string, which is then a tuple. Their
+    /// widths add up to data_len.
+    #[serde(default)]
+    pub fields: Option<Vec<input::Field>>,
+    /// HPKE public keys of servers 0 and 1, in hex, which clients encrypt
+    /// their key shares to.
+    pub server_public_keys: [String; 2],
+}
+
+impl ClientConfig {
+    pub fn server_public_keys(&self) -> [envelope::PublicKey; 2] {
+        self.server_public_keys
+            .clone()
+            .map(|k| envelope::PublicKey::from_hex(&k).expect("validated at load"))
+    }
+
+    /// Draws or loads the strings of up to `nclients` clients.
+    pub fn population(&self, data_len: usize, nclients: usize) -> io::Result<input::Population> {
+        match (&self.input, self.num_sites, self.zipf_exponent) {
+            (Some(source), _, _) => {
+                let mut pop = match &self.fields {
+                    Some(fields) => input::Population::load_tuples(source, fields)?,
+                    None => input::Population::load(source, data_len)?,
+                };
+                pop.truncate(nclients);
+                Ok(pop)
+            }
+            (None, Some(num_sites), Some(exponent)) => {
+                input::Population::zipf(num_sites, exponent, nclients, data_len)
+            }
+            _ => unreachable!("validated at load"),
+        }
+    }
+}
+
+fn default_inner_field() -> FieldType {
+    FieldPair::default().inner
+}
+
+fn default_last_field() -> FieldType {
+    FieldPair::default().last
+}
+
+fn default_addkey_batch_size() -> usize {
+    100
+}
+
+fn default_sketch_batch_size() -> usize {
+    100000
+}
+
+fn default_sketch_batch_size_last() -> usize {
+    25000
+}
+
+fn default_rpc_timeout_secs() -> u64 {
+    3600
+}
+
+fn default_rpc_attempts() -> u32 {
+    5
+}
+
+fn default_http_max_body_mb() -> usize {
+    16
+}
+
+// Cap on http_max_body_mb. Every request body is buffered whole, and the
+// upload front end faces the public.
+const MAX_HTTP_BODY_MB: usize = 1024;
+
+fn default_max_message_mb() -> usize {
+    rpc::DEFAULT_MAX_MESSAGE_LEN >> 20
+}
+
+// Cap on max_message_mb, so that a typo cannot open the door to
+// messages that exhaust memory.
+const MAX_MESSAGE_MB: usize = 4096;
+
+fn default_epochs() -> usize {
+    1
+}
+
+fn default_merge_level() -> usize {
+    8
+}
+
+/// Sections that a role needs in its config file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Server,
+    Leader,
+    Client,
+}
+
+impl Section {
+    fn name(self) -> &'static str {
+        match self {
+            Section::Server => "server",
+            Section::Leader => "leader",
+            Section::Client => "client",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, io::Error),
+    Invalid(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(file, e) => write!(f, "cannot read config {}: {}", file, e),
+            ConfigError::Invalid(file, e) => write!(f, "invalid config {}: {}", file, e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn check(ok: bool, msg: impl FnOnce() -> String) -> Result<(), String> {
+    if ok {
+        Ok(())
+    } else {
+        Err(msg())
+    }
+}
+
+impl LeaderConfig {
+    pub fn retry_policy(&self) -> rpc::RetryPolicy {
+        rpc::RetryPolicy {
+            timeout: Duration::from_secs(self.rpc_timeout_secs),
+            attempts: self.rpc_attempts,
+        }
+    }
+
+    pub fn max_message_len(&self) -> usize {
+        self.max_message_mb << 20
+    }
+
+    pub fn dealer_key(&self) -> mpc::DealerKey {
+        mpc::DealerKey::from_secret_hex(&self.dealer_secret).expect("validated at load")
+    }
+}
+
+impl ServerConfig {
+    pub fn keypair(&self) -> envelope::KeyPair {
+        envelope::KeyPair::from_secret_hex(&self.hpke_secret).expect("validated at load")
+    }
+
+    pub fn max_message_len(&self) -> usize {
+        self.max_message_mb << 20
+    }
+
+    pub fn http_max_body_len(&self) -> usize {
+        self.http_max_body_mb << 20
+    }
+}
+
+fn check_message_mb(section: &str, mb: usize) -> Result<(), String> {
+    check(mb > 0 && mb <= MAX_MESSAGE_MB, || {
+        format!("{}.max_message_mb must lie in 1..={}, not {}", section, MAX_MESSAGE_MB, mb)
+    })
+}
+
+impl Config {
+    pub fn fields(&self) -> FieldPair {
+        FieldPair {
+            inner: self.inner_field,
+            last: self.last_field,
+        }
+    }
+
+    pub fn server(&self) -> &ServerConfig {
+        self.server.as_ref().expect("Config has no server section")
+    }
+
+    pub fn leader(&self) -> &LeaderConfig {
+        self.leader.as_ref().expect("Config has no leader section")
+    }
+
+    // The order of the leader's crawl, in bits of the strings.
+    pub fn crawl_order(&self) -> sim::CrawlOrder {
+        match (self.leader().field_order, &self.client().fields) {
+            (FieldOrder::FirstField, Some(fields)) => sim::CrawlOrder::Within(8 * fields[0].bytes),
+            _ => sim::CrawlOrder::Joint,
+        }
+    }
+
+    pub fn client(&self) -> &ClientConfig {
+        self.client.as_ref().expect("Config has no client section")
+    }
+
+    /// Parses a config from JSON and checks that it has the given sections
+    /// and sensible values.
+    pub fn from_json(json: &str, sections: &[Section]) -> Result<Config, String> {
+        let de = &mut serde_json::Deserializer::from_str(json);
+        let cfg: Config = serde_path_to_error::deserialize(de).map_err(|e| {
+            if e.path().iter().next().is_none() {
+                e.inner().to_string()
+            } else {
+                format!("{}: {}", e.path(), e.inner())
+            }
+        })?;
+        cfg.validate(sections)?;
+        Ok(cfg)
+    }
+
+    pub fn validate(&self, sections: &[Section]) -> Result<(), String> {
+        for &section in sections {
+            let present = match section {
+                Section::Server => self.server.is_some(),
+                Section::Leader => self.leader.is_some(),
+                Section::Client => self.client.is_some(),
+            };
+            check(present, || format!("missing section `{}`", section.name()))?;
+        }
+
+        check(self.data_len > 0 && self.data_len.is_multiple_of(8), || {
+            format!("data_len must be a positive multiple of 8, not {}", self.data_len)
+        })?;
+        check(self.fields().is_supported(), || {
+            format!(
+                "unsupported inner_field/last_field combination {:?}/{:?}",
+                self.inner_field, self.last_field
+            )
+        })?;
+
+        if let Some(server) = &self.server {
+            check(server.id < 2, || format!("server.id must be 0 or 1, not {}", server.id))?;
+            check(envelope::KeyPair::from_secret_hex(&server.hpke_secret).is_some(), || {
+                format!("server.hpke_secret must be {} bytes in hex", envelope::SECRET_LEN)
+            })?;
+            check_message_mb("server", server.max_message_mb)?;
+            check(server.http_max_body_mb > 0 && server.http_max_body_mb <= MAX_HTTP_BODY_MB, || {
+                format!(
+                    "server.http_max_body_mb must lie in 1..={}, not {}",
+                    MAX_HTTP_BODY_MB, server.http_max_body_mb
+                )
+            })?;
+            check(server.http_listen.is_none() || server.http_listen != server.http_control_listen, || {
+                "server.http_listen and http_control_listen must differ".to_string()
+            })?;
+        }
+
+        if let Some(leader) = &self.leader {
+            check(leader.threshold > 0.0 && leader.threshold < 1.0, || {
+                format!("leader.threshold must lie in (0, 1), not {}", leader.threshold)
+            })?;
+            for (name, size) in [
+                ("addkey_batch_size", leader.addkey_batch_size),
+                ("sketch_batch_size", leader.sketch_batch_size),
+                ("sketch_batch_size_last", leader.sketch_batch_size_last),
+            ] {
+                check(size > 0, || format!("leader.{} must be nonzero", name))?;
+            }
+            check(leader.rpc_timeout_secs > 0, || {
+                "leader.rpc_timeout_secs must be nonzero".to_string()
+            })?;
+            check(leader.rpc_attempts > 0, || {
+                "leader.rpc_attempts must be nonzero".to_string()
+            })?;
+            check_message_mb("leader", leader.max_message_mb)?;
+            check(mpc::DealerKey::from_secret_hex(&leader.dealer_secret).is_some(), || {
+                format!("leader.dealer_secret must be {} bytes in hex", mpc::DEALER_SECRET_LEN)
+            })?;
+            check(leader.epochs > 0, || "leader.epochs must be nonzero".to_string())?;
+            check(leader.epochs == 1 || leader.merge_level < self.data_len, || {
+                format!("leader.merge_level must be less than data_len, not {}", leader.merge_level)
+            })?;
+        }
+
+        if let Some(client) = &self.client {
+            for (i, key) in client.server_public_keys.iter().enumerate() {
+                check(envelope::PublicKey::from_hex(key).is_some(), || {
+                    format!("client.server_public_keys[{}] is not an X25519 public key in hex", i)
+                })?;
+            }
+            match (&client.input, client.num_sites, client.zipf_exponent) {
+                (Some(_), None, None) => (),
+                (Some(_), _, _) => {
+                    return Err("client.input replaces num_sites and zipf_exponent".to_string())
+                }
+                (None, Some(num_sites), Some(exponent)) => {
+                    check(num_sites > 0, || "client.num_sites must be nonzero".to_string())?;
+                    check(exponent > 0.0, || {
+                        format!("client.zipf_exponent must be positive, not {}", exponent)
+                    })?;
+                }
+                (None, _, _) => {
+                    return Err("client needs either input, or num_sites and zipf_exponent".to_string())
+                }
+            }
+            if let Some(fields) = &client.fields {
+                check(client.input.is_some(), || "client.fields needs client.input".to_string())?;
+                check(fields.len() > 1, || "client.fields needs at least two fields".to_string())?;
+                for f in fields {
+                    check(f.bytes > 0, || format!("client.fields: {:?} needs a nonzero width", f.name))?;
+                }
+                let bits = 8 * fields.iter().map(|f| f.bytes).sum::<usize>();
+                check(bits == self.data_len, || {
+                    format!("client.fields add up to {} bits, not data_len {}", bits, self.data_len)
+                })?;
+            }
+            if let Some(input::Source::Csv { column, .. }) = &client.input {
+                match (column, &client.fields) {
+                    (None, None) => return Err("client.input.csv needs a column".to_string()),
+                    (Some(_), Some(_)) => {
+                        return Err("client.input.csv reads each field from its own column, not one".to_string())
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        if let (Some(leader), Some(client)) = (&self.leader, &self.client) {
+            if leader.field_order == FieldOrder::FirstField {
+                let Some(fields) = &client.fields else {
+                    return Err("leader.field_order first_field needs client.fields".to_string());
+                };
+                check(leader.epochs == 1 || leader.merge_level < 8 * fields[0].bytes, || {
+                    format!(
+                        "leader.merge_level must be less than the first field's {} bits, not {}",
+                        8 * fields[0].bytes,
+                        leader.merge_level
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn get_config(filename: &str, sections: &[Section]) -> Result<Config, ConfigError> {
+    let json = fs::read_to_string(filename).map_err(|e| ConfigError::Read(filename.into(), e))?;
+    Config::from_json(&json, sections).map_err(|e| ConfigError::Invalid(filename.into(), e))
+}
+
+/// Reads the command line and the config file that it names, which must
+/// have the given sections. Exits with an error message if either one is
+/// bad.
+pub fn get_args(name: &str, sections: &[Section], get_n_reqs: bool) -> (Config, usize) {
     let mut flags = App::new(name)
         .version("0.1")
         .author("Henry Corrigan-Gibbs <henrycg@csail.mit.edu>")
@@ -68,18 +563,6 @@ pub fn get_args(name: &str, get_server_id: bool, get_n_reqs: bool) -> (Config, i
                 .takes_value(true),
         );
 
-    if get_server_id {
-        flags = flags.arg(
-            Arg::with_name("server_id")
-                .short("i")
-                .long("server_id")
-                .value_name("NUMBER")
-                .help("Zero-indexed ID of server")
-                .required(true)
-                .takes_value(true),
-        );
-    }
-
     if get_n_reqs {
         flags = flags.arg(
             Arg::with_name("num_requests")
@@ -94,19 +577,215 @@ pub fn get_args(name: &str, get_server_id: bool, get_n_reqs: bool) -> (Config, i
 
     let flags = flags.get_matches();
 
-    let mut server_id = -1;
-    if get_server_id {
-        server_id = flags.value_of("server_id").unwrap().parse().unwrap();
-    }
-
     let mut n_reqs = 0;
     if get_n_reqs {
-        n_reqs = flags.value_of("num_requests").unwrap().parse().unwrap();
+        n_reqs = flags
+            .value_of("num_requests")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e| clap::Error::value_validation_auto(format!("num_requests: {}", e)).exit());
+    }
+
+    let cfg = get_config(flags.value_of("config").unwrap(), sections).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(1)
+    });
+
+    (cfg, n_reqs)
+}
+
+/// Sends tracing events to stderr. `RUST_LOG` picks what to show, as in
+/// `RUST_LOG=counttree=debug`; the default is `info`, with tarpc's own
+/// per-request events held back to warnings.
+pub fn init_logging() {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info,tarpc=warn"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEADER: &str = r#"{
+        "data_len": 64,
+        "leader": { "servers": ["127.0.0.1:8000", "127.0.0.1:8001"], "threshold": 0.1,
+            "dealer_secret": "5f0e8a21c47b93d6e2a1f04b68c3d95e" },
+        "client": { "num_sites": 10, "zipf_exponent": 1.03, "server_public_keys": [
+            "3c9d06990b7f72d71cdadf2395fc45dba7ba4234b6a4ae3c00a7447e9ad92053",
+            "037c1e561e95baaa86fc1ff0b1d519cab10ffc396b5ba79e90fe756140e49a1a"
+        ] }
+    }"#;
+
+    fn threshold(value: &str) -> String {
+        LEADER.replace(r#""threshold": 0.1"#, &format!(r#""threshold": {}"#, value))
+    }
+
+    fn leader_with(key: &str, value: &str) -> String {
+        LEADER.replace(r#""threshold": 0.1"#, &format!(r#""threshold": 0.1, "{}": {}"#, key, value))
+    }
+
+    #[test]
+    fn examples_parse() {
+        let leader = &[Section::Leader, Section::Client];
+        let cfg = Config::from_json(include_str!("bin/config.json"), leader).unwrap();
+        Config::from_json(include_str!("bin/amazon.json"), leader).unwrap_err();
+
+        // The example servers' keys are the ones that the example clients
+        // pin.
+        let pinned = cfg.client().server_public_keys();
+        for (id, json) in [include_str!("bin/server0.json"), include_str!("bin/server1.json")].iter().enumerate() {
+            let cfg = Config::from_json(json, &[Section::Server]).unwrap();
+            assert_eq!(cfg.server().id as usize, id);
+            assert_eq!(cfg.server().keypair().public_key(), pinned[id]);
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let cfg = Config::from_json(LEADER, &[Section::Leader, Section::Client]).unwrap();
+        assert_eq!(cfg.fields(), FieldPair::default());
+        let leader = cfg.leader();
+        assert_eq!(leader.addkey_batch_size, 100);
+        assert_eq!(leader.rpc_attempts, 5);
+        assert_eq!(leader.max_message_len(), rpc::DEFAULT_MAX_MESSAGE_LEN);
+        assert_eq!(leader.on_misbehaviour, collect::Policy::Abort);
+        assert_eq!((leader.epochs, leader.merge_level), (1, 8));
+        assert!(cfg.server.is_none());
+
+        let cfg = Config::from_json(&leader_with("on_misbehaviour", r#""quarantine""#), &[]).unwrap();
+        assert_eq!(cfg.leader().on_misbehaviour, collect::Policy::Quarantine);
+    }
+
+    #[test]
+    fn fields() {
+        let json = LEADER.replace(r#""data_len": 64,"#, r#""data_len": 64, "inner_field": "m127", "last_field": "m127","#);
+        let cfg = Config::from_json(&json, &[]).unwrap();
+        assert_eq!(cfg.fields().inner, FieldType::M127);
+
+        let json = LEADER.replace(r#""data_len": 64,"#, r#""data_len": 64, "inner_field": "p255", "last_field": "fe62","#);
+        let err = Config::from_json(&json, &[]).unwrap_err();
+        assert!(err.contains("unsupported"), "{}", err);
+    }
+
+    #[test]
+    fn client_input() {
+        let json = LEADER.replace(
+            r#""num_sites": 10, "zipf_exponent": 1.03"#,
+            r#""input": { "csv": { "file": "log.csv", "column": "url" } }"#,
+        );
+        let cfg = Config::from_json(&json, &[Section::Client]).unwrap();
+        let source = input::Source::Csv {
+            file: "log.csv".into(),
+            column: Some("url".to_string()),
+        };
+        assert_eq!(cfg.client().input, Some(source));
+        assert_eq!(cfg.crawl_order(), sim::CrawlOrder::Joint);
     }
 
-    (
-        get_config(flags.value_of("config").unwrap()),
-        server_id,
-        n_reqs,
-    )
+    #[test]
+    fn client_fields() {
+        let fields = r#""input": { "csv": { "file": "log.csv" } },
+            "fields": [{ "name": "country", "bytes": 2 }, { "name": "url", "bytes": 6 }]"#;
+        let json = leader_with("field_order", r#""first_field""#)
+            .replace(r#""num_sites": 10, "zipf_exponent": 1.03"#, fields);
+        let cfg = Config::from_json(&json, &[Section::Leader, Section::Client]).unwrap();
+        let client = cfg.client().fields.as_ref().unwrap();
+        assert_eq!((client[0].name.as_str(), client[1].bytes), ("country", 6));
+        assert_eq!(cfg.crawl_order(), sim::CrawlOrder::Within(16));
+
+        let bad = [
+            (json.replace(r#""bytes": 6"#, r#""bytes": 5"#), "fields add up to 56 bits, not data_len 64"),
+            (json.replace(r#""bytes": 2"#, r#""bytes": 0"#), "\"country\" needs a nonzero width"),
+            (json.replace(r#""log.csv""#, r#""log.csv", "column": "url""#), "reads each field from its own column"),
+            (
+                json.replace(r#""first_field""#, r#""first_field", "epochs": 2, "merge_level": 16"#),
+                "first field's 16 bits, not 16",
+            ),
+            (json.replace(r#", { "name": "url", "bytes": 6 }"#, ""), "at least two fields"),
+        ];
+        for (json, msg) in &bad {
+            let err = Config::from_json(json, &[]).unwrap_err();
+            assert!(err.contains(msg), "expected {:?} in {:?}", msg, err);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let bad = [
+            (LEADER.replace("\"data_len\": 64", "\"data_len\": 60"), "data_len"),
+            (threshold("1.0"), "threshold"),
+            (threshold("0"), "threshold"),
+            (threshold(r#""high""#), "leader.threshold: invalid type"),
+            (leader_with("sketch_batch_size", "0"), "sketch_batch_size"),
+            (leader_with("rpc_attempts", "0"), "rpc_attempts"),
+            (leader_with("max_message_mb", "0"), "leader.max_message_mb must lie in 1..=4096, not 0"),
+            (leader_with("max_message_mb", "16384"), "leader.max_message_mb must lie in 1..=4096"),
+            (leader_with("epochs", "0"), "epochs"),
+            (leader_with("epochs", "2, \"merge_level\": 64"), "merge_level must be less than data_len"),
+            (leader_with("on_misbehaviour", r#""ignore""#), "leader.on_misbehaviour: unknown variant `ignore`"),
+            (leader_with("thresold", "0.2"), "leader.thresold: unknown field `thresold`"),
+            (LEADER.replace(r#", "threshold": 0.1"#, ""), "missing field `threshold`"),
+            (LEADER.replace("5f0e8a", "5f0e8"), "leader.dealer_secret must be 16 bytes in hex"),
+            (LEADER.replace("d95e", "d95e00"), "leader.dealer_secret must be 16 bytes in hex"),
+            (LEADER.replace("127.0.0.1:8001", "nowhere"), "leader.servers[1]: invalid socket address"),
+            (LEADER.replace("\"num_sites\": 10", "\"num_sites\": 0"), "num_sites"),
+            (LEADER.replace(r#""num_sites": 10, "#, ""), "either input, or num_sites"),
+            (
+                LEADER.replace(r#""num_sites": 10"#, r#""input": { "lines": "in.txt" }"#),
+                "input replaces",
+            ),
+            (
+                LEADER.replace(r#""num_sites": 10, "zipf_exponent": 1.03"#, r#""input": { "tsv": "in.tsv" }"#),
+                "client.input: unknown variant `tsv`",
+            ),
+            (
+                LEADER.replace(
+                    r#""num_sites": 10, "zipf_exponent": 1.03"#,
+                    r#""input": { "csv": { "file": "a.csv" } }"#,
+                ),
+                "client.input.csv needs a column",
+            ),
+            (leader_with("field_order", r#""first_field""#), "first_field needs client.fields"),
+            (LEADER.replace("037c1e", "037c1"), "client.server_public_keys[1] is not an X25519 public key"),
+            (LEADER.replace(r#", "server_public_keys": ["#, r#", "keys": ["#), "client.keys: unknown field"),
+        ];
+        for (json, msg) in &bad {
+            let err = Config::from_json(json, &[]).unwrap_err();
+            assert!(err.contains(msg), "expected {:?} in {:?}", msg, err);
+        }
+    }
+
+    #[test]
+    fn server_http() {
+        let server = r#"{ "data_len": 16, "server": { "id": 0, "listen": "127.0.0.1:8000",
+            "hpke_secret": "0dd16b934510bc846f6f1bf19e56c44c770068e5539396efe7f4aed6a1396584",
+            "http_listen": "0.0.0.0:8080", "http_control_listen": "10.0.0.1:8081" } }"#;
+        let cfg = Config::from_json(server, &[Section::Server]).unwrap();
+        assert_eq!(cfg.server().http_max_body_len(), 16 << 20);
+        assert_eq!(cfg.server().max_message_len(), rpc::DEFAULT_MAX_MESSAGE_LEN);
+
+        let bad = [
+            (server.replace("10.0.0.1:8081", "0.0.0.0:8080"), "http_listen and http_control_listen must differ"),
+            (
+                server.replace(r#""id": 0,"#, r#""id": 0, "http_max_body_mb": 16384,"#),
+                "http_max_body_mb must lie in 1..=1024",
+            ),
+            (server.replace(r#""id": 0,"#, r#""id": 0, "max_message_mb": 0,"#), "server.max_message_mb must lie in"),
+            (server.replace("0dd16b", "0dd16"), "server.hpke_secret must be 32 bytes in hex"),
+        ];
+        for (json, msg) in &bad {
+            let err = Config::from_json(json, &[]).unwrap_err();
+            assert!(err.contains(msg), "expected {:?} in {:?}", msg, err);
+        }
+    }
+
+    #[test]
+    fn missing_section() {
+        let err = Config::from_json(LEADER, &[Section::Server]).unwrap_err();
+        assert_eq!(err, "missing section `server`");
+    }
 }
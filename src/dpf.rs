@@ -252,7 +252,7 @@ where
     }
 
     pub fn eval(&self, idx: &[bool]) -> (Vec<T>,U) {
-        debug_assert!(idx.len() <= self.domain_size());
+        debug_assert!(idx.len() <= self.domain_size()+1);
         debug_assert!(!idx.is_empty());
         let mut out = vec![];
         let mut state = self.eval_init();
@@ -0,0 +1,240 @@
+// Encryption of key shares to the servers, for clients that can only
+// reach a single upload endpoint.
+//
+// A client encrypts key 0 to server 0's public key and key 1 to server
+// 1's public key using HPKE (RFC 9180) in base mode, with X25519,
+// HKDF-SHA256 and ChaCha20-Poly1305. It uploads both ciphertexts, along
+// with its token, to a relay, which passes each server its own half.
+// Only the servers ever see the plaintext keys.
+//
+// The ciphertexts are bound to the client's token and the server index,
+// so a relay cannot move a share to another report or server.
+//
+// Each server derives a long-term key pair from a secret in its config
+// file, and clients pin the public keys in theirs, so that neither the
+// relay nor the leader can hand the clients keys of their own.
+
+use crate::auth;
+
+use hpke::aead::ChaCha20Poly1305;
+use hpke::kdf::HkdfSha256;
+use hpke::kem::X25519HkdfSha256;
+use hpke::{Deserializable, Kem as KemTrait, OpModeR, OpModeS, Serializable};
+use rand::Rng;
+use rand_chacha::rand_core::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+type Kem = X25519HkdfSha256;
+type Kdf = HkdfSha256;
+type Aead = ChaCha20Poly1305;
+
+const INFO: &[u8] = b"counttree key share";
+
+// Length of the secret that a server's key pair is derived from.
+pub const SECRET_LEN: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicKey(Vec<u8>);
+
+pub struct KeyPair {
+    secret: <Kem as KemTrait>::PrivateKey,
+    public: <Kem as KemTrait>::PublicKey,
+}
+
+// One server's key share, encrypted to that server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptedShare {
+    encapped_key: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+// What a client uploads to the relay.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SealedReport {
+    pub token: auth::Token,
+    pub shares: [EncryptedShare; 2],
+}
+
+// What the relay forwards to one server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptedReport {
+    pub token: auth::Token,
+    pub share: EncryptedShare,
+}
+
+fn csprng() -> rand_chacha::ChaCha20Rng {
+    rand_chacha::ChaCha20Rng::from_seed(rand::thread_rng().gen())
+}
+
+fn aad(server_idx: usize, token: &auth::Token) -> Vec<u8> {
+    let mut out = bincode::serialize(token).unwrap();
+    out.push(server_idx as u8);
+    out
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl PublicKey {
+    // Parses a key in the hex form that Display gives, or returns None if
+    // it is not a valid X25519 public key.
+    pub fn from_hex(hex: &str) -> Option<PublicKey> {
+        let bytes = from_hex(hex)?;
+        <Kem as KemTrait>::PublicKey::from_bytes(&bytes).ok()?;
+        Some(PublicKey(bytes))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in &self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl KeyPair {
+    pub fn random() -> KeyPair {
+        let (secret, public) = Kem::gen_keypair(&mut csprng());
+        KeyPair { secret, public }
+    }
+
+    // Derives the key pair from a secret of SECRET_LEN bytes, given in
+    // hex, or returns None if the secret is not that.
+    pub fn from_secret_hex(hex: &str) -> Option<KeyPair> {
+        let ikm = from_hex(hex).filter(|ikm| ikm.len() == SECRET_LEN)?;
+        let (secret, public) = Kem::derive_keypair(&ikm);
+        Some(KeyPair { secret, public })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.public.to_bytes().to_vec())
+    }
+
+    // Decrypts a share that a client encrypted to this server, or returns
+    // None if it was not encrypted for this server and token.
+    pub fn open(&self, server_idx: usize, report: &EncryptedReport) -> Option<Vec<u8>> {
+        let encapped_key =
+            <Kem as KemTrait>::EncappedKey::from_bytes(&report.share.encapped_key).ok()?;
+        hpke::single_shot_open::<Aead, Kdf, Kem>(
+            &OpModeR::Base,
+            &self.secret,
+            &encapped_key,
+            INFO,
+            &report.share.ciphertext,
+            &aad(server_idx, &report.token),
+        )
+        .ok()
+    }
+}
+
+fn seal_share(
+    public: &PublicKey,
+    server_idx: usize,
+    token: &auth::Token,
+    plaintext: &[u8],
+) -> EncryptedShare {
+    let pk = <Kem as KemTrait>::PublicKey::from_bytes(&public.0).expect("Bad server public key");
+    let (encapped_key, ciphertext) = hpke::single_shot_seal::<Aead, Kdf, Kem, _>(
+        &OpModeS::Base,
+        &pk,
+        INFO,
+        plaintext,
+        &aad(server_idx, token),
+        &mut csprng(),
+    )
+    .expect("Encryption failed");
+
+    EncryptedShare {
+        encapped_key: encapped_key.to_bytes().to_vec(),
+        ciphertext,
+    }
+}
+
+impl SealedReport {
+    // Encrypts key shares plain[i] to server i, whose public key is
+    // public[i].
+    pub fn seal(public: &[PublicKey; 2], token: auth::Token, plain: [&[u8]; 2]) -> SealedReport {
+        SealedReport {
+            token,
+            shares: [
+                seal_share(&public[0], 0, &token, plain[0]),
+                seal_share(&public[1], 1, &token, plain[1]),
+            ],
+        }
+    }
+
+    // Splits the report into the parts that the relay sends to each server.
+    pub fn split(self) -> (EncryptedReport, EncryptedReport) {
+        let [share0, share1] = self.shares;
+        (
+            EncryptedReport {
+                token: self.token,
+                share: share0,
+            },
+            EncryptedReport {
+                token: self.token,
+                share: share1,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let servers = [KeyPair::random(), KeyPair::random()];
+        let public = [servers[0].public_key(), servers[1].public_key()];
+        let token = auth::Authority::new(&auth::STAND_IN_KEY).issue(0, "alice").unwrap();
+
+        let report = SealedReport::seal(&public, token, [b"zero", b"one"]);
+        let (r0, r1) = report.split();
+        assert_eq!(servers[0].open(0, &r0).unwrap(), b"zero");
+        assert_eq!(servers[1].open(1, &r1).unwrap(), b"one");
+
+        // Shares only open at the server they were sent to.
+        assert!(servers[1].open(1, &r0).is_none());
+        assert!(servers[0].open(1, &r0).is_none());
+    }
+
+    #[test]
+    fn from_secret() {
+        let secret = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let pair = KeyPair::from_secret_hex(secret).unwrap();
+        assert_eq!(pair.public_key(), KeyPair::from_secret_hex(secret).unwrap().public_key());
+        assert_ne!(pair.public_key(), KeyPair::from_secret_hex(&secret.replace("ff", "fe")).unwrap().public_key());
+
+        let hex = pair.public_key().to_string();
+        assert_eq!(PublicKey::from_hex(&hex), Some(pair.public_key()));
+        assert_eq!(PublicKey::from_hex(&hex[2..]), None);
+        assert_eq!(PublicKey::from_hex("zz"), None);
+
+        assert!(KeyPair::from_secret_hex(&secret[2..]).is_none());
+        assert!(KeyPair::from_secret_hex(&secret.replace('0', "g")).is_none());
+    }
+
+    #[test]
+    fn bound_to_token() {
+        let server = KeyPair::random();
+        let public = [server.public_key(), server.public_key()];
+        let auth = auth::Authority::new(&auth::STAND_IN_KEY);
+        let token = auth.issue(0, "alice").unwrap();
+        let other = auth.issue(0, "bob").unwrap();
+
+        let (mut r0, _) = SealedReport::seal(&public, token, [b"zero", b"one"]).split();
+        r0.token = other;
+        assert!(server.open(0, &r0).is_none());
+    }
+}
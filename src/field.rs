@@ -35,6 +35,10 @@ impl FieldElm {
         BigUint::parse_bytes(inp, 16).map(|value| FieldElm { value })
     }
 
+    pub fn value(&self) -> &BigUint {
+        &self.value
+    }
+
     pub fn to_vec(&self, len: usize) -> Vec<FieldElm> {
         std::iter::repeat(self.clone()).take(len).collect()
     }
@@ -0,0 +1,280 @@
+// Prime field modulo the Mersenne prime p = 2^127 - 1, stored in a
+// single u128.
+//
+// Values are always kept fully reduced, in range 0..p-1. Since
+// 2^127 = 1 (mod p), reducing a value amounts to adding its bits
+// above position 127 back into its low 127 bits.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+const MODULUS: u128 = (1 << 127) - 1;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawFieldElm127")]
+pub struct FieldElm127 {
+    value: u128,
+}
+
+// The wire form of a FieldElm127. Decoding goes through it so that a
+// value of p or more, which the arithmetic below assumes away, is
+// rejected rather than let in unreduced.
+#[derive(Deserialize)]
+struct RawFieldElm127 {
+    value: u128,
+}
+
+impl TryFrom<RawFieldElm127> for FieldElm127 {
+    type Error = String;
+
+    fn try_from(raw: RawFieldElm127) -> Result<Self, String> {
+        if raw.value < MODULUS {
+            Ok(FieldElm127 { value: raw.value })
+        } else {
+            Err(format!("Field element {} is not below the modulus", raw.value))
+        }
+    }
+}
+
+// Given v in range 0..2^128-1, returns v mod p.
+#[inline(always)]
+fn reduce(v: u128) -> u128 {
+    // Result is at most p + 1.
+    let r = (v & MODULUS) + (v >> 127);
+
+    // Returns "if r >= p { r - p } else { r }".
+    let diff = r.wrapping_sub(MODULUS);
+    let mask = 0u128.wrapping_sub(diff >> 127);
+    (r & mask) | (diff & !mask)
+}
+
+// Returns the 256-bit product a*b as (high, low) halves.
+#[inline(always)]
+fn mul_wide(a: u128, b: u128) -> (u128, u128) {
+    let (a0, a1) = (a as u64 as u128, a >> 64);
+    let (b0, b1) = (b as u64 as u128, b >> 64);
+
+    let p00 = a0 * b0;
+    let p01 = a0 * b1;
+    let p10 = a1 * b0;
+    let p11 = a1 * b1;
+
+    let mid = (p00 >> 64) + (p01 as u64 as u128) + (p10 as u64 as u128);
+    let lo = (p00 as u64 as u128) | (mid << 64);
+    let hi = p11 + (p01 >> 64) + (p10 >> 64) + (mid >> 64);
+
+    (hi, lo)
+}
+
+impl FieldElm127 {
+    pub fn new(v: u128) -> Self {
+        FieldElm127 { value: reduce(v) }
+    }
+
+    // Return the value of this element, in range 0..p-1.
+    pub fn value(&self) -> u128 {
+        self.value
+    }
+}
+
+impl From<u32> for FieldElm127 {
+    #[inline]
+    fn from(inp: u32) -> Self {
+        FieldElm127 { value: inp.into() }
+    }
+}
+
+impl Ord for FieldElm127 {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value.cmp(&other.value)
+    }
+}
+
+impl PartialOrd for FieldElm127 {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl crate::Group for FieldElm127 {
+    #[inline]
+    fn zero() -> Self {
+        FieldElm127::from(0)
+    }
+
+    #[inline]
+    fn one() -> Self {
+        FieldElm127::from(1)
+    }
+
+    #[inline]
+    fn add(&mut self, other: &Self) {
+        // Both values are below 2^127, so the sum fits in a u128.
+        self.value = reduce(self.value + other.value);
+    }
+
+    #[inline]
+    fn mul(&mut self, other: &Self) {
+        let (hi, lo) = mul_wide(self.value, other.value);
+
+        // The product is below 2^254, so hi < 2^126 and
+        //     hi*2^128 + lo = 2*hi + lo (mod p).
+        // The sum below is less than 2^128.
+        let folded = (lo & MODULUS) + (lo >> 127) + (hi << 1);
+        self.value = reduce(folded);
+    }
+
+    #[inline]
+    fn add_lazy(&mut self, other: &Self) {
+        self.add(other);
+    }
+
+    #[inline]
+    fn mul_lazy(&mut self, other: &Self) {
+        self.mul(other);
+    }
+
+    #[inline]
+    fn reduce(&mut self) {}
+
+    #[inline]
+    fn sub(&mut self, other: &Self) {
+        self.value = reduce(self.value + (MODULUS - other.value));
+    }
+
+    #[inline]
+    fn negate(&mut self) {
+        self.value = reduce(MODULUS - self.value);
+    }
+}
+
+impl crate::prg::FromRng for FieldElm127 {
+    #[inline]
+    fn from_rng(&mut self, rng: &mut impl rand::Rng) {
+        // Rejection-sample a 127-bit value below p. Only the value
+        // p itself is rejected.
+        loop {
+            let hi = rng.next_u64() as u128;
+            let lo = rng.next_u64() as u128;
+            let v = ((hi << 64) | lo) & MODULUS;
+            if v < MODULUS {
+                self.value = v;
+                break;
+            }
+        }
+    }
+}
+
+impl crate::Share for FieldElm127 {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{Group, Share};
+    use num_bigint::BigUint;
+
+    const TRIALS: usize = 2000;
+
+    fn big(x: &FieldElm127) -> BigUint {
+        BigUint::from(x.value)
+    }
+
+    fn big_mod() -> BigUint {
+        BigUint::from(MODULUS)
+    }
+
+    fn check_all_ops(a: &FieldElm127, b: &FieldElm127) {
+        let p = big_mod();
+
+        let mut res = a.clone();
+        res.add(b);
+        assert_eq!(big(&res), (big(a) + big(b)) % &p);
+
+        let mut res = a.clone();
+        res.sub(b);
+        assert_eq!(big(&res), (big(a) + &p - big(b)) % &p);
+
+        let mut res = a.clone();
+        res.mul(b);
+        assert_eq!(big(&res), (big(a) * big(b)) % &p);
+
+        let mut res = a.clone();
+        res.negate();
+        assert_eq!(big(&res), (&p - big(a)) % &p);
+    }
+
+    #[test]
+    fn differential_edge() {
+        let vals = vec![
+            FieldElm127::zero(),
+            FieldElm127::one(),
+            FieldElm127::from(2),
+            FieldElm127::from(u32::MAX),
+            FieldElm127 {
+                value: MODULUS - 1,
+            },
+            FieldElm127 {
+                value: MODULUS - 2,
+            },
+            FieldElm127 { value: 1 << 126 },
+            FieldElm127 {
+                value: u64::MAX as u128,
+            },
+            FieldElm127::random(),
+        ];
+
+        for a in &vals {
+            for b in &vals {
+                check_all_ops(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn differential_random() {
+        for _ in 0..TRIALS {
+            check_all_ops(&FieldElm127::random(), &FieldElm127::random());
+        }
+    }
+
+    #[test]
+    fn new_reduces() {
+        assert_eq!(FieldElm127::new(MODULUS), FieldElm127::zero());
+        assert_eq!(FieldElm127::new(MODULUS + 5), FieldElm127::from(5));
+        assert_eq!(FieldElm127::new(u128::MAX), FieldElm127::one());
+    }
+
+    #[test]
+    fn decode_rejects_unreduced() {
+        let top = FieldElm127 {
+            value: MODULUS - 1,
+        };
+        let bytes = bincode::serialize(&top).unwrap();
+        assert_eq!(bincode::deserialize::<FieldElm127>(&bytes).unwrap(), top);
+
+        for value in [MODULUS, u128::MAX] {
+            let bytes = bincode::serialize(&FieldElm127 { value }).unwrap();
+            assert!(bincode::deserialize::<FieldElm127>(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn order() {
+        assert!(FieldElm127::from(3) < FieldElm127::from(4));
+        assert!(FieldElm127::from(u32::MAX) < FieldElm127 { value: 1 << 100 });
+    }
+
+    #[test]
+    fn share() {
+        let val = FieldElm127::random();
+        let (s0, s1) = val.share();
+        let mut out = FieldElm127::zero();
+        out.add(&s0);
+        out.add(&s1);
+        assert_eq!(out, val);
+    }
+}
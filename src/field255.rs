@@ -0,0 +1,416 @@
+// Prime field modulo p = 2^255 - 19, stored as four 64-bit limbs.
+//
+// This is a drop-in replacement for the BigUint-backed `FieldElm`
+// that never allocates and whose arithmetic does not branch on
+// secret data.
+//
+// Values are always kept fully reduced, in range 0..p-1, with the
+// least significant limb first. Reduction uses the identity
+//
+//      2^256 = 2*p + 38  ==>  2^256 = 38 (mod p),
+//
+// so the high half of a 512-bit product folds into the low half with
+// a multiply by 38.
+
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+const NLIMBS: usize = 4;
+
+const MODULUS: [u64; NLIMBS] = [
+    0xffffffffffffffed,
+    0xffffffffffffffff,
+    0xffffffffffffffff,
+    0x7fffffffffffffff,
+];
+
+// 2^256 mod p
+const FOLD: u64 = 38;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawFieldElm255")]
+pub struct FieldElm255 {
+    limbs: [u64; NLIMBS],
+}
+
+// The wire form of a FieldElm255. Decoding goes through it so that
+// limbs of p or more, which the arithmetic below assumes away, are
+// rejected rather than let in unreduced.
+#[derive(Deserialize)]
+struct RawFieldElm255 {
+    limbs: [u64; NLIMBS],
+}
+
+impl TryFrom<RawFieldElm255> for FieldElm255 {
+    type Error = String;
+
+    fn try_from(raw: RawFieldElm255) -> Result<Self, String> {
+        // borrow == 1 exactly when the limbs are below p.
+        let (_, borrow) = sub_limbs(&raw.limbs, &MODULUS);
+        let elm = FieldElm255 { limbs: raw.limbs };
+        if borrow == 1 {
+            Ok(elm)
+        } else {
+            Err(format!("Field element {} is not below the modulus", elm.to_biguint()))
+        }
+    }
+}
+
+// Returns a - b and the outgoing borrow (0 or 1).
+#[inline(always)]
+fn sub_limbs(a: &[u64; NLIMBS], b: &[u64; NLIMBS]) -> ([u64; NLIMBS], u64) {
+    let mut out = [0u64; NLIMBS];
+    let mut borrow = 0u64;
+    for i in 0..NLIMBS {
+        let (d0, b0) = a[i].overflowing_sub(b[i]);
+        let (d1, b1) = d0.overflowing_sub(borrow);
+        out[i] = d1;
+        borrow = (b0 | b1) as u64;
+    }
+    (out, borrow)
+}
+
+// Returns a + b and the outgoing carry (0 or 1).
+#[inline(always)]
+fn add_limbs(a: &[u64; NLIMBS], b: &[u64; NLIMBS]) -> ([u64; NLIMBS], u64) {
+    let mut out = [0u64; NLIMBS];
+    let mut carry = 0u64;
+    for i in 0..NLIMBS {
+        let (s0, c0) = a[i].overflowing_add(b[i]);
+        let (s1, c1) = s0.overflowing_add(carry);
+        out[i] = s1;
+        carry = (c0 | c1) as u64;
+    }
+    (out, carry)
+}
+
+// Returns "if mask == !0 { a } else { b }" without branching.
+#[inline(always)]
+fn select(mask: u64, a: &[u64; NLIMBS], b: &[u64; NLIMBS]) -> [u64; NLIMBS] {
+    let mut out = [0u64; NLIMBS];
+    for i in 0..NLIMBS {
+        out[i] = (a[i] & mask) | (b[i] & !mask);
+    }
+    out
+}
+
+// Returns "if v >= p { v - p } else { v }".
+#[inline(always)]
+fn reduce_once(v: &[u64; NLIMBS]) -> [u64; NLIMBS] {
+    let (diff, borrow) = sub_limbs(v, &MODULUS);
+    // borrow == 1 exactly when v < p.
+    select(0u64.wrapping_sub(borrow), v, &diff)
+}
+
+// Given v + carry*2^256, with carry small, returns an equivalent value
+// in range 0..2^256-1.
+#[inline(always)]
+fn fold_carry(v: &mut [u64; NLIMBS], carry: u64) {
+    let mut acc = (carry as u128) * (FOLD as u128);
+    for limb in v.iter_mut() {
+        acc += *limb as u128;
+        *limb = acc as u64;
+        acc >>= 64;
+    }
+
+    // If the addition above wrapped past 2^256, the low limbs are now
+    // tiny, so adding another 38 cannot carry out of the first limb.
+    v[0] += (acc as u64) * FOLD;
+}
+
+impl FieldElm255 {
+    pub fn from_limbs(limbs: [u64; NLIMBS]) -> Self {
+        FieldElm255 {
+            limbs: reduce_once(&reduce_once(&limbs)),
+        }
+    }
+
+    pub fn limbs(&self) -> [u64; NLIMBS] {
+        self.limbs
+    }
+
+    pub fn to_biguint(&self) -> BigUint {
+        let mut bytes = Vec::with_capacity(8 * NLIMBS);
+        for limb in &self.limbs {
+            bytes.extend_from_slice(&limb.to_le_bytes());
+        }
+        BigUint::from_bytes_le(&bytes)
+    }
+}
+
+impl From<u32> for FieldElm255 {
+    #[inline]
+    fn from(inp: u32) -> Self {
+        FieldElm255 {
+            limbs: [inp.into(), 0, 0, 0],
+        }
+    }
+}
+
+impl From<BigUint> for FieldElm255 {
+    fn from(inp: BigUint) -> Self {
+        let modulus = FieldElm255 { limbs: MODULUS }.to_biguint();
+        let mut bytes = (inp % modulus).to_bytes_le();
+        bytes.resize(8 * NLIMBS, 0);
+
+        let mut limbs = [0u64; NLIMBS];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let mut chunk = [0u8; 8];
+            chunk.copy_from_slice(&bytes[8 * i..8 * (i + 1)]);
+            *limb = u64::from_le_bytes(chunk);
+        }
+
+        FieldElm255 { limbs }
+    }
+}
+
+impl Ord for FieldElm255 {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.limbs.iter().rev().cmp(other.limbs.iter().rev())
+    }
+}
+
+impl PartialOrd for FieldElm255 {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl crate::Group for FieldElm255 {
+    #[inline]
+    fn zero() -> Self {
+        FieldElm255::from(0)
+    }
+
+    #[inline]
+    fn one() -> Self {
+        FieldElm255::from(1)
+    }
+
+    #[inline]
+    fn add(&mut self, other: &Self) {
+        let (mut sum, carry) = add_limbs(&self.limbs, &other.limbs);
+        fold_carry(&mut sum, carry);
+        self.limbs = reduce_once(&reduce_once(&sum));
+    }
+
+    #[inline]
+    fn mul(&mut self, other: &Self) {
+        let a = &self.limbs;
+        let b = &other.limbs;
+
+        // Schoolbook multiplication into eight limbs.
+        let mut prod = [0u64; 2 * NLIMBS];
+        for i in 0..NLIMBS {
+            let mut carry = 0u128;
+            for j in 0..NLIMBS {
+                let t = (prod[i + j] as u128) + (a[i] as u128) * (b[j] as u128) + carry;
+                prod[i + j] = t as u64;
+                carry = t >> 64;
+            }
+            prod[i + NLIMBS] = carry as u64;
+        }
+
+        // Fold the high half into the low half: lo + 38*hi.
+        let mut out = [0u64; NLIMBS];
+        let mut carry = 0u128;
+        for i in 0..NLIMBS {
+            let t = (prod[i] as u128) + (prod[i + NLIMBS] as u128) * (FOLD as u128) + carry;
+            out[i] = t as u64;
+            carry = t >> 64;
+        }
+
+        fold_carry(&mut out, carry as u64);
+
+        // Now out < 2^256 = 2p + 38, so two conditional
+        // subtractions suffice.
+        self.limbs = reduce_once(&reduce_once(&out));
+    }
+
+    #[inline]
+    fn add_lazy(&mut self, other: &Self) {
+        self.add(other);
+    }
+
+    #[inline]
+    fn mul_lazy(&mut self, other: &Self) {
+        self.mul(other);
+    }
+
+    #[inline]
+    fn reduce(&mut self) {}
+
+    #[inline]
+    fn sub(&mut self, other: &Self) {
+        let (diff, borrow) = sub_limbs(&self.limbs, &other.limbs);
+        let mask = 0u64.wrapping_sub(borrow);
+        let (wrapped, _) = add_limbs(&diff, &MODULUS);
+        self.limbs = select(mask, &wrapped, &diff);
+    }
+
+    #[inline]
+    fn negate(&mut self) {
+        let mut out = FieldElm255::zero();
+        out.sub(self);
+        *self = out;
+    }
+}
+
+impl crate::prg::FromRng for FieldElm255 {
+    #[inline]
+    fn from_rng(&mut self, rng: &mut impl rand::Rng) {
+        // Rejection-sample a 255-bit value below p. A sample is
+        // rejected with probability 19/2^255.
+        loop {
+            let mut limbs = [0u64; NLIMBS];
+            for limb in limbs.iter_mut() {
+                *limb = rng.next_u64();
+            }
+            limbs[NLIMBS - 1] &= 0x7fffffffffffffff;
+
+            let (_, borrow) = sub_limbs(&limbs, &MODULUS);
+            if borrow == 1 {
+                self.limbs = limbs;
+                break;
+            }
+        }
+    }
+}
+
+impl crate::Share for FieldElm255 {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{FieldElm, Group, Share};
+
+    const TRIALS: usize = 2000;
+
+    fn edge_values() -> Vec<FieldElm255> {
+        let mut out = vec![
+            FieldElm255::zero(),
+            FieldElm255::one(),
+            FieldElm255::from(2),
+            FieldElm255::from(19),
+            FieldElm255::from(38),
+            FieldElm255::from(u32::MAX),
+            FieldElm255 {
+                limbs: [MODULUS[0] - 1, MODULUS[1], MODULUS[2], MODULUS[3]],
+            },
+            FieldElm255 {
+                limbs: [MODULUS[0] - 2, MODULUS[1], MODULUS[2], MODULUS[3]],
+            },
+            FieldElm255 {
+                limbs: [0, 0, 0, 1 << 62],
+            },
+            FieldElm255 {
+                limbs: [!0, !0, !0, 0],
+            },
+        ];
+        for _ in 0..8 {
+            out.push(FieldElm255::random());
+        }
+        out
+    }
+
+    fn to_big(x: &FieldElm255) -> FieldElm {
+        FieldElm::from(x.to_biguint())
+    }
+
+    fn check_op(
+        op255: impl Fn(&mut FieldElm255, &FieldElm255),
+        op_big: impl Fn(&mut FieldElm, &FieldElm),
+        a: &FieldElm255,
+        b: &FieldElm255,
+    ) {
+        let mut res = a.clone();
+        op255(&mut res, b);
+
+        let mut res_big = to_big(a);
+        op_big(&mut res_big, &to_big(b));
+
+        // The BigUint version does not always fully reduce (e.g., it
+        // negates zero to p), so compare modulo p.
+        assert_eq!(res, FieldElm255::from(res_big.value().clone()), "a={:?} b={:?}", a, b);
+        assert!(res < FieldElm255 { limbs: MODULUS });
+    }
+
+    fn check_all_ops(a: &FieldElm255, b: &FieldElm255) {
+        check_op(|x, y| x.add(y), |x, y| x.add(y), a, b);
+        check_op(|x, y| x.sub(y), |x, y| x.sub(y), a, b);
+        check_op(|x, y| x.mul(y), |x, y| x.mul(y), a, b);
+        check_op(|x, _| x.negate(), |x, _| x.negate(), a, b);
+    }
+
+    #[test]
+    fn differential_edge() {
+        let vals = edge_values();
+        for a in &vals {
+            for b in &vals {
+                check_all_ops(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn differential_random() {
+        for _ in 0..TRIALS {
+            check_all_ops(&FieldElm255::random(), &FieldElm255::random());
+        }
+    }
+
+    #[test]
+    fn biguint_roundtrip() {
+        for _ in 0..TRIALS {
+            let x = FieldElm::random();
+            let y = FieldElm255::from(x.value().clone());
+            assert_eq!(FieldElm::from(y.to_biguint()), x);
+        }
+    }
+
+    #[test]
+    fn from_limbs_reduces() {
+        assert_eq!(FieldElm255::from_limbs(MODULUS), FieldElm255::zero());
+        assert_eq!(FieldElm255::from_limbs([!0; NLIMBS]), FieldElm255::from(37));
+    }
+
+    #[test]
+    fn decode_rejects_unreduced() {
+        let mut top = MODULUS;
+        top[0] -= 1;
+        let top = FieldElm255 { limbs: top };
+        let bytes = bincode::serialize(&top).unwrap();
+        assert_eq!(bincode::deserialize::<FieldElm255>(&bytes).unwrap(), top);
+
+        for limbs in [MODULUS, [!0; NLIMBS]] {
+            let bytes = bincode::serialize(&FieldElm255 { limbs }).unwrap();
+            assert!(bincode::deserialize::<FieldElm255>(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn order() {
+        let small = FieldElm255::from(7);
+        let big = FieldElm255 {
+            limbs: [0, 0, 0, 1],
+        };
+        assert!(small < big);
+        assert!(FieldElm255::zero() < small);
+        assert_eq!(small.cmp(&FieldElm255::from(7)), Ordering::Equal);
+    }
+
+    #[test]
+    fn share() {
+        let val = FieldElm255::random();
+        let (s0, s1) = val.share();
+        let mut out = FieldElm255::zero();
+        out.add(&s0);
+        out.add(&s1);
+        assert_eq!(out, val);
+    }
+}
@@ -0,0 +1,230 @@
+// HTTP front end for the Collector service, for deployments that sit
+// behind HTTP load balancers. It drives the same CollectorServer as the
+// tarpc front end, so the two can serve one server process side by side.
+//
+// Schema: every operation is a POST to /v1/<method>, where <method> is
+// the name of the Collector method. The request body is the method's
+// request struct from rpc.rs and the response body is its return value
+// (a Reply for calls whose requests carry a Tag), both encoded with
+// bincode 1.3 default options: integers little-endian and fixed width,
+// sequence and string lengths as u64, enum variants as u32 indices, no
+// padding. Payload fields are themselves bincode bytes,
+// as listed in rpc.rs. Bodies are sent as application/octet-stream. A
+// request body that does not decode gets a 400 with a plain-text error,
+// and one over the configured limit a 413.
+//
+// The calls split into two front ends with their own listeners. The
+// upload front end serves only public_key and add_keys, which clients
+// and relays make, and can face the public. The control front end serves
+// every call, including reset, add_triples, the crawl and final_shares,
+// and has no authentication of its own, so only the leader and dealer
+// may be able to reach it.
+
+use crate::collect::ClientId;
+use crate::envelope;
+use crate::rpc::{
+    AddKeysRequest, AddTriplesRequest, Collector, CrawlKeys, FinalSharesRequest, KeyIdsRequest, Payload,
+    PublicKeyRequest, ReconcileRequest, Reply, ResetRequest, TreeCrawlLastRequest, TreeCrawlRequest,
+    TreeInitRequest, TreeOutSharesLastRequest, TreeOutSharesRequest, TreePruneLastRequest,
+    TreePruneRequest, TreeRecountLastRequest, TreeRecountRequest, TreeRejectRequest, TreeSketchFrontierLastRequest,
+    TreeSketchFrontierRequest, TripleCount, TripleCountRequest,
+};
+use crate::server::CollectorServer;
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Router,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use tarpc::context;
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Which calls a front end serves, as described above.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Api {
+    Upload,
+    Control,
+}
+
+async fn handle<Req, Resp, Fut>(body: Bytes, method: impl FnOnce(Req) -> Fut) -> Response
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    Fut: Future<Output = Resp>,
+{
+    let req = match bincode::deserialize(&body) {
+        Ok(req) => req,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Bad request body: {}", e)).into_response(),
+    };
+    let resp = method(req).await;
+    let body = bincode::serialize(&resp).expect("Could not encode response");
+    ([(header::CONTENT_TYPE, OCTET_STREAM)], body).into_response()
+}
+
+/// Client for the HTTP front end.
+#[derive(Clone)]
+pub struct Client {
+    addr: SocketAddr,
+    http: hyper::Client<hyper::client::HttpConnector>,
+}
+
+impl Client {
+    pub fn new(addr: SocketAddr) -> Client {
+        Client {
+            addr,
+            http: hyper::Client::new(),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    async fn call<Req, Resp>(&self, method: &str, req: &Req) -> io::Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let body = bincode::serialize(req).map_err(io::Error::other)?;
+        let request = hyper::Request::post(format!("http://{}/v1/{}", self.addr, method))
+            .header(header::CONTENT_TYPE, OCTET_STREAM)
+            .body(hyper::Body::from(body))
+            .map_err(io::Error::other)?;
+
+        let response = self.http.request(request).await.map_err(io::Error::other)?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(io::Error::other)?;
+        if !status.is_success() {
+            return Err(io::Error::other(format!(
+                "{} failed with {}: {}",
+                method,
+                status,
+                String::from_utf8_lossy(&body)
+            )));
+        }
+
+        bincode::deserialize(&body).map_err(io::Error::other)
+    }
+}
+
+// Defines a route and a client method for each Collector method. The
+// client methods for tagged calls turn a refusal into an error.
+macro_rules! http_api {
+    (
+        untagged { $($method:ident($req:ty) -> $resp:ty;)* }
+        tagged { $($tmethod:ident($treq:ty) -> $tresp:ty;)* }
+    ) => {
+        fn control_router() -> Router<CollectorServer> {
+            Router::new()
+                $(.route(
+                    concat!("/v1/", stringify!($method)),
+                    post(|State(server): State<CollectorServer>, body: Bytes| {
+                        handle(body, move |req: $req| {
+                            Collector::$method(server, context::current(), req)
+                        })
+                    }),
+                ))*
+                $(.route(
+                    concat!("/v1/", stringify!($tmethod)),
+                    post(|State(server): State<CollectorServer>, body: Bytes| {
+                        handle(body, move |req: $treq| {
+                            Collector::$tmethod(server, context::current(), req)
+                        })
+                    }),
+                ))*
+        }
+
+        impl Client {
+            $(pub async fn $method(&self, req: $req) -> io::Result<$resp> {
+                self.call(stringify!($method), &req).await
+            })*
+
+            $(pub async fn $tmethod(&self, req: $treq) -> io::Result<$tresp> {
+                self.call::<_, Reply<$tresp>>(stringify!($tmethod), &req)
+                    .await?
+                    .map_err(io::Error::other)
+            })*
+        }
+    };
+}
+
+http_api! {
+    untagged {
+        public_key(PublicKeyRequest) -> envelope::PublicKey;
+        reset(ResetRequest) -> String;
+        add_triples(AddTriplesRequest) -> String;
+        triple_count(TripleCountRequest) -> TripleCount;
+    }
+    tagged {
+        add_keys(AddKeysRequest) -> String;
+        key_ids(KeyIdsRequest) -> Vec<ClientId>;
+        reconcile(ReconcileRequest) -> usize;
+        tree_init(TreeInitRequest) -> CrawlKeys;
+        tree_crawl(TreeCrawlRequest) -> Payload;
+        tree_crawl_last(TreeCrawlLastRequest) -> Payload;
+        tree_prune(TreePruneRequest) -> String;
+        tree_prune_last(TreePruneLastRequest) -> String;
+        tree_reject(TreeRejectRequest) -> usize;
+        tree_recount(TreeRecountRequest) -> Payload;
+        tree_recount_last(TreeRecountLastRequest) -> Payload;
+        tree_sketch_frontier(TreeSketchFrontierRequest) -> Payload;
+        tree_sketch_frontier_last(TreeSketchFrontierLastRequest) -> Payload;
+        tree_out_shares(TreeOutSharesRequest) -> Payload;
+        tree_out_shares_last(TreeOutSharesLastRequest) -> Payload;
+        final_shares(FinalSharesRequest) -> Payload;
+    }
+}
+
+fn upload_router() -> Router<CollectorServer> {
+    Router::new()
+        .route(
+            "/v1/public_key",
+            post(|State(server): State<CollectorServer>, body: Bytes| {
+                handle(body, move |req: PublicKeyRequest| Collector::public_key(server, context::current(), req))
+            }),
+        )
+        .route(
+            "/v1/add_keys",
+            post(|State(server): State<CollectorServer>, body: Bytes| {
+                handle(body, move |req: AddKeysRequest| Collector::add_keys(server, context::current(), req))
+            }),
+        )
+}
+
+// The routes of `api`, taking request bodies of up to `max_body_len`
+// bytes.
+pub fn router(server: CollectorServer, api: Api, max_body_len: usize) -> Router {
+    let routes = match api {
+        Api::Upload => upload_router(),
+        Api::Control => control_router(),
+    };
+    routes.layer(DefaultBodyLimit::max(max_body_len)).with_state(server)
+}
+
+/// Binds the `api` front end for `server` to `addr`. Returns the address
+/// bound, which tells the caller the port if `addr` had port 0, and a
+/// future that serves requests until it fails.
+pub fn bind(
+    server: CollectorServer,
+    addr: &SocketAddr,
+    api: Api,
+    max_body_len: usize,
+) -> io::Result<(SocketAddr, impl Future<Output = io::Result<()>> + Send)> {
+    let http = axum::Server::try_bind(addr)
+        .map_err(io::Error::other)?
+        .serve(router(server, api, max_body_len).into_make_service());
+    let local_addr = http.local_addr();
+
+    Ok((local_addr, async move { http.await.map_err(io::Error::other) }))
+}
@@ -0,0 +1,489 @@
+// Client inputs for the leader and the simulation: either random strings
+// drawn from a distribution, or real strings replayed from a file.
+//
+// Every string is encoded into exactly data_len bits: its UTF-8 bytes,
+// cut or padded with zero bytes to data_len / 8 bytes, with each byte's
+// bits in the order that string_to_bits() gives. Cutting can split a
+// multi-byte character, which decode() then shows as U+FFFD.
+//
+// A structured string, such as a (country, URL) pair, is a tuple of
+// fields, each encoded as above into its own width, one after another.
+
+use rand::distributions::{Alphanumeric, Distribution};
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// A file of client strings.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Source {
+    /// One client per line. Blank lines are skipped. Tuples separate
+    /// their fields with tabs.
+    Lines(PathBuf),
+    /// One client per row of a CSV file whose first row names the
+    /// columns. Each client holds the value in the named column, or for
+    /// tuples, with no column named, the value in each field's column.
+    Csv {
+        file: PathBuf,
+        #[serde(default)]
+        column: Option<String>,
+    },
+    /// CSV rows of `string,count`, with no header row, standing for
+    /// `count` clients that each hold `string`. Tuples give each field
+    /// before the count.
+    Counts(PathBuf),
+}
+
+/// One field of a structured string.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Field {
+    /// The field's name, which is also its CSV column.
+    pub name: String,
+    /// The field's width in bytes.
+    pub bytes: usize,
+}
+
+/// Distinct strings, and which one each client holds.
+#[derive(Clone, Debug, Default)]
+pub struct Population {
+    pub sites: Vec<Vec<bool>>,
+    pub clients: Vec<usize>,
+    /// The input strings that were cut to fit, with the ranges of clients
+    /// that hold each one.
+    pub cut: BTreeMap<String, Vec<Range<usize>>>,
+}
+
+// Encodes s into exactly data_len bits, as described above.
+pub fn encode(s: &str, data_len: usize) -> Vec<bool> {
+    assert!(data_len.is_multiple_of(8));
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.resize(data_len / 8, 0);
+
+    let mut bits = Vec::with_capacity(data_len);
+    for b in bytes {
+        bits.extend(crate::u32_to_bits(8, b.into()));
+    }
+    bits
+}
+
+// Inverts encode(), dropping the padding. Strings that were cut come
+// back cut.
+pub fn decode(bits: &[bool]) -> String {
+    assert!(bits.len().is_multiple_of(8));
+    let mut bytes: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().rev().fold(0u8, |acc, &b| (acc << 1) | b as u8))
+        .collect();
+    while bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Encodes each value into its field's width, one after another.
+pub fn encode_tuple(values: &[&str], fields: &[Field]) -> Vec<bool> {
+    assert_eq!(values.len(), fields.len());
+    values.iter().zip(fields).flat_map(|(v, f)| encode(v, 8 * f.bytes)).collect()
+}
+
+// Inverts encode_tuple(). A prefix gives only the fields that it
+// reaches, the last of them cut to its whole bytes.
+pub fn decode_tuple(bits: &[bool], fields: &[Field]) -> Vec<String> {
+    let mut values = vec![];
+    let mut rest = bits;
+    for f in fields {
+        if rest.is_empty() {
+            break;
+        }
+        let (value, tail) = rest.split_at((8 * f.bytes).min(rest.len()));
+        values.push(decode(&value[..value.len() / 8 * 8]));
+        rest = tail;
+    }
+    values
+}
+
+impl Population {
+    // Each of nclients clients draws one of num_sites random strings from
+    // a Zipf distribution with the given exponent.
+    pub fn zipf(num_sites: usize, exponent: f64, nclients: usize, data_len: usize) -> io::Result<Population> {
+        let zipf = zipf::ZipfDistribution::new(num_sites, exponent)
+            .map_err(|()| invalid("Zipf input needs num_sites > 0 and exponent > 0".to_string()))?;
+        let mut rng = rand::thread_rng();
+        Ok(Population {
+            sites: random_strings(num_sites, data_len),
+            clients: (0..nclients).map(|_| zipf.sample(&mut rng) - 1).collect(),
+            cut: BTreeMap::new(),
+        })
+    }
+
+    // Each of nclients clients draws one of num_sites random strings
+    // uniformly.
+    pub fn uniform(num_sites: usize, nclients: usize, data_len: usize) -> io::Result<Population> {
+        if num_sites == 0 {
+            return Err(invalid("Uniform input needs num_sites > 0".to_string()));
+        }
+        let mut rng = rand::thread_rng();
+        Ok(Population {
+            sites: random_strings(num_sites, data_len),
+            clients: (0..nclients).map(|_| rng.gen_range(0, num_sites)).collect(),
+            cut: BTreeMap::new(),
+        })
+    }
+
+    // Reads the clients' strings from a file, in file order.
+    pub fn load(source: &Source, data_len: usize) -> io::Result<Population> {
+        let whole = Field {
+            name: String::new(),
+            bytes: data_len / 8,
+        };
+        Population::load_tuples(source, &[whole])
+    }
+
+    // Reads the clients' tuples from a file, in file order. A single
+    // field stands for plain strings, which are read whole.
+    pub fn load_tuples(source: &Source, fields: &[Field]) -> io::Result<Population> {
+        let tuples = fields.len() > 1;
+        let mut pop = Population::default();
+        let mut index = HashMap::new();
+        let mut add = |values: &[&str], count: usize| {
+            let bits = encode_tuple(values, fields);
+            let site = *index.entry(bits.clone()).or_insert_with(|| {
+                pop.sites.push(bits);
+                pop.sites.len() - 1
+            });
+            let first = pop.clients.len();
+            pop.clients.extend(std::iter::repeat_n(site, count));
+            if values.iter().zip(fields).any(|(v, f)| v.len() > f.bytes) && count > 0 {
+                let key = if tuples { format!("{:?}", values) } else { values[0].to_string() };
+                pop.cut.entry(key).or_default().push(first..first + count);
+            }
+        };
+
+        match source {
+            Source::Lines(file) => {
+                for (i, line) in read(file)?.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let values: Vec<&str> = if tuples { line.split('\t').collect() } else { vec![line] };
+                    if values.len() != fields.len() {
+                        let n = fields.len();
+                        return Err(invalid(format!("{}:{}: expected {} tab-separated fields", file.display(), i + 1, n)));
+                    }
+                    add(&values, 1);
+                }
+            }
+            Source::Csv { file, column } => {
+                let columns: Vec<&str> = match (column, tuples) {
+                    (Some(column), false) => vec![column],
+                    (None, true) => fields.iter().map(|f| f.name.as_str()).collect(),
+                    (Some(_), true) => {
+                        return Err(invalid(format!("{}: tuples read each field's column, not one", file.display())));
+                    }
+                    (None, false) => return Err(invalid(format!("{}: no column to read", file.display()))),
+                };
+                let text = read(file)?;
+                let mut rows = csv_rows(file, &text);
+                let header = rows.next().unwrap_or(Ok((1, vec![])))?.1;
+                let cols = columns
+                    .iter()
+                    .map(|column| {
+                        header.iter().position(|h| h == column).ok_or_else(|| {
+                            invalid(format!("{}: no column named {:?}", file.display(), column))
+                        })
+                    })
+                    .collect::<io::Result<Vec<usize>>>()?;
+                for row in rows {
+                    let (line, values) = row?;
+                    let values = cols
+                        .iter()
+                        .zip(&columns)
+                        .map(|(&col, column)| {
+                            values.get(col).map(String::as_str).ok_or_else(|| {
+                                invalid(format!("{}:{}: no field for column {:?}", file.display(), line, column))
+                            })
+                        })
+                        .collect::<io::Result<Vec<&str>>>()?;
+                    add(&values, 1);
+                }
+            }
+            Source::Counts(file) => {
+                let text = read(file)?;
+                for row in csv_rows(file, &text) {
+                    let (line, values) = row?;
+                    let count = match &values[..] {
+                        [.., count] if values.len() == fields.len() + 1 => count.trim().parse::<usize>().ok(),
+                        _ => None,
+                    };
+                    let count = count.ok_or_else(|| {
+                        let expected = if tuples { "fields,count" } else { "string,count" };
+                        invalid(format!("{}:{}: expected {}", file.display(), line, expected))
+                    })?;
+                    let values: Vec<&str> = values[..fields.len()].iter().map(String::as_str).collect();
+                    add(&values, count);
+                }
+            }
+        }
+        Ok(pop)
+    }
+
+    // Number of clients.
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    // Keeps only the first n clients.
+    pub fn truncate(&mut self, n: usize) {
+        self.clients.truncate(n);
+        self.cut.retain(|_, ranges| {
+            ranges.retain_mut(|r| {
+                r.end = r.end.min(n);
+                r.start < r.end
+            });
+            !ranges.is_empty()
+        });
+    }
+
+    // Only the clients in the range, numbered from its start.
+    pub fn window(&self, clients: Range<usize>) -> Population {
+        let mut cut = self.cut.clone();
+        cut.retain(|_, ranges| {
+            ranges.retain_mut(|r| {
+                let (start, end) = (r.start.max(clients.start), r.end.min(clients.end));
+                *r = start.saturating_sub(clients.start)..end.saturating_sub(clients.start);
+                r.start < r.end
+            });
+            !ranges.is_empty()
+        });
+        Population {
+            sites: self.sites.clone(),
+            clients: self.clients[clients].to_vec(),
+            cut,
+        }
+    }
+
+    // The input strings that at least `threshold` clients hold but that
+    // were cut to fit, so that the output can at best name a prefix of
+    // them.
+    pub fn cut_heavy(&self, threshold: usize) -> Vec<&str> {
+        self.cut
+            .iter()
+            .filter(|(_, ranges)| ranges.iter().map(|r| r.len()).sum::<usize>() >= threshold)
+            .map(|(s, _)| s.as_str())
+            .collect()
+    }
+
+    // Each client's string, in client order.
+    pub fn strings(&self) -> Vec<Vec<bool>> {
+        self.clients.iter().map(|&i| self.sites[i].clone()).collect()
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn read(file: &Path) -> io::Result<String> {
+    fs::read_to_string(file).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file.display(), e)))
+}
+
+fn random_strings(n: usize, data_len: usize) -> Vec<Vec<bool>> {
+    let mut rng = rand::thread_rng();
+    (0..n)
+        .map(|_| {
+            let s: String = std::iter::repeat(())
+                .map(|()| rng.sample(Alphanumeric))
+                .take(data_len / 8)
+                .collect();
+            encode(&s, data_len)
+        })
+        .collect()
+}
+
+// The non-blank rows of a CSV file, with their line numbers. Fields may
+// be quoted, with "" standing for a quote, but may not span lines.
+fn csv_rows<'a>(
+    file: &'a Path,
+    text: &'a str,
+) -> impl Iterator<Item = io::Result<(usize, Vec<String>)>> + 'a {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(move |(i, line)| {
+            csv_fields(line)
+                .map(|fields| (i + 1, fields))
+                .ok_or_else(|| invalid(format!("{}:{}: unterminated quote", file.display(), i + 1)))
+        })
+}
+
+fn csv_fields(line: &str) -> Option<Vec<String>> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (_, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("counttree-input-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(encode("ab", 16), crate::string_to_bits("ab"));
+        assert_eq!(decode(&encode("ab", 16)), "ab");
+        assert_eq!(decode(&encode("abcdef", 16)), "ab");
+        assert_eq!(encode("a", 24).len(), 24);
+        assert_eq!(decode(&encode("a", 24)), "a");
+
+        // "é" is two bytes, and cutting it after one leaves half a
+        // character.
+        assert_eq!(decode(&encode("aé", 16)), "a\u{FFFD}");
+    }
+
+    #[test]
+    fn cut_strings() {
+        let lines = file("cut", "abc\nab\nabc\nxyz\nabc\n");
+        let mut pop = Population::load(&Source::Lines(lines.clone()), 16).unwrap();
+        assert_eq!(pop.sites.len(), 2);
+        assert_eq!(pop.cut["abc"], [0..1, 2..3, 4..5]);
+        assert_eq!(pop.cut_heavy(3), ["abc"]);
+        assert_eq!(pop.cut_heavy(1), ["abc", "xyz"]);
+
+        let window = pop.window(1..4);
+        assert_eq!(window.strings(), pop.strings()[1..4]);
+        assert_eq!(window.cut["abc"], vec![1..2]);
+        assert_eq!(window.cut_heavy(1), ["abc", "xyz"]);
+
+        pop.truncate(4);
+        assert_eq!(pop.cut["abc"], [0..1, 2..3]);
+        assert!(pop.cut_heavy(3).is_empty());
+        pop.truncate(2);
+        assert_eq!(pop.cut_heavy(1), ["abc"]);
+        fs::remove_file(lines).unwrap();
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(csv_fields("a,b,c").unwrap(), ["a", "b", "c"]);
+        assert_eq!(csv_fields("\"a,b\",\"say \"\"hi\"\"\",").unwrap(), ["a,b", "say \"hi\"", ""]);
+        assert!(csv_fields("\"a,b").is_none());
+    }
+
+    #[test]
+    fn sources() {
+        let lines = file("lines", "ab\ncd\n\nab\nabc\r\n");
+        let pop = Population::load(&Source::Lines(lines.clone()), 16).unwrap();
+        assert_eq!(pop.len(), 4);
+        assert_eq!(pop.sites.len(), 2);
+        assert_eq!(pop.clients, [0, 1, 0, 0]);
+
+        let csv = file("csv", "id,url\n1,ab\n2,\"cd\"\n3,ab\n");
+        let source = Source::Csv {
+            file: csv.clone(),
+            column: Some("url".to_string()),
+        };
+        let pop = Population::load(&source, 16).unwrap();
+        assert_eq!(pop.strings(), [encode("ab", 16), encode("cd", 16), encode("ab", 16)]);
+
+        let source = Source::Csv {
+            file: csv.clone(),
+            column: Some("host".to_string()),
+        };
+        let err = Population::load(&source, 16).unwrap_err();
+        assert!(err.to_string().contains("no column named \"host\""), "{}", err);
+
+        let counts = file("counts", "ab,3\n\"c,d\",2\n");
+        let pop = Population::load(&Source::Counts(counts.clone()), 24).unwrap();
+        assert_eq!(pop.len(), 5);
+        assert_eq!(decode(&pop.sites[1]), "c,d");
+        assert!(pop.cut.is_empty());
+
+        let bad = file("bad", "ab,3\ncd\n");
+        let err = Population::load(&Source::Counts(bad.clone()), 16).unwrap_err();
+        assert!(err.to_string().contains(":2: expected string,count"), "{}", err);
+
+        for f in [lines, csv, counts, bad] {
+            fs::remove_file(f).unwrap();
+        }
+    }
+
+    #[test]
+    fn tuples() {
+        let fields = [
+            Field {
+                name: "country".to_string(),
+                bytes: 2,
+            },
+            Field {
+                name: "url".to_string(),
+                bytes: 3,
+            },
+        ];
+        let bits = encode_tuple(&["us", "a/b"], &fields);
+        assert_eq!(bits.len(), 40);
+        assert_eq!(bits[..16], encode("us", 16));
+        assert_eq!(decode_tuple(&bits, &fields), ["us", "a/b"]);
+        assert_eq!(decode_tuple(&bits[..27], &fields), ["us", "a"]);
+        assert_eq!(decode_tuple(&encode_tuple(&["", "a"], &fields), &fields), ["", "a"]);
+
+        let lines = file("tuple-lines", "us\ta/b\nfr\tc\n");
+        let pop = Population::load_tuples(&Source::Lines(lines.clone()), &fields).unwrap();
+        assert_eq!(pop.strings(), [bits.clone(), encode_tuple(&["fr", "c"], &fields)]);
+
+        let csv = file("tuple-csv", "url,id,country\na/b,1,us\nc,2,fr\n");
+        let source = Source::Csv {
+            file: csv.clone(),
+            column: None,
+        };
+        let pop = Population::load_tuples(&source, &fields).unwrap();
+        assert_eq!(pop.strings(), [bits.clone(), encode_tuple(&["fr", "c"], &fields)]);
+
+        let counts = file("tuple-counts", "us,a/b/c,3\nfr,c,2\n");
+        let pop = Population::load_tuples(&Source::Counts(counts.clone()), &fields).unwrap();
+        assert_eq!(pop.len(), 5);
+        assert_eq!(pop.cut_heavy(3), ["[\"us\", \"a/b/c\"]"]);
+        assert_eq!(decode_tuple(&pop.sites[0], &fields), ["us", "a/b"]);
+
+        let err = Population::load(&Source::Counts(counts.clone()), 16).unwrap_err();
+        assert!(err.to_string().contains(":1: expected string,count"), "{}", err);
+        let err = Population::load_tuples(&Source::Lines(counts.clone()), &fields).unwrap_err();
+        assert!(err.to_string().contains(":1: expected 2 tab-separated fields"), "{}", err);
+
+        for f in [lines, csv, counts] {
+            fs::remove_file(f).unwrap();
+        }
+    }
+}
@@ -1,13 +1,23 @@
 // extern crate cpuprofiler;
 
+pub mod auth;
 pub mod collect;
 pub mod config;
 pub mod dpf;
+pub mod envelope;
 pub mod fastfield;
 mod field;
+pub mod field127;
+pub mod field255;
+pub mod http;
+pub mod input;
+pub mod metrics;
 pub mod mpc;
 pub mod prg;
+pub mod report;
 pub mod rpc;
+pub mod server;
+pub mod sim;
 pub mod sketch;
 
 #[macro_use]
@@ -15,6 +25,8 @@ extern crate lazy_static;
 
 pub use crate::field::Dummy;
 pub use crate::field::FieldElm;
+pub use crate::field127::FieldElm127;
+pub use crate::field255::FieldElm255;
 pub use crate::rpc::CollectorClient;
 
 // Additive group, such as (Z_n, +)
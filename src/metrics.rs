@@ -0,0 +1,202 @@
+// Prometheus metrics for the servers and the leader, served in the text
+// format on GET /metrics. Each process keeps its own registry, and only
+// updates the metrics that make sense for its role: the servers time
+// and size every RPC, while the leader times each level as a whole.
+
+use axum::{http::header, routing::get, Router};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use serde::Serialize;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub struct Metrics {
+    registry: Registry,
+    /// Keys added to a collection.
+    pub keys_ingested: IntCounter,
+    /// Keys dropped, by reason: `undecryptable`, `bad_token`, `replay`
+    /// and `malformed` on the servers, `sketch` on the leader.
+    pub keys_rejected: IntCounterVec,
+    /// Number of nodes at each level of the tree after crawling it.
+    pub frontier_size: IntGaugeVec,
+    /// Time that the server took to handle each RPC, by method.
+    pub rpc_seconds: HistogramVec,
+    /// Encoded size of each RPC request and response, by method.
+    pub rpc_request_bytes: HistogramVec,
+    pub rpc_response_bytes: HistogramVec,
+    /// Time that the leader took to crawl each level, and to check each
+    /// level's sketches.
+    pub crawl_seconds: Histogram,
+    pub sketch_seconds: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let seconds = exponential_buckets(0.001, 4.0, 10).unwrap();
+        let bytes = exponential_buckets(64.0, 4.0, 15).unwrap();
+
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("counttree".into()), None).unwrap(),
+            keys_ingested: IntCounter::new("keys_ingested_total", "Keys added to a collection")
+                .unwrap(),
+            keys_rejected: IntCounterVec::new(
+                Opts::new("keys_rejected_total", "Keys dropped, by reason"),
+                &["reason"],
+            )
+            .unwrap(),
+            frontier_size: IntGaugeVec::new(
+                Opts::new("frontier_size", "Nodes at each level of the tree after crawling it"),
+                &["level"],
+            )
+            .unwrap(),
+            rpc_seconds: HistogramVec::new(
+                HistogramOpts::new("rpc_seconds", "Time to handle each RPC").buckets(seconds.clone()),
+                &["method"],
+            )
+            .unwrap(),
+            rpc_request_bytes: HistogramVec::new(
+                HistogramOpts::new("rpc_request_bytes", "Encoded size of each RPC request")
+                    .buckets(bytes.clone()),
+                &["method"],
+            )
+            .unwrap(),
+            rpc_response_bytes: HistogramVec::new(
+                HistogramOpts::new("rpc_response_bytes", "Encoded size of each RPC response")
+                    .buckets(bytes),
+                &["method"],
+            )
+            .unwrap(),
+            crawl_seconds: Histogram::with_opts(
+                HistogramOpts::new("crawl_seconds", "Time to crawl one level").buckets(seconds.clone()),
+            )
+            .unwrap(),
+            sketch_seconds: Histogram::with_opts(
+                HistogramOpts::new("sketch_seconds", "Time to check one level's sketches")
+                    .buckets(seconds),
+            )
+            .unwrap(),
+        };
+
+        let r = &metrics.registry;
+        r.register(Box::new(metrics.keys_ingested.clone())).unwrap();
+        r.register(Box::new(metrics.keys_rejected.clone())).unwrap();
+        r.register(Box::new(metrics.frontier_size.clone())).unwrap();
+        r.register(Box::new(metrics.rpc_seconds.clone())).unwrap();
+        r.register(Box::new(metrics.rpc_request_bytes.clone())).unwrap();
+        r.register(Box::new(metrics.rpc_response_bytes.clone())).unwrap();
+        r.register(Box::new(metrics.crawl_seconds.clone())).unwrap();
+        r.register(Box::new(metrics.sketch_seconds.clone())).unwrap();
+        metrics
+    }
+
+    pub fn reject(&self, reason: &str, n: usize) {
+        self.keys_rejected.with_label_values(&[reason]).inc_by(n as u64);
+    }
+
+    pub fn set_frontier_size(&self, level: usize, n: usize) {
+        self.frontier_size
+            .with_label_values(&[&level.to_string()])
+            .set(n as i64);
+    }
+
+    /// Runs one RPC handler, recording how long it took and how big its
+    /// request and response are.
+    pub fn observe_rpc<Req, Resp>(&self, method: &str, req: &Req, f: impl FnOnce() -> Resp) -> Resp
+    where
+        Req: Serialize,
+        Resp: Serialize,
+    {
+        let start = Instant::now();
+        let resp = f();
+        self.rpc_seconds
+            .with_label_values(&[method])
+            .observe(start.elapsed().as_secs_f64());
+
+        let req_len = bincode::serialized_size(req).unwrap_or(0);
+        let resp_len = bincode::serialized_size(&resp).unwrap_or(0);
+        self.rpc_request_bytes
+            .with_label_values(&[method])
+            .observe(req_len as f64);
+        self.rpc_response_bytes
+            .with_label_values(&[method])
+            .observe(resp_len as f64);
+        resp
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut out)
+            .expect("Could not encode metrics");
+        String::from_utf8(out).expect("Metrics are not UTF-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+/// Binds a `/metrics` endpoint for `metrics` to `addr`. Like http::bind,
+/// returns the address bound and a future that serves requests.
+pub fn bind(
+    metrics: Arc<Metrics>,
+    addr: &SocketAddr,
+) -> io::Result<(SocketAddr, impl Future<Output = io::Result<()>> + Send)> {
+    let router = Router::new().route(
+        "/metrics",
+        get(move || async move {
+            let content_type = TextEncoder::new().format_type().to_string();
+            ([(header::CONTENT_TYPE, content_type)], metrics.render())
+        }),
+    );
+    let http = axum::Server::try_bind(addr)
+        .map_err(io::Error::other)?
+        .serve(router.into_make_service());
+    let local_addr = http.local_addr();
+
+    Ok((local_addr, async move { http.await.map_err(io::Error::other) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let m = Metrics::new();
+        m.keys_ingested.inc_by(3);
+        m.reject("replay", 2);
+        m.set_frontier_size(4, 17);
+        let n = m.observe_rpc("tree_crawl", &vec![0u8; 100], || vec![0u8; 1000]);
+        assert_eq!(n.len(), 1000);
+
+        let text = m.render();
+        assert!(text.contains("counttree_keys_ingested_total 3"));
+        assert!(text.contains("counttree_keys_rejected_total{reason=\"replay\"} 2"));
+        assert!(text.contains("counttree_frontier_size{level=\"4\"} 17"));
+        assert!(text.contains("counttree_rpc_seconds_count{method=\"tree_crawl\"} 1"));
+        assert!(text.contains("counttree_rpc_response_bytes_sum{method=\"tree_crawl\"} 1008"));
+    }
+
+    #[tokio::test]
+    async fn endpoint() {
+        let m = Arc::new(Metrics::new());
+        m.keys_ingested.inc();
+        let (addr, serving) = bind(m, &"127.0.0.1:0".parse().unwrap()).unwrap();
+        tokio::spawn(serving);
+
+        let uri = format!("http://{}/metrics", addr).parse().unwrap();
+        let resp = hyper::Client::new().get(uri).await.unwrap();
+        assert!(resp.status().is_success());
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("counttree_keys_ingested_total 1"));
+    }
+}
@@ -1,12 +1,18 @@
+use crate::config::{FieldPair, FieldType};
+use crate::envelope;
+use crate::prg;
 use crate::sketch;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TripleShare<T> {
     pub a: T,
     pub b: T,
     pub c: T,
+    // The dealer's MAC on a, under this server's DealerKey.
+    pub a_mac: T,
 }
 
 // XXX: Optimization: compress Beaver triples.
@@ -14,6 +20,7 @@ impl<T> TripleShare<T>
 where
     T: crate::Share + std::fmt::Debug,
 {
+    // A triple with no MACs, which deal_triples() fills in.
     pub fn new() -> [TripleShare<T>; 2] {
         let (a_s0, a_s1) = T::share_random();
         let (b_s0, b_s1) = T::share_random();
@@ -34,16 +41,269 @@ where
                 a: a_s0,
                 b: b_s0,
                 c: c_s0,
+                a_mac: T::zero(),
             },
             TripleShare {
                 a: a_s1,
                 b: b_s1,
                 c: c_s1,
+                a_mac: T::zero(),
             },
         ]
     }
 }
 
+// Length of the secret that a DealerKey comes from.
+pub const DEALER_SECRET_LEN: usize = 16;
+
+/// The secret that the dealer MACs each server's shares of the triples
+/// under, so that a server cannot lie to the leader about them. The
+/// dealer and the leader share it; the servers never learn it.
+///
+/// Server b's MAC on its share a of triple i is delta_b * a + kappa_b(i),
+/// where delta_b and kappa_b are drawn from the secret. The leader only
+/// ever sees sums of shares over many triples, which say nothing about
+/// any one client.
+#[derive(Clone, Debug)]
+pub struct DealerKey {
+    seed: prg::PrgSeed,
+}
+
+impl DealerKey {
+    pub fn random() -> DealerKey {
+        DealerKey { seed: prg::PrgSeed::random() }
+    }
+
+    pub fn from_seed(seed: prg::PrgSeed) -> DealerKey {
+        DealerKey { seed }
+    }
+
+    // Parses a secret of DEALER_SECRET_LEN bytes in hex, or returns None
+    // if it is not that.
+    pub fn from_secret_hex(hex: &str) -> Option<DealerKey> {
+        let bytes = envelope::from_hex(hex).filter(|b| b.len() == DEALER_SECRET_LEN)?;
+        let mut seed = prg::PrgSeed::zero();
+        seed.key.copy_from_slice(&bytes);
+        Some(DealerKey { seed })
+    }
+
+    // The key for the triples in one field. Each field has its own pool,
+    // numbered from zero.
+    pub fn for_field(&self, field: FieldType) -> DealerKey {
+        let tag = match field {
+            FieldType::FE62 => 1,
+            FieldType::M127 => 2,
+            FieldType::P255 => 3,
+        };
+        DealerKey { seed: self.derive(tag, 0, 0).expand().seeds.0 }
+    }
+
+    fn derive(&self, kind: u8, server: usize, index: u64) -> prg::PrgSeed {
+        let mut tag = prg::PrgSeed::zero();
+        tag.key[0] = kind;
+        tag.key[1] = server as u8;
+        tag.key[8..].copy_from_slice(&index.to_le_bytes());
+        &self.seed ^ &tag
+    }
+
+    fn word<T: crate::Share>(&self, kind: u8, server: usize, index: u64) -> T {
+        self.derive(kind, server, index).convert::<T>().word
+    }
+
+    // Server `server`'s MAC on its share `a` of triple `index`.
+    pub fn mac<T: crate::Share>(&self, server: usize, index: u64, a: &T) -> T {
+        let mut mac = self.word::<T>(0x10, server, 0);
+        mac.mul(a);
+        mac.add(&self.word(0x11, server, index));
+        mac
+    }
+
+    // Whether `check` holds the sums of server `server`'s shares of a,
+    // and of their MACs, over the first triple of each key's sketch for
+    // which `live` holds, as CheckShare::new() takes them.
+    pub fn verify<T>(&self, server: usize, live: &[bool], check: &CheckShare<T>) -> bool
+    where
+        T: crate::Share + std::cmp::PartialEq,
+    {
+        let mut mac = self.word::<T>(0x10, server, 0);
+        mac.mul(&check.a);
+        for (i, _) in live.iter().enumerate().filter(|(_, l)| **l) {
+            let index = check.first + (sketch::TRIPLES_PER_LEVEL * i) as u64;
+            mac.add(&self.word(0x11, server, index));
+        }
+        mac == check.a_mac
+    }
+}
+
+// Stand-in for a trusted dealer, which generates n Beaver triples and
+// gives each server its shares, MACed under `key`, numbering them from
+// `first`. The dealer must not collude with either server or with the
+// leader.
+pub fn deal_triples<T>(key: &DealerKey, first: u64, n: usize) -> [Vec<TripleShare<T>>; 2]
+where
+    T: crate::Share + std::fmt::Debug,
+{
+    let mut out0 = Vec::with_capacity(n);
+    let mut out1 = Vec::with_capacity(n);
+    for i in 0..n {
+        let [mut t0, mut t1] = TripleShare::new();
+        t0.a_mac = key.mac(0, first + i as u64, &t0.a);
+        t1.a_mac = key.mac(1, first + i as u64, &t1.a);
+        out0.push(t0);
+        out1.push(t1);
+    }
+    [out0, out1]
+}
+
+/// One server's sums over the keys live in a count, of its shares of the
+/// first triple's a in each key's sketch, and of their MACs. The triples
+/// for a level's sketches are numbered from `first`, TRIPLES_PER_LEVEL
+/// for each key in turn.
+///
+/// With these, the leader can check a server's counts against its shares
+/// of the sketches: see counts_match().
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CheckShare<T> {
+    pub first: u64,
+    pub a: T,
+    pub a_mac: T,
+}
+
+impl<T> CheckShare<T>
+where
+    T: crate::Share,
+{
+    // For the sketches of a level that use `triples`, numbered from
+    // `first`, over the keys for which `live` holds.
+    pub fn new(first: u64, triples: &[TripleShare<T>], live: &[bool]) -> CheckShare<T> {
+        let mut out = CheckShare { first, a: T::zero(), a_mac: T::zero() };
+        for (t, _) in triples.chunks(sketch::TRIPLES_PER_LEVEL).zip(live).filter(|(_, l)| **l) {
+            out.a.add(&t[0].a);
+            out.a_mac.add(&t[0].a_mac);
+        }
+        out
+    }
+
+}
+
+// Whether one server's counts at the nodes of a level match its shares
+// of the level's sketches, which the leader opened with `weights`, the
+// sketch randomness at each node. `masked` is the sum over the live keys
+// of the server's share of <r, x> less the first triple's a, as the
+// leader got it in the sketches' corrections.
+//
+// Each server's counts are the sums of its shares of the live keys'
+// values at each node, so an honest server's always match, whatever the
+// other server does. A server that shifts a count in its crawl output
+// would have to shift its share of some key's <r, x> to match, which it
+// can only do once it knows the weights, and which throws off that key's
+// sketch check.
+pub fn counts_match<T>(weights: &[T], counts: &[T], masked: &T, check: &CheckShare<T>) -> bool
+where
+    T: crate::Share + std::cmp::PartialEq,
+{
+    if weights.len() != counts.len() {
+        return false;
+    }
+    let mut want = T::zero();
+    for (w, c) in weights.iter().zip(counts) {
+        let mut term = w.clone();
+        term.mul(c);
+        want.add(&term);
+    }
+    let mut got = masked.clone();
+    got.add(&check.a);
+    got == want
+}
+
+// Number of triples in each field that the servers may use up checking
+// the sketches of nclients clients over data_len-bit strings. A level at
+// which some keys fail is sketched a second time, after the recount; with
+// honest servers, every key left then passes.
+pub fn triples_needed(fields: FieldPair, data_len: usize, nclients: usize) -> Vec<(FieldType, u64)> {
+    let per_level = (2 * sketch::TRIPLES_PER_LEVEL * nclients) as u64;
+    let inner = per_level * (data_len as u64 - 1);
+    if fields.inner == fields.last {
+        vec![(fields.inner, inner + per_level)]
+    } else {
+        vec![(fields.inner, inner), (fields.last, per_level)]
+    }
+}
+
+// One server's supply of Beaver triples, filled in the offline phase.
+//
+// The dealer numbers the triples it deals to each server in the same
+// way, and sends them in batches tagged with the number of their first
+// triple. Batches may arrive in any order, but triples only become
+// available once all earlier ones have arrived. Both servers use up
+// triples in the same order, so the i-th triple taken on one server
+// always matches the i-th triple taken on the other.
+pub struct TriplePool<T> {
+    ready: VecDeque<TripleShare<T>>,
+    early: BTreeMap<u64, Vec<TripleShare<T>>>,
+    dealt: u64,
+}
+
+impl<T> TriplePool<T>
+where
+    T: crate::Share + std::fmt::Debug,
+{
+    pub fn new() -> TriplePool<T> {
+        TriplePool {
+            ready: VecDeque::new(),
+            early: BTreeMap::new(),
+            dealt: 0,
+        }
+    }
+
+    pub fn add(&mut self, first: u64, triples: Vec<TripleShare<T>>) {
+        // A dealer that retries a call may send a batch again.
+        if first < self.dealt {
+            return;
+        }
+        self.early.insert(first, triples);
+
+        while let Some(batch) = self.early.remove(&self.dealt) {
+            self.dealt += batch.len() as u64;
+            self.ready.extend(batch);
+        }
+    }
+
+    // Number of triples received so far, not counting any that arrived
+    // ahead of a missing batch. The dealer numbers new triples from here.
+    pub fn dealt(&self) -> u64 {
+        self.dealt
+    }
+
+    pub fn available(&self) -> usize {
+        self.ready.len()
+    }
+
+    // The number of the next triple to be taken.
+    pub fn taken(&self) -> u64 {
+        self.dealt - self.ready.len() as u64
+    }
+
+    pub fn take(&mut self, n: usize) -> Vec<TripleShare<T>> {
+        assert!(
+            n <= self.ready.len(),
+            "Out of Beaver triples: need {}, have {}",
+            n,
+            self.ready.len()
+        );
+        self.ready.drain(..n).collect()
+    }
+}
+
+impl<T> Default for TriplePool<T>
+where
+    T: crate::Share + std::fmt::Debug,
+{
+    fn default() -> Self {
+        TriplePool::new()
+    }
+}
+
 // We will compute in MPC:
 //    \sum_i [ (x_i * y_i) + z_i ]
 #[derive(Clone)]
@@ -85,16 +345,12 @@ where
         mac_key: &T,
         mac_key2: &T,
         sketch: &sketch::SketchOutput<T>,
-        level: usize,
     ) -> MulState<T> {
-        debug_assert!((level + 1) * sketch::TRIPLES_PER_LEVEL <= triples.len());
-
-        let trip_start = level * sketch::TRIPLES_PER_LEVEL;
-        let trip_end = trip_start + sketch::TRIPLES_PER_LEVEL;
+        debug_assert_eq!(triples.len(), sketch::TRIPLES_PER_LEVEL);
 
         let mut out = MulState {
             server_idx,
-            triples: triples[trip_start..trip_end].to_vec(),
+            triples,
 
             xs: Vec::with_capacity(sketch::TRIPLES_PER_LEVEL),
             ys: Vec::with_capacity(sketch::TRIPLES_PER_LEVEL),
@@ -250,22 +506,28 @@ where
         ManyMulState { states: vec![] }
     }
 
+    // Uses TRIPLES_PER_LEVEL triples for each sketch, in order.
     pub fn new(
         server_idx: bool,
-        triples: &[Vec<TripleShare<T>>],
+        triples: &[TripleShare<T>],
         mac_keys: &[T],
         mac_keys2: &[T],
         sketch: &[sketch::SketchOutput<T>],
-        level: usize,
     ) -> ManyMulState<T> {
-        debug_assert_eq!(triples.len(), sketch.len());
+        debug_assert_eq!(mac_keys.len(), sketch.len());
+        assert_eq!(triples.len(), sketch::TRIPLES_PER_LEVEL * sketch.len());
 
         let mut out = ManyMulState {
-            states: Vec::with_capacity(triples.len()),
+            states: Vec::with_capacity(sketch.len()),
         };
-        for i in 0..triples.len() {
-            out.states
-                .push(MulState::new(server_idx, triples[i].clone(), &mac_keys[i], &mac_keys2[i], &sketch[i], level));
+        for (i, triples) in triples.chunks(sketch::TRIPLES_PER_LEVEL).enumerate() {
+            out.states.push(MulState::new(
+                server_idx,
+                triples.to_vec(),
+                &mac_keys[i],
+                &mac_keys2[i],
+                &sketch[i],
+            ));
         }
 
         out
@@ -281,6 +543,17 @@ where
         out
     }
 
+    // The sum of one server's corrections for the first multiplication,
+    // its share of <r, x> less the triple's a, over the sketches for which
+    // `live` holds. See counts_match().
+    pub fn masked_r_x(shares: &ManyCorShare<T>, live: &[bool]) -> T {
+        let mut out = T::zero();
+        for (share, _) in shares.cor_shares.iter().zip(live).filter(|(_, l)| **l) {
+            out.add(&share.ds[0]);
+        }
+        out
+    }
+
     pub fn cors(s0: &ManyCorShare<T>, s1: &ManyCorShare<T>) -> ManyCor<T> {
         debug_assert_eq!(s0.cor_shares.len(), s1.cor_shares.len());
 
@@ -295,6 +568,15 @@ where
         out
     }
 
+    // Whether cor has a correction for every multiplication here, as
+    // out_shares needs.
+    pub fn fits(&self, cor: &ManyCor<T>) -> bool {
+        cor.cors.len() == self.states.len()
+            && cor.cors.iter().all(|c| {
+                c.ds.len() == sketch::TRIPLES_PER_LEVEL && c.es.len() == sketch::TRIPLES_PER_LEVEL
+            })
+    }
+
     pub fn out_shares(&self, cor: &ManyCor<T>) -> ManyOutShare<T> {
         debug_assert_eq!(cor.cors.len(), self.states.len());
         let mut out = ManyOutShare {
@@ -344,4 +626,95 @@ mod tests {
 
         assert_eq!(ab, c);
     }
+
+    #[test]
+    fn pool_orders_batches() {
+        let [d0, d1] = deal_triples::<FieldElm>(&DealerKey::random(), 0, 5);
+
+        // Server 0 gets the batches in order, server 1 out of order.
+        let mut pool0 = TriplePool::new();
+        pool0.add(0, d0[0..2].to_vec());
+        pool0.add(2, d0[2..5].to_vec());
+
+        let mut pool1 = TriplePool::new();
+        pool1.add(2, d1[2..5].to_vec());
+        assert_eq!(pool1.dealt(), 0);
+        assert_eq!(pool1.available(), 0);
+        pool1.add(0, d1[0..2].to_vec());
+        assert_eq!(pool1.dealt(), 5);
+
+        for _ in 0..5 {
+            let t0 = pool0.take(1).pop().unwrap();
+            let t1 = pool1.take(1).pop().unwrap();
+
+            let mut a = t0.a.clone();
+            a.add(&t1.a);
+            let mut b = t0.b.clone();
+            b.add(&t1.b);
+            let mut c = t0.c.clone();
+            c.add(&t1.c);
+
+            a.mul(&b);
+            assert_eq!(a, c);
+        }
+        assert_eq!(pool0.available(), 0);
+    }
+
+    #[test]
+    fn pool_ignores_resent_batch() {
+        let [t0, _] = deal_triples::<FieldElm>(&DealerKey::random(), 0, 4);
+        let mut pool = TriplePool::new();
+        pool.add(0, t0[..2].to_vec());
+        pool.add(0, t0[..2].to_vec());
+        pool.add(2, t0[2..].to_vec());
+        pool.add(2, t0[2..].to_vec());
+        assert_eq!(pool.dealt(), 4);
+        assert_eq!(pool.available(), 4);
+    }
+
+    #[test]
+    fn pool_counts_taken() {
+        let [t0, _] = deal_triples::<FieldElm>(&DealerKey::random(), 0, 6);
+        let mut pool = TriplePool::new();
+        pool.add(0, t0);
+        assert_eq!(pool.taken(), 0);
+        pool.take(4);
+        assert_eq!(pool.taken(), 4);
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn dealer_macs() {
+        let key = DealerKey::random().for_field(FieldType::FE62);
+        let first = 30;
+        let [t0, t1] = deal_triples::<FieldElm>(&key, first, 4 * sketch::TRIPLES_PER_LEVEL);
+        let live = [true, false, true, true];
+
+        let check0 = CheckShare::new(first, &t0, &live);
+        let check1 = CheckShare::new(first, &t1, &live);
+        assert!(key.verify(0, &live, &check0));
+        assert!(key.verify(1, &live, &check1));
+
+        // Each server's MACs are under its own key.
+        assert!(!key.verify(1, &live, &check0));
+        // Nor do they cover other triples.
+        assert!(!key.verify(0, &[true, true, true, true], &check0));
+        assert!(!key.verify(0, &live, &CheckShare { first: first + 1, ..check0.clone() }));
+        assert!(!DealerKey::random().for_field(FieldType::FE62).verify(0, &live, &check0));
+
+        // A server that shifts its sum of a cannot shift the MAC to match.
+        let mut lie = check0.clone();
+        lie.a.add(&FieldElm::from(1u32));
+        assert!(!key.verify(0, &live, &lie));
+        lie.a_mac.add(&FieldElm::from(1u32));
+        assert!(!key.verify(0, &live, &lie));
+    }
+
+    #[test]
+    #[should_panic(expected = "Out of Beaver triples")]
+    fn pool_empty() {
+        let mut pool = TriplePool::<FieldElm>::new();
+        pool.add(0, deal_triples(&DealerKey::random(), 0, 2)[0].clone());
+        pool.take(3);
+    }
 }
@@ -1,10 +1,6 @@
-use core::arch::x86_64::{
-    __m128i, _mm_add_epi64, _mm_loadu_si128, _mm_set_epi64x, _mm_storeu_si128,
-};
-
-use aes::block_cipher::{generic_array::GenericArray, Block, BlockCipher, NewBlockCipher};
+use aes::block_cipher::{generic_array::GenericArray, BlockCipher, NewBlockCipher};
 use aes::Aes128;
-use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher};
+use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher, SyncStreamCipherSeek};
 use aes_ctr::Aes128Ctr;
 
 use rand::Rng;
@@ -24,7 +20,7 @@ pub const AES_BLOCK_SIZE: usize = 16;
 // XXX Todo try using 8-way parallelism
 pub struct FixedKeyPrgStream {
     aes: Aes128,
-    ctr: __m128i,
+    ctr: [u8; AES_BLOCK_SIZE],
     buf: [u8; AES_BLOCK_SIZE * 8],
     have: usize,
     buf_ptr: usize,
@@ -41,16 +37,18 @@ pub struct PrgSeed {
 }
 
 pub trait FromRng {
-    fn from_rng(&mut self, stream: &mut (impl rand::Rng + rand_core::RngCore));
+    fn from_rng(&mut self, stream: &mut impl rand::Rng);
 
     fn randomize(&mut self) {
         self.from_rng(&mut rand::thread_rng());
     }
 }
 
-#[derive(Clone)]
 pub struct PrgStream {
+    key: [u8; AES_KEY_SIZE],
     stream: Aes128Ctr,
+    // Bytes of keystream consumed so far.
+    pos: u64,
 }
 
 pub struct PrgOutput {
@@ -79,27 +77,18 @@ impl ops::BitXor for &PrgSeed {
 
 impl PrgSeed {
     pub fn to_rng(&self) -> PrgStream {
-        let iv: [u8; AES_BLOCK_SIZE] = [0; AES_BLOCK_SIZE];
-
-        let key = GenericArray::from_slice(&self.key);
-        let nonce = GenericArray::from_slice(&iv);
-        PrgStream {
-            stream: Aes128Ctr::new(key, nonce),
-        }
+        PrgStream::new(&self.key)
     }
 
     pub fn expand_dir(self: &PrgSeed, left: bool, right: bool) -> PrgOutput {
-        FIXED_KEY_STREAM.with(|s_in| {
-            let mut key_short = self.key;
+        let mut key_short = self.key;
 
-            // Zero out first two bits and use for output
-            key_short[0] &= 0xFC;
-
-            let mut s = s_in.borrow_mut();
-            s.set_key(&key_short);
+        // Zero out first two bits and use for output
+        key_short[0] &= 0xFC;
 
+        with_fixed_key_stream(&key_short, |s| {
             let mut out = PrgOutput {
-                bits: ((key_short[0] & 0x1) == 0, (key_short[0] & 0x2) == 0),
+                bits: ((self.key[0] & 0x1) == 0, (self.key[0] & 0x2) == 0),
                 seeds: (PrgSeed::zero(), PrgSeed::zero()),
             };
 
@@ -129,14 +118,9 @@ impl PrgSeed {
             word: T::zero(),
         };
 
-        FIXED_KEY_STREAM.with(|s_in| {
-            let mut s = s_in.borrow_mut();
-            s.set_key(&self.key);
+        with_fixed_key_stream(&self.key, |s| {
             s.fill_bytes(&mut out.seed.key);
-            unsafe {
-                let sp = s_in.as_ptr();
-                out.word.from_rng(&mut *sp);
-            }
+            out.word.from_rng(s);
         });
 
         out
@@ -156,6 +140,47 @@ impl PrgSeed {
     }
 }
 
+// Runs f on this thread's fixed-key stream, after setting its counter
+// to key. The stream is borrowed mutably for the duration of f, so f
+// must not call back into expand_dir() or convert().
+fn with_fixed_key_stream<R>(
+    key: &[u8; AES_KEY_SIZE],
+    f: impl FnOnce(&mut FixedKeyPrgStream) -> R,
+) -> R {
+    FIXED_KEY_STREAM.with(|s_in| {
+        let mut s = s_in.borrow_mut();
+        s.set_key(key);
+        f(&mut s)
+    })
+}
+
+impl PrgStream {
+    fn new(key: &[u8; AES_KEY_SIZE]) -> PrgStream {
+        let iv: [u8; AES_BLOCK_SIZE] = [0; AES_BLOCK_SIZE];
+
+        let aes_key = GenericArray::from_slice(key);
+        let nonce = GenericArray::from_slice(&iv);
+        PrgStream {
+            key: *key,
+            stream: Aes128Ctr::new(aes_key, nonce),
+            pos: 0,
+        }
+    }
+}
+
+// The portable AES-CTR implementation, used when AES-NI is not
+// available (e.g., under Miri), is not Clone. Rebuild the stream and
+// seek it to the same position instead. We keep our own count of the
+// position, since aesni's current_pos() reads the wrong counter half.
+impl Clone for PrgStream {
+    fn clone(&self) -> Self {
+        let mut out = PrgStream::new(&self.key);
+        out.stream.seek(self.pos);
+        out.pos = self.pos;
+        out
+    }
+}
+
 impl rand::RngCore for PrgStream {
     fn next_u32(&mut self) -> u32 {
         rand_core::impls::next_u32_via_fill(self)
@@ -171,6 +196,7 @@ impl rand::RngCore for PrgStream {
         }
 
         self.stream.apply_keystream(dest);
+        self.pos += dest.len() as u64;
     }
 
     fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
@@ -183,10 +209,9 @@ impl FixedKeyPrgStream {
     fn new() -> Self {
         let key = GenericArray::from_slice(&[0; AES_KEY_SIZE]);
 
-        let ctr_init = FixedKeyPrgStream::load(&[0; AES_BLOCK_SIZE]);
         FixedKeyPrgStream {
             aes: Aes128::new(&key),
-            ctr: ctr_init,
+            ctr: [0; AES_BLOCK_SIZE],
             buf: [0; AES_BLOCK_SIZE * 8],
             buf_ptr: AES_BLOCK_SIZE,
             have: AES_BLOCK_SIZE,
@@ -195,7 +220,7 @@ impl FixedKeyPrgStream {
     }
 
     fn set_key(&mut self, key: &[u8; 16]) {
-        self.ctr = FixedKeyPrgStream::load(key);
+        self.ctr = *key;
         self.buf_ptr = AES_BLOCK_SIZE;
         self.have = AES_BLOCK_SIZE;
     }
@@ -215,7 +240,7 @@ impl FixedKeyPrgStream {
         self.buf_ptr = 0;
 
         // Write counter into buffer.
-        FixedKeyPrgStream::store(self.ctr, &mut self.buf[0..AES_BLOCK_SIZE]);
+        self.buf[0..AES_BLOCK_SIZE].copy_from_slice(&self.ctr);
 
         let count_bytes = self.buf;
         let mut gen = GenericArray::from_mut_slice(&mut self.buf[0..AES_BLOCK_SIZE]);
@@ -241,8 +266,8 @@ impl FixedKeyPrgStream {
         let mut cnts = [[0u8; AES_BLOCK_SIZE]; 8];
         for i in 0..8 {
             // Write counter into buffer
-            FixedKeyPrgStream::store(self.ctr, &mut block8[i]);
-            FixedKeyPrgStream::store(self.ctr, &mut cnts[i]);
+            block8[i].copy_from_slice(&self.ctr);
+            cnts[i] = self.ctr;
             self.ctr = FixedKeyPrgStream::inc_be(self.ctr);
         }
 
@@ -267,32 +292,16 @@ impl FixedKeyPrgStream {
         //println!("Blocks: {:?}", self.buf[2]);
     }
 
-    // From RustCrypto aesni crate
-    #[inline(always)]
-    fn inc_be(v: __m128i) -> __m128i {
-        unsafe { _mm_add_epi64(v, _mm_set_epi64x(1, 0)) }
-    }
-
-    #[inline(always)]
-    fn store(val: __m128i, at: &mut [u8]) {
-        debug_assert_eq!(at.len(), AES_BLOCK_SIZE);
-
-        #[allow(clippy::cast_ptr_alignment)]
-        unsafe {
-            _mm_storeu_si128(at.as_mut_ptr() as *mut __m128i, val)
-        }
-    }
-
-    // Modified from RustCrypto aesni crate
+    // Same counter increment as the RustCrypto aesni crate: add one to
+    // the upper 64-bit half of the block, read as a little-endian
+    // integer, with no carry into the lower half.
     #[inline(always)]
-    fn load(key: &[u8; 16]) -> __m128i {
-        let val = Block::<Aes128>::from_slice(key);
-
-        // Safety: `loadu` supports unaligned loads
-        #[allow(clippy::cast_ptr_alignment)]
-        unsafe {
-            _mm_loadu_si128(val.as_ptr() as *const __m128i)
-        }
+    fn inc_be(mut v: [u8; AES_BLOCK_SIZE]) -> [u8; AES_BLOCK_SIZE] {
+        let mut hi = [0u8; 8];
+        hi.copy_from_slice(&v[8..]);
+        let hi = u64::from_le_bytes(hi).wrapping_add(1);
+        v[8..].copy_from_slice(&hi.to_le_bytes());
+        v
     }
 }
 
@@ -368,4 +377,152 @@ mod tests {
         assert_ne!(out.seeds.1.key, zero.key);
         assert_ne!(out.seeds.0.key, out.seeds.1.key);
     }
+
+    // Known-answer values produced by the earlier implementation, which
+    // manipulated the counter with SSE intrinsics.
+    #[test]
+    fn expand_known_answer() {
+        let seed = PrgSeed { key: [7u8; 16] };
+        let out = seed.expand();
+        assert_eq!(out.bits, (false, false));
+        assert_eq!(
+            out.seeds.0.key,
+            [188, 222, 236, 111, 159, 45, 208, 108, 18, 225, 130, 55, 135, 219, 196, 37]
+        );
+        assert_eq!(
+            out.seeds.1.key,
+            [56, 53, 26, 147, 129, 195, 158, 239, 152, 195, 43, 203, 22, 233, 118, 180]
+        );
+
+        let out = seed.expand_dir(false, true);
+        assert_eq!(
+            out.seeds.1.key,
+            [56, 53, 26, 147, 129, 195, 158, 239, 152, 195, 43, 203, 22, 233, 118, 180]
+        );
+    }
+
+    #[test]
+    fn expand_bits_from_seed() {
+        // The output bits come from the two low bits of the seed, which
+        // are masked off before keying the PRG.
+        let mut seed = PrgSeed { key: [4u8; 16] };
+        let out = seed.expand();
+        assert_eq!(out.bits, (true, true));
+
+        seed.key[0] = 5;
+        let out1 = seed.expand();
+        assert_eq!(out1.bits, (false, true));
+        assert_eq!(out.seeds.0.key, out1.seeds.0.key);
+
+        seed.key[0] = 6;
+        assert_eq!(seed.expand().bits, (true, false));
+    }
+
+    #[test]
+    fn convert_known_answer() {
+        let seed = PrgSeed { key: [7u8; 16] };
+        let seed_out = [148, 70, 211, 228, 114, 133, 95, 65, 227, 46, 108, 171, 245, 65, 79, 157];
+
+        let out = seed.convert::<crate::fastfield::FE>();
+        assert_eq!(out.seed.key, seed_out);
+        assert_eq!(out.word.value(), 359441381428230492);
+
+        let out = seed.convert::<(crate::FieldElm255, crate::FieldElm255)>();
+        assert_eq!(out.seed.key, seed_out);
+        assert_eq!(
+            out.word.0.limbs(),
+            [359441381428230492, 4019410653897976433, 7863728148087690331, 802322590878655628]
+        );
+        assert_eq!(
+            out.word.1.limbs(),
+            [6686206009902527141, 13511952995419766707, 4015223434142772537, 8072999695328257659]
+        );
+    }
+
+    #[test]
+    fn fill_known_answer() {
+        // Long reads go through refill8(), and the 0xff bytes make the
+        // upper half of the counter wrap around.
+        let mut key = [3u8; 16];
+        for b in key[8..].iter_mut() {
+            *b = 0xff;
+        }
+
+        let mut s = FixedKeyPrgStream::new();
+        s.set_key(&key);
+        let mut buf = [0u8; 160];
+        s.fill_bytes(&mut buf);
+
+        let expected: [u8; 48] = [
+            186, 47, 194, 102, 193, 39, 33, 82, 184, 55, 205, 129, 4, 195, 218, 221, 119, 49, 159,
+            208, 138, 122, 184, 54, 212, 166, 243, 124, 9, 141, 193, 246, 80, 119, 158, 38, 157,
+            225, 92, 46, 176, 17, 59, 144, 230, 231, 221, 148,
+        ];
+        assert_eq!(buf[..48], expected[..]);
+
+        let expected_tail: [u8; 16] = [
+            166, 2, 3, 188, 69, 52, 232, 210, 211, 245, 195, 178, 153, 158, 163, 71,
+        ];
+        assert_eq!(buf[142..158], expected_tail[..]);
+    }
+
+    #[test]
+    fn stream_clone() {
+        let mut s = PrgSeed::random().to_rng();
+        let _ = s.next_u64();
+        let _ = s.next_u32();
+
+        let mut copy = s.clone();
+        for _ in 0..10 {
+            assert_eq!(s.next_u64(), copy.next_u64());
+        }
+    }
+
+    #[test]
+    fn inc_wraps_upper_half() {
+        let mut ctr = [0u8; AES_BLOCK_SIZE];
+        for b in ctr.iter_mut() {
+            *b = 0xff;
+        }
+
+        let out = FixedKeyPrgStream::inc_be(ctr);
+        assert_eq!(out[..8], [0xff; 8]);
+        assert_eq!(out[8..], [0; 8]);
+    }
+
+    #[test]
+    fn convert_deterministic() {
+        let seed = PrgSeed::random();
+        let a = seed.convert::<crate::FieldElm127>();
+
+        // Interleave other uses of the thread-local stream.
+        let _ = PrgSeed::random().expand();
+        let _ = PrgSeed::random().convert::<crate::fastfield::FE>();
+
+        let b = seed.convert::<crate::FieldElm127>();
+        assert_eq!(a.seed.key, b.seed.key);
+        assert_eq!(a.word, b.word);
+    }
+
+    #[test]
+    fn convert_across_threads() {
+        let seed = PrgSeed::random();
+        let expected = seed.convert::<crate::fastfield::FE>();
+
+        let handles = (0..4)
+            .map(|_| {
+                let seed = seed.clone();
+                std::thread::spawn(move || {
+                    let out = seed.convert::<crate::fastfield::FE>();
+                    (out.seed.key, out.word)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for h in handles {
+            let (key, word) = h.join().unwrap();
+            assert_eq!(key, expected.seed.key);
+            assert_eq!(word, expected.word);
+        }
+    }
 }
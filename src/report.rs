@@ -0,0 +1,217 @@
+// How well a collection did, for the simulation and the leader: how
+// close its output came to the plaintext answer, and how much more than
+// the output the leader learned along the way.
+//
+// At each level the leader learns the count at every node that it
+// crawls. The heavy nodes it would learn about anyway, as prefixes of
+// the output. The light nodes with nonzero counts are the leakage: each
+// one tells the leader that some clients hold that prefix, and how many.
+
+use crate::collect::Suspects;
+use crate::input::Population;
+use crate::rpc::RpcField;
+
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LevelStats {
+    pub level: usize,
+    /// Nodes crawled, whose counts the leader saw.
+    pub frontier: usize,
+    /// Nodes at or above their threshold, which the crawl goes on from.
+    pub heavy: usize,
+    /// Nodes below their threshold with a nonzero count.
+    pub light: usize,
+    /// Nodes whose counts failed a check, and which the crawl dropped.
+    pub suspect: usize,
+}
+
+/// What became of the clients' keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Clients {
+    /// Keys sent to the servers.
+    pub submitted: usize,
+    /// Keys that both servers took in, each with a valid token that was
+    /// not used before.
+    pub accepted: usize,
+    /// Accepted keys that later failed a sketch check, and that the
+    /// crawl stopped counting.
+    pub rejected: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub levels: Vec<LevelStats>,
+    pub clients: Clients,
+    /// Strings in the private output.
+    pub found: usize,
+    /// Strings in the plaintext answer over the encoded inputs.
+    pub expected: usize,
+    /// Strings in both.
+    pub correct: usize,
+    /// Heavy input strings that were cut to fit in data_len bits, so that
+    /// the output cannot name them.
+    pub cut_heavy: usize,
+}
+
+// Counts one level's nodes from the counts and suspects that
+// collect::open_counts() returns, and the nodes that the leader kept.
+pub fn level_stats<V: RpcField>(level: usize, counts: &[V], keep: &[bool], suspects: &Suspects) -> LevelStats {
+    let mut stats = LevelStats {
+        level,
+        frontier: counts.len(),
+        heavy: 0,
+        light: 0,
+        suspect: suspects.nodes().len(),
+    };
+    for (v, &kept) in counts.iter().zip(keep) {
+        if kept {
+            stats.heavy += 1;
+        } else if *v != V::zero() {
+            stats.light += 1;
+        }
+    }
+    stats
+}
+
+impl Report {
+    // Compares the paths in the output to `expected`, the plaintext
+    // answer for the population that sim::answer() gives. Input strings
+    // that were cut count as heavy at `threshold` clients.
+    pub fn new(
+        levels: Vec<LevelStats>,
+        clients: Clients,
+        output: &[Vec<bool>],
+        pop: &Population,
+        expected: &[(Vec<bool>, usize)],
+        threshold: u32,
+    ) -> Report {
+        let expected: BTreeSet<&Vec<bool>> = expected.iter().map(|(path, _)| path).collect();
+        let found: BTreeSet<&Vec<bool>> = output.iter().collect();
+
+        Report {
+            levels,
+            clients,
+            found: found.len(),
+            expected: expected.len(),
+            correct: found.iter().filter(|p| expected.contains(*p)).count(),
+            cut_heavy: pop.cut_heavy(threshold as usize).len(),
+        }
+    }
+
+    // Fraction of the plaintext answer found. An empty answer is all
+    // found.
+    pub fn recall(&self) -> f64 {
+        ratio(self.correct, self.expected)
+    }
+
+    // Fraction of the output that is in the plaintext answer.
+    pub fn precision(&self) -> f64 {
+        ratio(self.correct, self.found)
+    }
+
+    // Light prefixes whose counts the leader saw, over all levels.
+    pub fn revealed_prefixes(&self) -> usize {
+        self.levels.iter().map(|l| l.light).sum()
+    }
+
+    // Nodes dropped because their counts failed a check, over all levels.
+    // The output is incomplete if there are any.
+    pub fn suspect_nodes(&self) -> usize {
+        self.levels.iter().map(|l| l.suspect).sum()
+    }
+}
+
+fn ratio(n: usize, d: usize) -> f64 {
+    if d == 0 {
+        1.0
+    } else {
+        n as f64 / d as f64
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "level frontier heavy light suspect")?;
+        for l in &self.levels {
+            writeln!(f, "{:5} {:8} {:5} {:5} {:7}", l.level, l.frontier, l.heavy, l.light, l.suspect)?;
+        }
+        writeln!(
+            f,
+            "clients: {} submitted, {} accepted, {} rejected",
+            self.clients.submitted, self.clients.accepted, self.clients.rejected
+        )?;
+        writeln!(
+            f,
+            "found {} of {} heavy hitters, {} correct: recall {:.3}, precision {:.3}",
+            self.found,
+            self.expected,
+            self.correct,
+            self.recall(),
+            self.precision()
+        )?;
+        writeln!(f, "heavy strings cut to fit: {}", self.cut_heavy)?;
+        writeln!(f, "light prefixes revealed: {}", self.revealed_prefixes())?;
+        write!(f, "suspect nodes dropped: {}", self.suspect_nodes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastfield::FE;
+
+    #[test]
+    fn stats() {
+        let counts: Vec<FE> = [5u32, 0, 1, 7].iter().map(|&v| FE::from(v)).collect();
+        let suspects = Suspects {
+            over_count: vec![1],
+            frontier: 4,
+            ..Suspects::default()
+        };
+        let stats = level_stats(3, &counts, &[true, false, false, true], &suspects);
+        assert_eq!(
+            stats,
+            LevelStats {
+                level: 3,
+                frontier: 4,
+                heavy: 2,
+                light: 1,
+                suspect: 1
+            }
+        );
+    }
+
+    #[test]
+    fn accuracy() {
+        let bits = |s: &str| crate::input::encode(s, 16);
+        let pop = Population {
+            sites: vec![bits("ab"), bits("cd"), bits("ef")],
+            clients: vec![0, 0, 0, 1, 1, 2],
+            cut: Default::default(),
+        };
+        let levels = vec![LevelStats {
+            level: 0,
+            frontier: 2,
+            heavy: 1,
+            light: 1,
+            suspect: 0,
+        }];
+
+        let clients = Clients {
+            submitted: 7,
+            accepted: 6,
+            rejected: 1,
+        };
+        let expected = crate::sim::oracle(&pop.strings(), 2);
+        let report = Report::new(levels, clients, &[bits("ab"), bits("ef")], &pop, &expected, 2);
+        assert_eq!((report.found, report.expected, report.correct), (2, 2, 1));
+        assert_eq!(report.recall(), 0.5);
+        assert_eq!(report.precision(), 0.5);
+        assert_eq!(report.revealed_prefixes(), 1);
+        assert!(report.to_string().contains("recall 0.500, precision 0.500"));
+        assert!(report.to_string().contains("7 submitted, 6 accepted, 1 rejected"));
+    }
+}
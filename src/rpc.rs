@@ -1,77 +1,780 @@
-use crate::collect;
-use crate::FieldElm;
-use crate::fastfield::FE;
-use crate::mpc::{ManyCor, ManyCorShare, ManyOutShare};
-use crate::sketch::SketchDPFKey;
+use crate::collect::ClientId;
+use crate::config::{FieldPair, FieldType};
+use crate::envelope;
+use crate::prg;
 
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{stream, Stream};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tarpc::tokio_serde::formats::Bincode;
+use tarpc::tokio_util::codec::{length_delimited::LengthDelimitedCodec, Decoder, Encoder, Framed};
+use tarpc::{client, context, tokio_serde};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tracing::warn;
 
+/// Field types that can be used at either level of the tree and shipped
+/// over RPC.
+pub trait RpcField:
+    crate::Share
+    + std::fmt::Debug
+    + std::cmp::PartialOrd
+    + std::convert::From<u32>
+    + Send
+    + Sync
+    + Serialize
+    + DeserializeOwned
+    + 'static
+{
+}
+
+impl<T> RpcField for T where
+    T: crate::Share
+        + std::fmt::Debug
+        + std::cmp::PartialOrd
+        + std::convert::From<u32>
+        + Send
+        + Sync
+        + Serialize
+        + DeserializeOwned
+        + 'static
+{
+}
+
+/// Bincode-encoded message whose type depends on the field pair.
+///
+/// The leader picks the fields for each collection at reset time, so
+/// keys, field elements and MPC messages are carried as opaque bytes
+/// and decoded by whichever side knows the pair in use.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct ResetRequest {}
+pub struct Payload(Vec<u8>);
+
+impl Payload {
+    pub fn encode<S: Serialize>(val: &S) -> Payload {
+        Payload(bincode::serialize(val).expect("Could not encode payload"))
+    }
+
+    pub fn decode<D: DeserializeOwned>(&self) -> D {
+        self.try_decode().expect("Could not decode payload")
+    }
+
+    // Like decode(), for bytes that a client chose.
+    pub fn try_decode<D: DeserializeOwned>(&self) -> bincode::Result<D> {
+        bincode::deserialize(&self.0)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Payload {
+        Payload(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Names one call within a collection. A call that the leader retries
+/// carries the same tag, and a server that has already answered a call
+/// to that method with that tag sends back the same reply instead of
+/// running it again, so every tagged call is idempotent. The exception
+/// is `add_keys`, which anyone may call: it runs every time, and is
+/// idempotent because a server takes each token only once.
+///
+/// `level` is the tree level that the call is about, counting the last
+/// level as `data_len - 1`, and `batch` numbers calls to the same method
+/// at that level. Calls that happen once per collection use zeros.
+///
+/// A collection that takes in keys over several epochs is crawled more
+/// than once. `round` numbers those crawls, and each epoch's key upload
+/// carries the round of the first crawl that reads it. A collection that
+/// is crawled once uses round zero throughout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Tag {
+    pub collection: u64,
+    pub round: u64,
+    pub level: usize,
+    pub batch: u64,
+}
+
+impl Tag {
+    pub fn new(collection: u64, level: usize, batch: u64) -> Tag {
+        Tag { collection, round: 0, level, batch }
+    }
+
+    pub fn in_round(self, round: u64) -> Tag {
+        Tag { round, ..self }
+    }
+}
+
+/// Requests that carry a Tag.
+pub trait Tagged {
+    fn tag(&self) -> Tag;
+}
+
+macro_rules! tagged {
+    ($($req:ident),*) => {
+        $(impl Tagged for $req {
+            fn tag(&self) -> Tag {
+                self.tag
+            }
+        })*
+    };
+}
+
+tagged!(
+    AddKeysRequest,
+    KeyIdsRequest,
+    ReconcileRequest,
+    TreeInitRequest,
+    TreeCrawlRequest,
+    TreeCrawlLastRequest,
+    TreePruneRequest,
+    TreePruneLastRequest,
+    TreeRejectRequest,
+    TreeRecountRequest,
+    TreeRecountLastRequest,
+    TreeSketchFrontierRequest,
+    TreeSketchFrontierLastRequest,
+    TreeOutSharesRequest,
+    TreeOutSharesLastRequest,
+    FinalSharesRequest
+);
+
+/// Returned for a tagged call that the server refuses. Clients do not
+/// retry these.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum CallError {
+    /// The server's current collection is not the one in the tag, for
+    /// instance because the server restarted and lost it. Retrying the
+    /// call cannot help.
+    WrongCollection { requested: u64, current: u64 },
+    /// The call does not fit the collection's state: it names a level or
+    /// sketch batch out of order, its payload does not decode, or the
+    /// server is out of triples.
+    Refused(String),
+}
+
+impl std::fmt::Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CallError::WrongCollection { requested, current } => write!(
+                f,
+                "call is for collection {} but the server has collection {}",
+                requested, current
+            ),
+            CallError::Refused(why) => write!(f, "call refused: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+pub type Reply<T> = Result<T, CallError>;
+
+/// Starts a new collection. Resending the current collection's reset
+/// does nothing. Client tokens are only valid for the
+/// collection that they were issued for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResetRequest {
+    pub fields: FieldPair,
+    pub collection: u64,
+}
 
+/// Each report holds one client's key share for this server, a
+/// `SketchDPFKey<T, U>` encrypted under the server's public key. Each
+/// client sends both servers the same token with its two key shares. The
+/// ID in the token pairs up the shares, so batches may be sent
+/// concurrently and arrive in any order.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AddKeysRequest {
-    pub keys: Vec<SketchDPFKey<FE,FieldElm>>,
+    pub tag: Tag,
+    pub reports: Vec<envelope::EncryptedReport>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct TreeInitRequest {}
+pub struct PublicKeyRequest {}
 
+/// Sent by the dealer in the offline phase. `triples` holds a
+/// `Vec<TripleShare<F>>` for the field `field`, numbered from `first`.
+/// A batch that the server already has is ignored.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct TreeCrawlRequest {}
+pub struct AddTriplesRequest {
+    pub field: FieldType,
+    pub first: u64,
+    pub triples: Payload,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TripleCountRequest {
+    pub field: FieldType,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TripleCount {
+    /// Triples received so far, which the dealer numbers new ones from.
+    pub dealt: u64,
+    /// Triples received and not yet used up.
+    pub available: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyIdsRequest {
+    pub tag: Tag,
+}
+
+/// `drop` lists the client IDs that only this server received. The keys
+/// left make up the collection's next epoch, numbered from zero, and the
+/// reply is how many there are.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReconcileRequest {
+    pub tag: Tag,
+    pub drop: Vec<ClientId>,
+}
+
+/// Starts a crawl over the keys of the epochs in `epochs`, or over every
+/// key if it is `None`. The crawl begins at the nodes named by
+/// `prefixes`, which must all have the same length, shorter than
+/// `data_len`; the first `tree_crawl` is then at that level.
+/// `TreeInitRequest::new` starts from the root.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TreeInitRequest {
+    pub tag: Tag,
+    pub epochs: Option<Range<usize>>,
+    pub prefixes: Vec<Vec<bool>>,
+}
+
+impl TreeInitRequest {
+    pub fn new(tag: Tag) -> TreeInitRequest {
+        TreeInitRequest { tag, epochs: None, prefixes: vec![vec![]] }
+    }
+}
+
+/// The keys that a crawl reads.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrawlKeys {
+    /// Keys in the crawl, which the leader sketches in batches.
+    pub keys: usize,
+    /// The indices of those that failed a sketch check in an earlier
+    /// crawl, in order.
+    pub dead: Vec<usize>,
+}
+
+impl CrawlKeys {
+    /// The keys that have not failed a sketch check.
+    pub fn live(&self) -> usize {
+        self.keys - self.dead.len()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TreeCrawlRequest {
+    pub tag: Tag,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct TreeCrawlLastRequest {}
+pub struct TreeCrawlLastRequest {
+    pub tag: Tag,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TreePruneRequest {
+    pub tag: Tag,
     pub keep: Vec<bool>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TreePruneLastRequest {
+    pub tag: Tag,
     pub keep: Vec<bool>,
 }
 
+/// Marks the keys at indices `keys` as dead, since they failed the sketch
+/// check at `tag.level`, so that later crawls do not count them. Returns
+/// the number of keys still live.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TreeRejectRequest {
+    pub tag: Tag,
+    pub keys: Vec<usize>,
+}
+
+/// Counts the nodes that the crawl of `tag.level` reached again, over
+/// the keys still live, and returns the same shares as `tree_crawl`. The
+/// leader asks for this once it has rejected keys that failed the level's
+/// sketch check. The level is then sketched again, with fresh triples, so
+/// the leader can check the new counts too.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TreeRecountRequest {
+    pub tag: Tag,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TreeRecountLastRequest {
+    pub tag: Tag,
+}
+
+/// Sketches keys `start..end` at the frontier that the last crawl or
+/// recount of `tag.level` left, with the randomness from `seed`. The
+/// leader draws the seed once the servers have sent their counts, and
+/// every batch of a level must carry the same one. Each batch has an ID,
+/// `tag.batch`, which its `tree_out_shares` request repeats, so the
+/// leader may keep several batches in flight.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TreeSketchFrontierRequest {
-    pub level: usize,
+    pub tag: Tag,
     pub start: usize,
     pub end: usize,
+    pub seed: prg::PrgSeed,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TreeSketchFrontierLastRequest {
+    pub tag: Tag,
     pub start: usize,
     pub end: usize,
+    pub seed: prg::PrgSeed,
 }
 
+/// `cor` holds a `ManyCor<T>`.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TreeOutSharesRequest {
-    pub cor: ManyCor<FE>,
+    pub tag: Tag,
+    pub cor: Payload,
 }
 
+/// `cor` holds a `ManyCor<U>`.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TreeOutSharesLastRequest {
-    pub cor: ManyCor<FieldElm>,
+    pub tag: Tag,
+    pub cor: Payload,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct FinalSharesRequest {}
+pub struct FinalSharesRequest {
+    pub tag: Tag,
+}
 
+// For a collection over fields (T, U), the payloads returned below hold:
+//
+//   tree_crawl                 collect::CountShares<T>
+//   tree_crawl_last            collect::CountShares<U>
+//   tree_recount               collect::CountShares<T>
+//   tree_recount_last          collect::CountShares<U>
+//   tree_sketch_frontier       ManyCorShare<T>
+//   tree_sketch_frontier_last  ManyCorShare<U>
+//   tree_out_shares            ManyOutShare<T>
+//   tree_out_shares_last       ManyOutShare<U>
+//   final_shares               Vec<collect::Result<U>>
+//
+// Calls whose requests carry a Tag answer with a Reply, which is an error
+// if the tag names a collection other than the server's current one, or
+// if the call does not fit that collection's state.
 #[tarpc::service]
 pub trait Collector {
+    async fn public_key(req: PublicKeyRequest) -> envelope::PublicKey;
     async fn reset(rst: ResetRequest) -> String;
-    async fn add_keys(add: AddKeysRequest) -> String;
-    async fn tree_init(req: TreeInitRequest) -> String;
-    async fn tree_crawl(req: TreeCrawlRequest) -> Vec<FE>;
-    async fn tree_crawl_last(req: TreeCrawlLastRequest) -> Vec<FieldElm>;
-    async fn tree_prune(req: TreePruneRequest) -> String;
-    async fn tree_prune_last(req: TreePruneLastRequest) -> String;
-    async fn tree_sketch_frontier(req: TreeSketchFrontierRequest) -> ManyCorShare<FE>;
-    async fn tree_sketch_frontier_last(req: TreeSketchFrontierLastRequest) -> ManyCorShare<FieldElm>;
-    async fn tree_out_shares(req: TreeOutSharesRequest) -> ManyOutShare<FE>;
-    async fn tree_out_shares_last(req: TreeOutSharesLastRequest) -> ManyOutShare<FieldElm>;
-    async fn final_shares(req: FinalSharesRequest) -> Vec<collect::Result<FieldElm>>;
+    async fn add_triples(req: AddTriplesRequest) -> String;
+    async fn triple_count(req: TripleCountRequest) -> TripleCount;
+    async fn add_keys(add: AddKeysRequest) -> Reply<String>;
+    async fn key_ids(req: KeyIdsRequest) -> Reply<Vec<ClientId>>;
+    async fn reconcile(req: ReconcileRequest) -> Reply<usize>;
+    async fn tree_init(req: TreeInitRequest) -> Reply<CrawlKeys>;
+    async fn tree_crawl(req: TreeCrawlRequest) -> Reply<Payload>;
+    async fn tree_crawl_last(req: TreeCrawlLastRequest) -> Reply<Payload>;
+    async fn tree_prune(req: TreePruneRequest) -> Reply<String>;
+    async fn tree_prune_last(req: TreePruneLastRequest) -> Reply<String>;
+    async fn tree_reject(req: TreeRejectRequest) -> Reply<usize>;
+    async fn tree_recount(req: TreeRecountRequest) -> Reply<Payload>;
+    async fn tree_recount_last(req: TreeRecountLastRequest) -> Reply<Payload>;
+    async fn tree_sketch_frontier(req: TreeSketchFrontierRequest) -> Reply<Payload>;
+    async fn tree_sketch_frontier_last(req: TreeSketchFrontierLastRequest) -> Reply<Payload>;
+    async fn tree_out_shares(req: TreeOutSharesRequest) -> Reply<Payload>;
+    async fn tree_out_shares_last(req: TreeOutSharesLastRequest) -> Reply<Payload>;
+    async fn final_shares(req: FinalSharesRequest) -> Reply<Payload>;
+}
+
+// Chunked framing for RPC messages.
+//
+// The length-delimited framing that tarpc uses by default caps each
+// message at 8 MiB, which the crawl values at a wide frontier or a big
+// batch of keys can easily exceed. Instead, each message goes out as a
+// run of frames of at most CHUNK_LEN bytes each. The first byte of each
+// frame says whether more frames of the same message follow.
+
+const CHUNK_LEN: usize = 1 << 20;
+
+/// Largest message that a peer reassembles unless configured otherwise.
+/// The largest messages are crawl outputs, at two field elements per
+/// frontier node, so this fits a frontier of four million nodes in the
+/// widest field.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 256 << 20;
+
+const LAST_CHUNK: u8 = 0;
+const MORE_CHUNKS: u8 = 1;
+
+pub struct ChunkedCodec {
+    frames: LengthDelimitedCodec,
+    partial: BytesMut,
+    // Largest message to reassemble. A longer one fails the connection
+    // before it is buffered in full.
+    max_message_len: usize,
+}
+
+impl ChunkedCodec {
+    pub fn new(max_message_len: usize) -> ChunkedCodec {
+        ChunkedCodec {
+            frames: LengthDelimitedCodec::builder()
+                .max_frame_length(CHUNK_LEN + 1)
+                .new_codec(),
+            partial: BytesMut::new(),
+            max_message_len,
+        }
+    }
+}
+
+impl Default for ChunkedCodec {
+    fn default() -> Self {
+        ChunkedCodec::new(DEFAULT_MAX_MESSAGE_LEN)
+    }
+}
+
+impl Encoder<Bytes> for ChunkedCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        let mut rest = msg;
+        loop {
+            let chunk = rest.split_to(std::cmp::min(CHUNK_LEN, rest.len()));
+            let flag = if rest.is_empty() { LAST_CHUNK } else { MORE_CHUNKS };
+
+            let mut frame = BytesMut::with_capacity(chunk.len() + 1);
+            frame.put_u8(flag);
+            frame.put(chunk);
+            self.frames.encode(frame.freeze(), dst)?;
+
+            if rest.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Decoder for ChunkedCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        while let Some(mut frame) = self.frames.decode(src)? {
+            if frame.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty RPC frame"));
+            }
+            let flag = frame.get_u8();
+
+            if flag == LAST_CHUNK && self.partial.is_empty() {
+                return Ok(Some(frame));
+            }
+            if self.partial.len() + frame.len() > self.max_message_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "RPC message too long"));
+            }
+            self.partial.extend_from_slice(&frame);
+            if flag == LAST_CHUNK {
+                return Ok(Some(self.partial.split()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A tarpc transport that sends and receives bincode-encoded messages
+/// of any size, in chunks.
+pub type ChunkedTransport<S, Item, SinkItem> =
+    tokio_serde::Framed<Framed<S, ChunkedCodec>, Item, SinkItem, Bincode<Item, SinkItem>>;
+
+pub fn chunked<S, Item, SinkItem>(io: S, max_message_len: usize) -> ChunkedTransport<S, Item, SinkItem>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: DeserializeOwned,
+    SinkItem: Serialize,
+{
+    tokio_serde::Framed::new(Framed::new(io, ChunkedCodec::new(max_message_len)), Bincode::default())
+}
+
+pub async fn connect(addr: SocketAddr, max_message_len: usize) -> io::Result<CollectorClient> {
+    let io = TcpStream::connect(addr).await?;
+    Ok(CollectorClient::new(client::Config::default(), chunked(io, max_message_len)).spawn())
+}
+
+/// Accepts connections on `addr`, each wrapped in a chunked transport
+/// that takes messages of up to `max_message_len` bytes.
+pub async fn listen<Item, SinkItem>(
+    addr: &SocketAddr,
+    max_message_len: usize,
+) -> io::Result<impl Stream<Item = io::Result<ChunkedTransport<TcpStream, Item, SinkItem>>>>
+where
+    Item: DeserializeOwned,
+    SinkItem: Serialize,
+{
+    let listener = TcpListener::bind(addr).await?;
+    Ok(stream::unfold(listener, move |listener| async move {
+        let conn = listener.accept().await.map(|(io, _)| chunked(io, max_message_len));
+        Some((conn, listener))
+    }))
+}
+
+/// How long each call may take and how many times to try it.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub attempts: u32,
+}
+
+// Wait between attempts, doubling from FIRST_BACKOFF up to MAX_BACKOFF.
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+async fn backoff(delay: &mut Duration) {
+    tokio::time::sleep(*delay).await;
+    *delay = std::cmp::min(2 * *delay, MAX_BACKOFF);
+}
+
+/// Connection to one server, as the leader and dealer use it. Each call
+/// times out after `policy.timeout`, and a call that fails is retried
+/// with backoff over a fresh connection, up to `policy.attempts` times
+/// in all. Retrying is safe because every call is idempotent: tagged
+/// calls by their tags, and the rest by what they do.
+#[derive(Clone)]
+pub struct Client {
+    addr: SocketAddr,
+    policy: RetryPolicy,
+    max_message_len: usize,
+    // The current connection, numbered so that concurrent calls that
+    // fail together only reconnect once.
+    conn: Arc<Mutex<(u64, CollectorClient)>>,
+}
+
+impl Client {
+    /// Connects to the server at `addr`, retrying as for any other call.
+    /// Replies longer than `max_message_len` bytes fail the call.
+    pub async fn connect(addr: SocketAddr, policy: RetryPolicy, max_message_len: usize) -> io::Result<Client> {
+        let mut delay = FIRST_BACKOFF;
+        let mut attempt = 1;
+        let client = loop {
+            match connect(addr, max_message_len).await {
+                Ok(client) => break client,
+                Err(e) if attempt >= policy.attempts => return Err(e),
+                Err(e) => warn!(%addr, error = %e, "could not connect; retrying"),
+            }
+            backoff(&mut delay).await;
+            attempt += 1;
+        };
+
+        Ok(Client {
+            addr,
+            policy,
+            max_message_len,
+            conn: Arc::new(Mutex::new((0, client))),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Replaces connection number `gen`, unless another call already has.
+    async fn reconnect(&self, gen: u64) {
+        let mut conn = self.conn.lock().await;
+        if conn.0 != gen {
+            return;
+        }
+        match connect(self.addr, self.max_message_len).await {
+            Ok(client) => *conn = (gen + 1, client),
+            Err(e) => warn!(addr = %self.addr, error = %e, "could not reconnect"),
+        }
+    }
+
+    async fn call<R, F, Fut>(&self, method: &str, f: F) -> io::Result<R>
+    where
+        F: Fn(CollectorClient, context::Context) -> Fut,
+        Fut: Future<Output = Result<R, client::RpcError>>,
+    {
+        let mut delay = FIRST_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let (gen, client) = self.conn.lock().await.clone();
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + self.policy.timeout;
+
+            let err = match f(client, ctx).await {
+                Ok(resp) => return Ok(resp),
+                Err(e) => e,
+            };
+            if attempt >= self.policy.attempts {
+                return Err(io::Error::other(format!(
+                    "{} to {} failed after {} attempts: {}",
+                    method, self.addr, attempt, err
+                )));
+            }
+            warn!(method, addr = %self.addr, error = %err, ?delay, "call failed; retrying");
+
+            backoff(&mut delay).await;
+            self.reconnect(gen).await;
+            attempt += 1;
+        }
+    }
+}
+
+// Defines a retrying Client method for each Collector method. Tagged
+// calls fail without a retry if the server refuses them.
+macro_rules! client_api {
+    (
+        untagged { $($method:ident($req:ty) -> $resp:ty;)* }
+        tagged { $($tmethod:ident($treq:ty) -> $tresp:ty;)* }
+    ) => {
+        impl Client {
+            $(pub async fn $method(&self, req: $req) -> io::Result<$resp> {
+                self.call(stringify!($method), move |c, ctx| {
+                    let req = req.clone();
+                    async move { c.$method(ctx, req).await }
+                })
+                .await
+            })*
+
+            $(pub async fn $tmethod(&self, req: $treq) -> io::Result<$tresp> {
+                self.call(stringify!($tmethod), move |c, ctx| {
+                    let req = req.clone();
+                    async move { c.$tmethod(ctx, req).await }
+                })
+                .await?
+                .map_err(io::Error::other)
+            })*
+        }
+    };
+}
+
+client_api! {
+    untagged {
+        public_key(PublicKeyRequest) -> envelope::PublicKey;
+        reset(ResetRequest) -> String;
+        add_triples(AddTriplesRequest) -> String;
+        triple_count(TripleCountRequest) -> TripleCount;
+    }
+    tagged {
+        add_keys(AddKeysRequest) -> String;
+        key_ids(KeyIdsRequest) -> Vec<ClientId>;
+        reconcile(ReconcileRequest) -> usize;
+        tree_init(TreeInitRequest) -> CrawlKeys;
+        tree_crawl(TreeCrawlRequest) -> Payload;
+        tree_crawl_last(TreeCrawlLastRequest) -> Payload;
+        tree_prune(TreePruneRequest) -> String;
+        tree_prune_last(TreePruneLastRequest) -> String;
+        tree_reject(TreeRejectRequest) -> usize;
+        tree_recount(TreeRecountRequest) -> Payload;
+        tree_recount_last(TreeRecountLastRequest) -> Payload;
+        tree_sketch_frontier(TreeSketchFrontierRequest) -> Payload;
+        tree_sketch_frontier_last(TreeSketchFrontierLastRequest) -> Payload;
+        tree_out_shares(TreeOutSharesRequest) -> Payload;
+        tree_out_shares_last(TreeOutSharesLastRequest) -> Payload;
+        final_shares(FinalSharesRequest) -> Payload;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fastfield::FE;
+    use crate::FieldElm255;
+
+    #[test]
+    fn payload_roundtrip() {
+        let vals = vec![FE::from(3u32), FE::from(7u32)];
+        let out: Vec<FE> = Payload::encode(&vals).decode();
+        assert_eq!(vals, out);
+
+        let vals = vec![FieldElm255::from(11), FieldElm255::from(13)];
+        let out: Vec<FieldElm255> = Payload::encode(&vals).decode();
+        assert_eq!(vals, out);
+    }
+
+    // Splits msgs into frames and feeds them back through a decoder a few
+    // bytes at a time.
+    fn chunk_roundtrip(msgs: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let mut wire = BytesMut::new();
+        let mut enc = ChunkedCodec::default();
+        for m in msgs {
+            enc.encode(Bytes::from(m.clone()), &mut wire).unwrap();
+        }
+
+        let mut dec = ChunkedCodec::default();
+        let mut src = BytesMut::new();
+        let mut out = vec![];
+        while !wire.is_empty() {
+            let n = std::cmp::min(wire.len(), 100_003);
+            src.extend_from_slice(&wire.split_to(n));
+            while let Some(m) = dec.decode(&mut src).unwrap() {
+                out.push(m.to_vec());
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn chunks_reassemble() {
+        let big: Vec<u8> = (0..(3 * CHUNK_LEN + 17)).map(|i| (i % 251) as u8).collect();
+        let exact = vec![7u8; CHUNK_LEN];
+        let msgs = vec![big, vec![], vec![1, 2, 3], exact];
+        assert_eq!(chunk_roundtrip(&msgs), msgs);
+    }
+
+    #[test]
+    fn oversized_frame_rejected() {
+        let mut wire = BytesMut::new();
+        LengthDelimitedCodec::new()
+            .encode(Bytes::from(vec![0u8; CHUNK_LEN + 2]), &mut wire)
+            .unwrap();
+        assert!(ChunkedCodec::default().decode(&mut wire).is_err());
+    }
+
+    #[test]
+    fn oversized_message_rejected() {
+        let mut wire = BytesMut::new();
+        ChunkedCodec::default()
+            .encode(Bytes::from(vec![0u8; 3 * CHUNK_LEN]), &mut wire)
+            .unwrap();
+        let err = ChunkedCodec::new(2 * CHUNK_LEN).decode(&mut wire).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    // A message well over tarpc's default 8 MiB frame limit gets through.
+    #[tokio::test]
+    async fn large_message() {
+        use futures::{SinkExt, StreamExt};
+
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let mut tx = chunked::<_, (), Vec<FE>>(a, DEFAULT_MAX_MESSAGE_LEN);
+        let mut rx = chunked::<_, Vec<FE>, ()>(b, DEFAULT_MAX_MESSAGE_LEN);
+
+        let vals: Vec<FE> = (0..(2 << 20)).map(|i| FE::from(i as u32)).collect();
+        let sent = vals.clone();
+        let send = tokio::spawn(async move { tx.send(sent).await.unwrap() });
+
+        let got = rx.next().await.unwrap().unwrap();
+        send.await.unwrap();
+        assert_eq!(got, vals);
+    }
 }
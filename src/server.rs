@@ -0,0 +1,857 @@
+// Server side of the Collector service. Both the tarpc and the HTTP
+// front ends drive the same CollectorServer.
+
+use crate::{
+    auth, collect, envelope, metrics,
+    config::{FieldPair, FieldType},
+    FieldElm127,
+    FieldElm255,
+    fastfield::FE,
+    mpc, prg,
+    rpc::Collector,
+    rpc::{
+        AddKeysRequest, AddTriplesRequest, CrawlKeys, FinalSharesRequest, KeyIdsRequest, Payload,
+        PublicKeyRequest, ReconcileRequest, Reply, ResetRequest, RpcField, Tag, TripleCount,
+        TripleCountRequest, CallError,
+        TreeCrawlRequest, TreeCrawlLastRequest, TreeInitRequest,
+        TreeOutSharesRequest,
+        TreeOutSharesLastRequest,
+        TreePruneRequest,
+        TreePruneLastRequest,
+        TreeRejectRequest,
+        TreeRecountRequest,
+        TreeRecountLastRequest,
+        TreeSketchFrontierRequest,
+        TreeSketchFrontierLastRequest,
+        Tagged,
+    },
+    sketch,
+};
+
+use futures::future::{self, Ready};
+use serde::Serialize;
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, OnceLock},
+};
+use tarpc::context;
+use tracing::{debug_span, info, warn};
+
+// Beaver triples from the dealer, one pool per field. These outlive any
+// one collection.
+#[derive(Default)]
+struct TriplePools {
+    fe62: mpc::TriplePool<FE>,
+    m127: mpc::TriplePool<FieldElm127>,
+    p255: mpc::TriplePool<FieldElm255>,
+}
+
+trait PoolFor<F> {
+    fn pool(&mut self) -> &mut mpc::TriplePool<F>;
+}
+
+impl PoolFor<FE> for TriplePools {
+    fn pool(&mut self) -> &mut mpc::TriplePool<FE> {
+        &mut self.fe62
+    }
+}
+
+impl PoolFor<FieldElm127> for TriplePools {
+    fn pool(&mut self) -> &mut mpc::TriplePool<FieldElm127> {
+        &mut self.m127
+    }
+}
+
+impl PoolFor<FieldElm255> for TriplePools {
+    fn pool(&mut self) -> &mut mpc::TriplePool<FieldElm255> {
+        &mut self.p255
+    }
+}
+
+macro_rules! dispatch_pool {
+    ($pools:expr, $field:expr, $p:ident => $body:expr) => {
+        match $field {
+            FieldType::FE62 => {
+                let $p = &mut $pools.fe62;
+                $body
+            }
+            FieldType::M127 => {
+                let $p = &mut $pools.m127;
+                $body
+            }
+            FieldType::P255 => {
+                let $p = &mut $pools.p255;
+                $body
+            }
+        }
+    };
+}
+
+// Server state for a collection over inner field T and last field U.
+//
+// The key collection and the sketching state have separate locks, so
+// that batches of one level can be sketched while the next level is
+// being crawled.
+struct Collection<T, U> {
+    coll: Mutex<collect::KeyCollection<T, U>>,
+    verifier: auth::Verifier,
+    sketches: Mutex<Sketches<T, U>>,
+}
+
+// What the server needs to sketch one level, set aside at crawl time.
+// The triples for the whole level are reserved then too: both servers
+// crawl in the same order, but sketch batches may arrive in any order.
+// The seed comes with the first batch, and every other batch must carry
+// the same one.
+struct SketchLevel<V> {
+    frontier: collect::FrontierSnapshot<V>,
+    mac_keys: Vec<V>,
+    mac_keys2: Vec<V>,
+    triples: Vec<mpc::TripleShare<V>>,
+    seed: Option<prg::PrgSeed>,
+    unsketched: usize,
+}
+
+// Levels crawled but not yet fully sketched, and the multiplications in
+// flight for each sketch batch, by batch ID.
+struct Sketches<T, U> {
+    crawls: usize,
+    levels: BTreeMap<usize, SketchLevel<T>>,
+    level_last: Option<SketchLevel<U>>,
+    muls: BTreeMap<u64, mpc::ManyMulState<T>>,
+    muls_last: BTreeMap<u64, mpc::ManyMulState<U>>,
+}
+
+impl<T, U> Sketches<T, U> {
+    // For a crawl whose first level is `level`.
+    fn new(level: usize) -> Self {
+        Sketches {
+            crawls: level,
+            levels: BTreeMap::new(),
+            level_last: None,
+            muls: BTreeMap::new(),
+            muls_last: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V> SketchLevel<V>
+where
+    V: RpcField,
+{
+    // For the level whose frontier is in `frontier`, over keys with the
+    // given MAC key shares.
+    fn new(
+        frontier: collect::FrontierSnapshot<V>,
+        (mac_keys, mac_keys2): (Vec<V>, Vec<V>),
+        triples: Vec<mpc::TripleShare<V>>,
+    ) -> Self {
+        SketchLevel {
+            unsketched: frontier.len(),
+            frontier,
+            mac_keys,
+            mac_keys2,
+            triples,
+            seed: None,
+        }
+    }
+
+    // Hands out what batch start..end needs, and forgets the parts of
+    // the level that no other batch does.
+    #[allow(clippy::type_complexity)]
+    fn take_batch(
+        &mut self,
+        start: usize,
+        end: usize,
+        seed: &prg::PrgSeed,
+    ) -> Reply<(collect::FrontierSnapshot<V>, Vec<V>, Vec<V>, Vec<mpc::TripleShare<V>>)> {
+        let n = sketch::TRIPLES_PER_LEVEL;
+        if start >= end || n * end > self.triples.len() || end - start > self.unsketched {
+            return Err(refused(format!("Batch {}..{} is out of range or sketched twice", start, end)));
+        }
+        if self.seed.as_ref().is_some_and(|s| s.key != seed.key) {
+            return Err(refused(format!("Batch {}..{} has another seed than the level's", start, end)));
+        }
+        self.seed = Some(seed.clone());
+        self.unsketched -= end - start;
+
+        Ok((
+            self.frontier.clone(),
+            self.mac_keys[start..end].to_vec(),
+            self.mac_keys2[start..end].to_vec(),
+            self.triples[n * start..n * end].to_vec(),
+        ))
+    }
+}
+
+fn refused(why: String) -> CallError {
+    CallError::Refused(why)
+}
+
+// Takes the triples to sketch a level of n keys with, along with the
+// number of the first, or refuses the crawl if the dealer has not dealt
+// enough. Nothing is taken then, so the crawl can be retried once the
+// dealer has dealt more.
+fn take_triples<V>(pools: &Mutex<TriplePools>, n: usize) -> Reply<(u64, Vec<mpc::TripleShare<V>>)>
+where
+    V: RpcField,
+    TriplePools: PoolFor<V>,
+{
+    let mut pools = pools.lock().unwrap();
+    let pool = PoolFor::<V>::pool(&mut *pools);
+    let need = sketch::TRIPLES_PER_LEVEL * n;
+    if pool.available() < need {
+        return Err(refused(format!("Out of Beaver triples: need {}, have {}", need, pool.available())));
+    }
+    Ok((pool.taken(), pool.take(need)))
+}
+
+impl<T, U> Collection<T, U>
+where
+    T: RpcField,
+    U: RpcField,
+    TriplePools: PoolFor<T> + PoolFor<U>,
+{
+    fn new(data_len: usize, verifier: auth::Verifier) -> Self {
+        Collection {
+            coll: Mutex::new(collect::KeyCollection::new(data_len)),
+            verifier,
+            sketches: Mutex::new(Sketches::new(0)),
+        }
+    }
+
+    fn add_keys(&self, batches: Vec<Vec<(auth::Token, Payload)>>, metrics: &metrics::Metrics) {
+        // Keys are bytes that the clients chose, so each token is checked
+        // before its key is decoded, and neither happens under the lock.
+        let reports: Vec<_> = batches
+            .into_iter()
+            .flatten()
+            .map(|(token, k)| {
+                if !self.verifier.verify(&token) {
+                    return Err(collect::Ingest::BadToken);
+                }
+                let k = k.try_decode::<sketch::SketchDPFKey<T, U>>().map_err(|_| collect::Ingest::Malformed)?;
+                Ok((token.id, k))
+            })
+            .collect();
+
+        let mut coll = self.coll.lock().unwrap();
+        let (mut accepted, mut bad, mut replays, mut malformed) = (0, 0, 0, 0);
+        for report in reports {
+            let ingest = match report {
+                Ok((id, k)) => coll.add_verified(id, k),
+                Err(ingest) => ingest,
+            };
+            match ingest {
+                collect::Ingest::Accepted => accepted += 1,
+                collect::Ingest::BadToken => bad += 1,
+                collect::Ingest::Replay => replays += 1,
+                collect::Ingest::Malformed => malformed += 1,
+            }
+        }
+        if bad + replays + malformed > 0 {
+            warn!(bad_token = bad, replay = replays, malformed, "rejected keys");
+        }
+        metrics.keys_ingested.inc_by(accepted);
+        metrics.reject("bad_token", bad);
+        metrics.reject("replay", replays);
+        metrics.reject("malformed", malformed);
+    }
+
+    fn tree_init(&self, req: &TreeInitRequest) -> CrawlKeys {
+        let mut coll = self.coll.lock().unwrap();
+        coll.tree_init_at(req.epochs.clone(), &req.prefixes);
+        let level = req.prefixes.first().map_or(0, |p| p.len());
+        *self.sketches.lock().unwrap() = Sketches::new(level);
+        CrawlKeys {
+            keys: coll.crawl_len(),
+            dead: coll.live_keys().iter().enumerate().filter(|(_, l)| !**l).map(|(i, _)| i).collect(),
+        }
+    }
+
+    // Marks the keys at the given indices dead. Returns the number of
+    // live keys before and after.
+    fn reject(&self, keys: &[usize]) -> (usize, usize) {
+        let mut coll = self.coll.lock().unwrap();
+        let before = coll.live_clients();
+        let mut alive = vec![true; coll.crawl_len()];
+        for &i in keys {
+            if let Some(a) = alive.get_mut(i) {
+                *a = false;
+            }
+        }
+        coll.apply_sketch_results(&alive);
+        (before, coll.live_clients())
+    }
+
+    // Counts the level last crawled again, without the keys rejected
+    // since, and sets aside fresh triples to sketch it again with. The
+    // leader checks the new counts against the new sketches.
+    fn tree_recount(&self, level: usize, pools: &Mutex<TriplePools>) -> Reply<Payload> {
+        let mut coll = self.coll.lock().unwrap();
+        let (first, triples) = take_triples::<T>(pools, coll.crawl_len())?;
+        let shares = collect::CountShares::new(coll.tree_recount(), first, &triples, &coll.live_keys());
+
+        let sketch = SketchLevel::new(coll.frontier_snapshot(), coll.mac_keys(), triples);
+        self.sketches.lock().unwrap().levels.insert(level, sketch);
+
+        Ok(Payload::encode(&shares))
+    }
+
+    fn tree_recount_last(&self, pools: &Mutex<TriplePools>) -> Reply<Payload> {
+        let mut coll = self.coll.lock().unwrap();
+        let (first, triples) = take_triples::<U>(pools, coll.crawl_len())?;
+        let shares = collect::CountShares::new(coll.tree_recount_last(), first, &triples, &coll.live_keys());
+
+        let sketch = SketchLevel::new(coll.frontier_snapshot_last(), coll.mac_keys_last(), triples);
+        self.sketches.lock().unwrap().level_last = Some(sketch);
+
+        Ok(Payload::encode(&shares))
+    }
+
+    // Refuses a crawl of any level but the next one.
+    fn check_crawl(&self, crawl: usize) -> Reply<()> {
+        let crawls = self.sketches.lock().unwrap().crawls;
+        if crawls != crawl {
+            return Err(refused(format!("Crawled level {} out of order, expected level {}", crawl, crawls)));
+        }
+        Ok(())
+    }
+
+    fn tree_crawl(&self, crawl: usize, pools: &Mutex<TriplePools>, metrics: &metrics::Metrics) -> Reply<Payload> {
+        let mut coll = self.coll.lock().unwrap();
+        self.check_crawl(crawl)?;
+        let (first, triples) = take_triples::<T>(pools, coll.crawl_len())?;
+        let shares = collect::CountShares::new(coll.tree_crawl(), first, &triples, &coll.live_keys());
+        metrics.set_frontier_size(crawl, shares.counts.len());
+
+        let level = SketchLevel::new(coll.frontier_snapshot(), coll.mac_keys(), triples);
+        let mut sketches = self.sketches.lock().unwrap();
+        sketches.levels.insert(crawl, level);
+        sketches.crawls += 1;
+
+        Ok(Payload::encode(&shares))
+    }
+
+    fn tree_crawl_last(&self, level: usize, pools: &Mutex<TriplePools>, metrics: &metrics::Metrics) -> Reply<Payload> {
+        let mut coll = self.coll.lock().unwrap();
+        self.check_crawl(level)?;
+        let (first, triples) = take_triples::<U>(pools, coll.crawl_len())?;
+        let shares = collect::CountShares::new(coll.tree_crawl_last(), first, &triples, &coll.live_keys());
+        metrics.set_frontier_size(level, shares.counts.len());
+
+        let level = SketchLevel::new(coll.frontier_snapshot_last(), coll.mac_keys_last(), triples);
+        self.sketches.lock().unwrap().level_last = Some(level);
+
+        Ok(Payload::encode(&shares))
+    }
+
+    fn tree_sketch_frontier(&self, server_idx: u16, req: &TreeSketchFrontierRequest) -> Reply<Payload> {
+        let (frontier, mac, macp, triples) = {
+            let mut sketches = self.sketches.lock().unwrap();
+            let level = sketches
+                .levels
+                .get_mut(&req.tag.level)
+                .ok_or_else(|| refused(format!("Level {} not crawled, or already sketched", req.tag.level)))?;
+            let batch = level.take_batch(req.start, req.end, &req.seed)?;
+            if level.unsketched == 0 {
+                sketches.levels.remove(&req.tag.level);
+            }
+            batch
+        };
+
+        let sketch = frontier.sketch(req.start, req.end, &req.seed);
+        let state = mpc::ManyMulState::new(server_idx > 0, &triples, &mac, &macp, &sketch);
+        let cor_shares = state.cor_shares();
+        self.sketches.lock().unwrap().muls.insert(req.tag.batch, state);
+
+        Ok(Payload::encode(&cor_shares))
+    }
+
+    fn tree_sketch_frontier_last(
+        &self,
+        server_idx: u16,
+        req: &TreeSketchFrontierLastRequest,
+    ) -> Reply<Payload> {
+        let (frontier, mac, macp, triples) = {
+            let mut sketches = self.sketches.lock().unwrap();
+            let level = sketches
+                .level_last
+                .as_mut()
+                .ok_or_else(|| refused("Last level not crawled, or already sketched".to_string()))?;
+            let batch = level.take_batch(req.start, req.end, &req.seed)?;
+            if level.unsketched == 0 {
+                sketches.level_last = None;
+            }
+            batch
+        };
+
+        let sketch = frontier.sketch(req.start, req.end, &req.seed);
+        let state = mpc::ManyMulState::new(server_idx > 0, &triples, &mac, &macp, &sketch);
+        let cor_shares = state.cor_shares();
+        self.sketches.lock().unwrap().muls_last.insert(req.tag.batch, state);
+
+        Ok(Payload::encode(&cor_shares))
+    }
+
+    fn tree_out_shares(&self, req: &TreeOutSharesRequest) -> Reply<Payload> {
+        let cor = decode_cor(req.tag.batch, &req.cor)?;
+        let state = take_muls(&mut self.sketches.lock().unwrap().muls, req.tag.batch, &cor)?;
+        Ok(Payload::encode(&state.out_shares(&cor)))
+    }
+
+    fn tree_out_shares_last(&self, req: &TreeOutSharesLastRequest) -> Reply<Payload> {
+        let cor = decode_cor(req.tag.batch, &req.cor)?;
+        let state = take_muls(&mut self.sketches.lock().unwrap().muls_last, req.tag.batch, &cor)?;
+        Ok(Payload::encode(&state.out_shares(&cor)))
+    }
+}
+
+fn decode_cor<V: RpcField>(batch: u64, cor: &Payload) -> Reply<mpc::ManyCor<V>> {
+    cor.try_decode()
+        .map_err(|e| refused(format!("Malformed corrections for sketch batch {}: {}", batch, e)))
+}
+
+// Takes the multiplications of sketch batch `batch` to finish with the
+// leader's corrections. They stay put if the corrections do not fit them,
+// so that a good call can still follow.
+fn take_muls<V: RpcField>(
+    muls: &mut BTreeMap<u64, mpc::ManyMulState<V>>,
+    batch: u64,
+    cor: &mpc::ManyCor<V>,
+) -> Reply<mpc::ManyMulState<V>> {
+    match muls.get(&batch) {
+        None => Err(refused(format!("Unknown sketch batch {}", batch))),
+        Some(state) if !state.fits(cor) => {
+            Err(refused(format!("Corrections do not fit sketch batch {}", batch)))
+        }
+        Some(_) => Ok(muls.remove(&batch).unwrap()),
+    }
+}
+
+// One variant per supported config::FieldPair.
+#[derive(Clone)]
+enum CollectionState {
+    FeFe(Arc<Collection<FE, FE>>),
+    FeM127(Arc<Collection<FE, FieldElm127>>),
+    FeP255(Arc<Collection<FE, FieldElm255>>),
+    M127M127(Arc<Collection<FieldElm127, FieldElm127>>),
+    P255P255(Arc<Collection<FieldElm255, FieldElm255>>),
+}
+
+macro_rules! dispatch {
+    ($state:expr, $c:ident => $body:expr) => {
+        match $state {
+            CollectionState::FeFe($c) => $body,
+            CollectionState::FeM127($c) => $body,
+            CollectionState::FeP255($c) => $body,
+            CollectionState::M127M127($c) => $body,
+            CollectionState::P255P255($c) => $body,
+        }
+    };
+}
+
+impl CollectionState {
+    fn fields(&self) -> FieldPair {
+        let (inner, last) = match self {
+            CollectionState::FeFe(_) => (FieldType::FE62, FieldType::FE62),
+            CollectionState::FeM127(_) => (FieldType::FE62, FieldType::M127),
+            CollectionState::FeP255(_) => (FieldType::FE62, FieldType::P255),
+            CollectionState::M127M127(_) => (FieldType::M127, FieldType::M127),
+            CollectionState::P255P255(_) => (FieldType::P255, FieldType::P255),
+        };
+        FieldPair { inner, last }
+    }
+
+    fn new(fields: FieldPair, data_len: usize, collection: u64) -> Self {
+        let verifier = auth::Verifier::new(&auth::STAND_IN_KEY, collection);
+        match (fields.inner, fields.last) {
+            (FieldType::FE62, FieldType::FE62) => {
+                CollectionState::FeFe(Arc::new(Collection::new(data_len, verifier)))
+            }
+            (FieldType::FE62, FieldType::M127) => {
+                CollectionState::FeM127(Arc::new(Collection::new(data_len, verifier)))
+            }
+            (FieldType::FE62, FieldType::P255) => {
+                CollectionState::FeP255(Arc::new(Collection::new(data_len, verifier)))
+            }
+            (FieldType::M127, FieldType::M127) => {
+                CollectionState::M127M127(Arc::new(Collection::new(data_len, verifier)))
+            }
+            (FieldType::P255, FieldType::P255) => {
+                CollectionState::P255P255(Arc::new(Collection::new(data_len, verifier)))
+            }
+            _ => panic!("Unsupported field pair {:?}", fields),
+        }
+    }
+}
+
+type ReplyCell = Arc<OnceLock<Box<dyn Any + Send + Sync>>>;
+
+// The reply to every tagged call in a collection, by method and tag, so
+// that a retried call gets the first call's reply. A retry that arrives
+// while the first call is still running waits for it.
+#[derive(Default)]
+struct Replies {
+    calls: Mutex<HashMap<(&'static str, Tag), ReplyCell>>,
+}
+
+impl Replies {
+    fn once<R>(&self, method: &'static str, tag: Tag, f: impl FnOnce() -> R) -> R
+    where
+        R: Clone + Send + Sync + 'static,
+    {
+        let cell = self.calls.lock().unwrap().entry((method, tag)).or_default().clone();
+        let reply = cell.get_or_init(|| Box::new(f()));
+        reply.downcast_ref::<R>().expect("Reply of the wrong type").clone()
+    }
+
+    // Forgets the reply to one call, so that the next call with its tag
+    // runs again.
+    fn forget(&self, method: &'static str, tag: Tag) {
+        self.calls.lock().unwrap().remove(&(method, tag));
+    }
+
+    // Forgets the replies to calls about earlier rounds, and about levels
+    // before `level` in this one, which the leader is done with.
+    fn forget_before(&self, round: u64, level: usize) {
+        self.calls
+            .lock()
+            .unwrap()
+            .retain(|(_, tag), _| (tag.round, tag.level) >= (round, level));
+    }
+}
+
+struct ServerState {
+    collection: u64,
+    coll: CollectionState,
+    replies: Arc<Replies>,
+    // Decrypted key batches uploaded since the last key_ids.
+    incoming: collect::KeyReceiver<Vec<(auth::Token, Payload)>>,
+}
+
+#[derive(Clone)]
+pub struct CollectorServer {
+    data_len: usize,
+    server_idx: u16,
+    keypair: Arc<envelope::KeyPair>,
+    keys_in: collect::KeySender<Vec<(auth::Token, Payload)>>,
+    pools: Arc<Mutex<TriplePools>>,
+    metrics: Arc<metrics::Metrics>,
+    arc: Arc<Mutex<ServerState>>,
+}
+
+impl CollectorServer {
+    pub fn new(
+        server_idx: u16,
+        keypair: envelope::KeyPair,
+        data_len: usize,
+        fields: FieldPair,
+    ) -> Self {
+        // The leader picks the fields and collection again on every reset.
+        // Clients encrypt their key shares to the keypair, whose public key
+        // they pin.
+        let keypair = Arc::new(keypair);
+
+        let (keys_in, incoming) = collect::key_queue();
+        let state = ServerState {
+            collection: 0,
+            coll: CollectionState::new(fields, data_len, 0),
+            replies: Arc::new(Replies::default()),
+            incoming,
+        };
+
+        CollectorServer {
+            data_len,
+            server_idx,
+            keypair,
+            keys_in,
+            pools: Arc::new(Mutex::new(TriplePools::default())),
+            metrics: Arc::new(metrics::Metrics::new()),
+            arc: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<metrics::Metrics> {
+        self.metrics.clone()
+    }
+
+    // Runs f on the collection that the request's tag names, once for each
+    // method and tag. Holding on to the collection does not stop a reset.
+    // A refusal is not kept: f refuses before it changes anything, so the
+    // call may run again, for instance once the dealer has dealt more.
+    fn once<Req, R>(&self, method: &'static str, req: &Req, f: impl FnOnce(CollectionState) -> Reply<R>) -> Reply<R>
+    where
+        Req: Tagged + Serialize,
+        R: Clone + Send + Sync + Serialize + 'static,
+    {
+        let tag = req.tag();
+        let _span = debug_span!(
+            "rpc",
+            method,
+            collection = tag.collection,
+            round = tag.round,
+            level = tag.level,
+            batch = tag.batch
+        )
+        .entered();
+        self.metrics.observe_rpc(method, req, || {
+            let (coll, replies) = self.collection(tag)?;
+            let reply = replies.once(method, tag, || f(coll));
+            if reply.is_err() {
+                replies.forget(method, tag);
+            }
+            reply
+        })
+    }
+
+    // Returns the collection that the tag names, and its replies, if that
+    // is the current collection.
+    fn collection(&self, tag: Tag) -> Reply<(CollectionState, Arc<Replies>)> {
+        let state = self.arc.lock().unwrap();
+        if state.collection != tag.collection {
+            return Err(CallError::WrongCollection {
+                requested: tag.collection,
+                current: state.collection,
+            });
+        }
+        Ok((state.coll.clone(), state.replies.clone()))
+    }
+
+    // Forgets the replies from earlier rounds, and from levels before
+    // `level`. The leader checks each level's sketches before it crawls
+    // the next one, and finishes each round before it starts the next.
+    fn forget_replies(&self, round: u64, level: usize) {
+        let replies = self.arc.lock().unwrap().replies.clone();
+        replies.forget_before(round, level);
+    }
+}
+
+impl Collector for CollectorServer {
+    type PublicKeyFut = Ready<envelope::PublicKey>;
+    type AddKeysFut = Ready<Reply<String>>;
+    type KeyIdsFut = Ready<Reply<Vec<collect::ClientId>>>;
+    type ReconcileFut = Ready<Reply<usize>>;
+    type TreeInitFut = Ready<Reply<CrawlKeys>>;
+    type TreeCrawlFut = Ready<Reply<Payload>>;
+    type TreeCrawlLastFut = Ready<Reply<Payload>>;
+    type TreePruneFut = Ready<Reply<String>>;
+    type TreePruneLastFut = Ready<Reply<String>>;
+    type TreeRejectFut = Ready<Reply<usize>>;
+    type TreeRecountFut = Ready<Reply<Payload>>;
+    type TreeRecountLastFut = Ready<Reply<Payload>>;
+    type TreeSketchFrontierFut = Ready<Reply<Payload>>;
+    type TreeSketchFrontierLastFut = Ready<Reply<Payload>>;
+    type TreeOutSharesFut = Ready<Reply<Payload>>;
+    type TreeOutSharesLastFut = Ready<Reply<Payload>>;
+    type FinalSharesFut = Ready<Reply<Payload>>;
+    type ResetFut = Ready<String>;
+    type AddTriplesFut = Ready<String>;
+    type TripleCountFut = Ready<TripleCount>;
+
+    fn public_key(self, _: context::Context, req: PublicKeyRequest) -> Self::PublicKeyFut {
+        future::ready(self.metrics.observe_rpc("public_key", &req, || self.keypair.public_key()))
+    }
+
+    fn reset(self, _: context::Context, rst: ResetRequest) -> Self::ResetFut {
+        future::ready(self.metrics.observe_rpc("reset", &rst, || {
+            let mut state = self.arc.lock().unwrap();
+            if state.collection == rst.collection && state.coll.fields() == rst.fields {
+                return "Done".to_string();
+            }
+            info!(collection = rst.collection, fields = ?rst.fields, "new collection");
+            state.collection = rst.collection;
+            state.coll = CollectionState::new(rst.fields, self.data_len, rst.collection);
+            state.replies = Arc::new(Replies::default());
+
+            // Drop any keys still queued for the old collection.
+            state.incoming.drain();
+
+            "Done".to_string()
+        }))
+    }
+
+    fn add_triples(self, _: context::Context, req: AddTriplesRequest) -> Self::AddTriplesFut {
+        future::ready(self.metrics.observe_rpc("add_triples", &req, || {
+            let mut pools = self.pools.lock().unwrap();
+            // Triples come from the dealer, so this should not fail, but a
+            // panic here would poison the pools for every collection.
+            dispatch_pool!(pools, req.field, p => match req.triples.try_decode() {
+                Ok(triples) => {
+                    p.add(req.first, triples);
+                    "Done".to_string()
+                }
+                Err(e) => {
+                    warn!(error = %e, "malformed triples");
+                    format!("Malformed triples: {}", e)
+                }
+            })
+        }))
+    }
+
+    fn triple_count(self, _: context::Context, req: TripleCountRequest) -> Self::TripleCountFut {
+        future::ready(self.metrics.observe_rpc("triple_count", &req, || {
+            let mut pools = self.pools.lock().unwrap();
+            dispatch_pool!(pools, req.field, p => TripleCount {
+                dealt: p.dealt(),
+                available: p.available() as u64,
+            })
+        }))
+    }
+
+    fn add_keys(self, _: context::Context, add: AddKeysRequest) -> Self::AddKeysFut {
+        // Keys are decrypted here but only decoded and added at key_ids,
+        // so uploads never wait on the collection lock. Anyone can call
+        // this, so its replies are not kept by tag: a call sent ahead with
+        // the leader's next tag would otherwise stand in for the real
+        // batch. A batch sent twice is harmless, since key_ids takes each
+        // token once.
+        let _span = debug_span!("rpc", method = "add_keys", collection = add.tag.collection).entered();
+        future::ready(self.metrics.observe_rpc("add_keys", &add, || {
+            self.collection(add.tag)?;
+            let n = add.reports.len();
+            let keys: Vec<(auth::Token, Payload)> = add
+                .reports
+                .iter()
+                .filter_map(|r| {
+                    let plain = self.keypair.open(self.server_idx.into(), r)?;
+                    Some((r.token, Payload::from_bytes(plain)))
+                })
+                .collect();
+            if keys.len() < n {
+                warn!(undecryptable = n - keys.len(), "rejected keys");
+                self.metrics.reject("undecryptable", n - keys.len());
+            }
+            self.keys_in.push(keys);
+            Ok("".to_string())
+        }))
+    }
+
+    fn key_ids(self, _: context::Context, req: KeyIdsRequest) -> Self::KeyIdsFut {
+        future::ready(self.once("key_ids", &req, |coll| {
+            let batches = self.arc.lock().unwrap().incoming.drain();
+            Ok(dispatch!(coll, c => {
+                c.add_keys(batches, &self.metrics);
+                c.coll.lock().unwrap().pending_ids()
+            }))
+        }))
+    }
+
+    fn reconcile(self, _: context::Context, req: ReconcileRequest) -> Self::ReconcileFut {
+        future::ready(self.once("reconcile", &req, |coll| {
+            let (n, epochs) = dispatch!(coll, c => {
+                let mut coll = c.coll.lock().unwrap();
+                (coll.reconcile(&req.drop), coll.epochs())
+            });
+            info!(dropped = req.drop.len(), keys = n, epoch = epochs - 1, "reconciled one-sided keys");
+            Ok(n)
+        }))
+    }
+
+    fn tree_init(self, _: context::Context, req: TreeInitRequest) -> Self::TreeInitFut {
+        self.forget_replies(req.tag.round, 0);
+        future::ready(self.once("tree_init", &req, |coll| {
+            let keys = dispatch!(coll, c => c.tree_init(&req));
+            info!(epochs = ?req.epochs, prefixes = req.prefixes.len(), keys = keys.keys, "started a crawl");
+            Ok(keys)
+        }))
+    }
+
+    fn tree_crawl(self, _: context::Context, req: TreeCrawlRequest) -> Self::TreeCrawlFut {
+        self.forget_replies(req.tag.round, req.tag.level);
+        future::ready(self.once("tree_crawl", &req, |coll| {
+            dispatch!(coll, c => c.tree_crawl(req.tag.level, &self.pools, &self.metrics))
+        }))
+    }
+
+    fn tree_crawl_last(self, _: context::Context, req: TreeCrawlLastRequest) -> Self::TreeCrawlLastFut {
+        self.forget_replies(req.tag.round, req.tag.level);
+        future::ready(self.once("tree_crawl_last", &req, |coll| {
+            dispatch!(coll, c => c.tree_crawl_last(req.tag.level, &self.pools, &self.metrics))
+        }))
+    }
+
+    fn tree_prune(self, _: context::Context, req: TreePruneRequest) -> Self::TreePruneFut {
+        future::ready(self.once("tree_prune", &req, |coll| {
+            dispatch!(coll, c => c.coll.lock().unwrap().tree_prune(&req.keep));
+            Ok("Done".to_string())
+        }))
+    }
+
+    fn tree_prune_last(self, _: context::Context, req: TreePruneLastRequest) -> Self::TreePruneLastFut {
+        future::ready(self.once("tree_prune_last", &req, |coll| {
+            dispatch!(coll, c => c.coll.lock().unwrap().tree_prune_last(&req.keep));
+            Ok("Done".to_string())
+        }))
+    }
+
+    fn tree_reject(self, _: context::Context, req: TreeRejectRequest) -> Self::TreeRejectFut {
+        future::ready(self.once("tree_reject", &req, |coll| {
+            let (before, after) = dispatch!(coll, c => c.reject(&req.keys));
+            if after < before {
+                info!(rejected = before - after, live = after, "rejected keys that failed a sketch");
+            }
+            self.metrics.reject("sketch", before - after);
+            Ok(after)
+        }))
+    }
+
+    fn tree_recount(self, _: context::Context, req: TreeRecountRequest) -> Self::TreeRecountFut {
+        future::ready(self.once("tree_recount", &req, |coll| {
+            dispatch!(coll, c => c.tree_recount(req.tag.level, &self.pools))
+        }))
+    }
+
+    fn tree_recount_last(self, _: context::Context, req: TreeRecountLastRequest) -> Self::TreeRecountLastFut {
+        future::ready(self.once("tree_recount_last", &req, |coll| {
+            dispatch!(coll, c => c.tree_recount_last(&self.pools))
+        }))
+    }
+
+    fn tree_sketch_frontier(
+        self,
+        _: context::Context,
+        req: TreeSketchFrontierRequest,
+    ) -> Self::TreeSketchFrontierFut {
+        future::ready(self.once("tree_sketch_frontier", &req, |coll| {
+            dispatch!(coll, c => c.tree_sketch_frontier(self.server_idx, &req))
+        }))
+    }
+
+    fn tree_sketch_frontier_last(
+        self,
+        _: context::Context,
+        req: TreeSketchFrontierLastRequest,
+    ) -> Self::TreeSketchFrontierLastFut {
+        future::ready(self.once("tree_sketch_frontier_last", &req, |coll| {
+            dispatch!(coll, c => c.tree_sketch_frontier_last(self.server_idx, &req))
+        }))
+    }
+
+    fn tree_out_shares(
+        self,
+        _: context::Context,
+        req: TreeOutSharesRequest,
+    ) -> Self::TreeOutSharesFut {
+        future::ready(self.once("tree_out_shares", &req, |coll| {
+            dispatch!(coll, c => c.tree_out_shares(&req))
+        }))
+    }
+
+    fn tree_out_shares_last(
+        self,
+        _: context::Context,
+        req: TreeOutSharesLastRequest,
+    ) -> Self::TreeOutSharesLastFut {
+        future::ready(self.once("tree_out_shares_last", &req, |coll| {
+            dispatch!(coll, c => c.tree_out_shares_last(&req))
+        }))
+    }
+
+    fn final_shares(self, _: context::Context, req: FinalSharesRequest) -> Self::FinalSharesFut {
+        future::ready(self.once("final_shares", &req, |coll| {
+            Ok(dispatch!(coll, c => Payload::encode(&c.coll.lock().unwrap().final_shares())))
+        }))
+    }
+}
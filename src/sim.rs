@@ -0,0 +1,640 @@
+// In-process simulation of a whole collection: the leader and both
+// servers run in one process, each server as a plain KeyCollection, with
+// the same steps that the binaries take over RPC. The result can be
+// compared to a plaintext count of the same inputs, and clients or
+// servers can be made to misbehave.
+
+use crate::collect::{self, KeyCollection};
+use crate::input::{Field, Population, Source};
+use crate::mpc;
+use crate::report;
+use crate::rpc::RpcField;
+use crate::sketch::{self, SketchDPFKey};
+
+use std::collections::BTreeMap;
+use std::io;
+
+/// Where the honest clients' strings come from.
+#[derive(Clone, Debug)]
+pub enum Input {
+    /// `nclients` samples from a Zipf distribution with the given
+    /// exponent over `num_sites` random strings, as the leader draws them.
+    Zipf {
+        num_sites: usize,
+        exponent: f64,
+        nclients: usize,
+    },
+    /// `nclients` samples drawn uniformly from `num_sites` random strings.
+    Uniform { num_sites: usize, nclients: usize },
+    /// The clients in a file, each string cut or padded to `data_len`
+    /// bits.
+    File(Source),
+    /// The clients in a file, each a tuple of the given fields, whose
+    /// widths add up to `data_len` bits.
+    Tuples(Source, Vec<Field>),
+}
+
+/// The order in which the crawl settles the bits of each string.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CrawlOrder {
+    /// Every node needs the threshold over all live clients, so the
+    /// output is the heavy strings, or for tuples the heavy tuples.
+    #[default]
+    Joint,
+    /// The first `n` bits, such as a tuple's first field, are settled
+    /// as under Joint. Past them, a node needs the threshold only over
+    /// the clients that share its first `n` bits, so the output is the
+    /// heavy rest under each heavy prefix: the top URLs within each
+    /// heavy country.
+    Within(usize),
+}
+
+// The threshold at each node of a crawl in a given order, as the leader
+// computes it. Under CrawlOrder::Within, it carries the count of each
+// kept prefix down to the nodes under it.
+#[derive(Clone, Debug)]
+pub struct Thresholds {
+    threshold: f64,
+    order: CrawlOrder,
+    // The count of the settled prefix of each frontier node, once the
+    // crawl is past it.
+    prefixes: Vec<usize>,
+}
+
+/// A client that sends a malformed key for `path`.
+#[derive(Clone, Debug)]
+pub enum ClientAttack {
+    /// Claims a weight other than one at every level, to inflate the
+    /// count of its string.
+    Weight(u32),
+    /// Claims weight one at the inner levels and another weight at the
+    /// last, so that only the last level's check catches it.
+    LastWeight(u32),
+    /// Sends each server its half of a different key for the string, so
+    /// that the halves add up to garbage at every node.
+    Mismatched,
+}
+
+/// A server that adds `by` to its share of the count at node `node` of
+/// its crawl output at `level`. Level `data_len - 1` is the last level.
+///
+/// Alone, it cannot shift its shares of the level's sketches to match,
+/// so the leader names it. With an `accomplice`, the index of a client
+/// (the honest strings first, then the bad clients) whose key it knows,
+/// it moves its share of that client's sketch to cover the shift, and
+/// makes the sketch open as a valid one. Nothing catches that unless the
+/// count goes past the number of live clients: servers that collude with
+/// clients can skew the counts, though not learn the clients' strings.
+#[derive(Clone, Debug)]
+pub struct ServerAttack {
+    pub server: usize,
+    pub level: usize,
+    pub node: usize,
+    pub by: u32,
+    pub accomplice: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sim {
+    pub data_len: usize,
+    /// Fraction of the clients that must hold a string for it to be
+    /// heavy, as in the leader's config.
+    pub threshold: f64,
+    pub input: Input,
+    pub bad_clients: Vec<(Vec<bool>, ClientAttack)>,
+    pub bad_servers: Vec<ServerAttack>,
+    /// What the leader does about counts that cannot be honest.
+    pub policy: collect::Policy,
+    pub order: CrawlOrder,
+}
+
+#[derive(Clone, Debug)]
+pub struct Outcome<U> {
+    /// The heavy hitters and their counts, in path order.
+    pub heavy: Vec<collect::Result<U>>,
+    /// Clients whose keys failed a sketch check.
+    pub rejected: usize,
+    /// What the leader saw at each level.
+    pub levels: Vec<report::LevelStats>,
+    /// The levels at which some counts failed a check even without the
+    /// keys that failed the level's sketch check, and the nodes.
+    /// Under Policy::Abort the leader stops at the first, as the binary
+    /// does, with no output.
+    pub suspects: Vec<(usize, collect::Suspects)>,
+}
+
+impl Input {
+    // Draws or loads the honest clients.
+    pub fn population(&self, data_len: usize) -> io::Result<Population> {
+        match self {
+            Input::Zipf {
+                num_sites,
+                exponent,
+                nclients,
+            } => Population::zipf(*num_sites, *exponent, *nclients, data_len),
+            Input::Uniform { num_sites, nclients } => Population::uniform(*num_sites, *nclients, data_len),
+            Input::File(source) => Population::load(source, data_len),
+            Input::Tuples(source, fields) => Population::load_tuples(source, fields),
+        }
+    }
+}
+
+// The number of clients that a string needs to be heavy, as the leader
+// computes it.
+pub fn threshold_count(threshold: f64, nclients: usize) -> u32 {
+    std::cmp::max(1, (threshold * (nclients as f64)) as u32)
+}
+
+// The plaintext answer: every string that at least `threshold` clients
+// hold, with its count, in path order.
+pub fn oracle(strings: &[Vec<bool>], threshold: u32) -> Vec<(Vec<bool>, usize)> {
+    let mut counts = BTreeMap::new();
+    for s in strings {
+        *counts.entry(s.clone()).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .filter(|(_, n)| *n >= threshold as usize)
+        .collect()
+}
+
+// The plaintext answer that a crawl in `order` should give, with its
+// threshold a fraction of `nclients`, in path order.
+pub fn answer(strings: &[Vec<bool>], threshold: f64, nclients: usize, order: CrawlOrder) -> Vec<(Vec<bool>, usize)> {
+    let CrawlOrder::Within(bits) = order else {
+        return oracle(strings, threshold_count(threshold, nclients));
+    };
+    let prefixes: Vec<Vec<bool>> = strings.iter().map(|s| s[..bits].to_vec()).collect();
+    oracle(&prefixes, threshold_count(threshold, nclients))
+        .into_iter()
+        .flat_map(|(prefix, n)| {
+            let within: Vec<Vec<bool>> = strings.iter().filter(|s| s.starts_with(&prefix)).cloned().collect();
+            oracle(&within, threshold_count(threshold, n))
+        })
+        .collect()
+}
+
+impl Thresholds {
+    pub fn new(threshold: f64, order: CrawlOrder) -> Thresholds {
+        Thresholds {
+            threshold,
+            order,
+            prefixes: vec![],
+        }
+    }
+
+    // Which nodes to keep from the crawl of `level`, given the counts
+    // that collect::open_counts() returns and the `live` clients at the
+    // crawl. Takes every level of one crawl in turn.
+    pub fn keep<V: PartialOrd + From<u32>>(&mut self, level: usize, live: usize, counts: &[V]) -> Vec<bool> {
+        let keep: Vec<bool> = match self.order {
+            CrawlOrder::Within(bits) if level >= bits => {
+                assert_eq!(counts.len(), 2 * self.prefixes.len());
+                // Both children of a node share its prefix.
+                let prefixes: Vec<usize> = self.prefixes.iter().flat_map(|&n| [n, n]).collect();
+                let keep: Vec<bool> = counts
+                    .iter()
+                    .zip(&prefixes)
+                    .map(|(v, &n)| *v >= V::from(threshold_count(self.threshold, n)))
+                    .collect();
+                self.prefixes = prefixes.into_iter().zip(&keep).filter(|(_, &k)| k).map(|(n, _)| n).collect();
+                keep
+            }
+            _ => {
+                let threshold = V::from(threshold_count(self.threshold, live));
+                counts.iter().map(|v| *v >= threshold).collect()
+            }
+        };
+        if self.order == CrawlOrder::Within(level + 1) {
+            self.prefixes = counts.iter().zip(&keep).filter(|(_, &k)| k).map(|(v, _)| count_value(v, live)).collect();
+        }
+        keep
+    }
+}
+
+// The number that an opened count stands for. Field elements only
+// compare, so this searches up to `max`, which open_counts() keeps every
+// count within.
+fn count_value<V: PartialOrd + From<u32>>(count: &V, max: usize) -> usize {
+    let (mut lo, mut hi) = (0, max as u32);
+    while lo < hi {
+        let mid = lo + (hi - lo).div_ceil(2);
+        if *count >= V::from(mid) {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    lo as usize
+}
+
+impl<U: RpcField> Outcome<U> {
+    // Records the suspects at a level, and returns whether the crawl
+    // goes on without them.
+    fn judge(&mut self, policy: collect::Policy, level: usize, suspects: collect::Suspects) -> bool {
+        if suspects.is_empty() {
+            return true;
+        }
+        self.suspects.push((level, suspects));
+        policy == collect::Policy::Quarantine
+    }
+
+    // Counts the rejected clients once the crawl is over, at whatever
+    // level it ended.
+    fn finished<T: RpcField>(mut self, col: &KeyCollection<T, U>) -> Self {
+        self.rejected = col.keys.iter().filter(|k| !k.0).count();
+        self
+    }
+
+    // Whether the private output is exactly the plaintext answer.
+    pub fn matches(&self, expected: &[(Vec<bool>, usize)]) -> bool {
+        self.heavy.len() == expected.len()
+            && self
+                .heavy
+                .iter()
+                .zip(expected)
+                .all(|(r, (path, n))| r.path == *path && r.value == U::from(*n as u32))
+    }
+}
+
+impl Sim {
+    // The plaintext answer for `strings`, under the threshold over the
+    // honest clients alone. Those are the clients left live at the last
+    // level, once the sketch checks have caught the bad ones.
+    pub fn expected(&self, strings: &[Vec<bool>]) -> Vec<(Vec<bool>, usize)> {
+        answer(strings, self.threshold, strings.len(), self.order)
+    }
+
+    // Runs one collection over `strings`, the honest clients drawn
+    // for self.input, plus the bad clients.
+    pub fn run_on<T: RpcField, U: RpcField>(&self, strings: &[Vec<bool>]) -> Outcome<U> {
+        let dealer = mpc::DealerKey::random();
+        let mut col0 = KeyCollection::<T, U>::new(self.data_len);
+        let mut col1 = KeyCollection::<T, U>::new(self.data_len);
+
+        for s in strings {
+            let [k0, k1] = SketchDPFKey::gen_from_bits(s);
+            col0.add_key(k0);
+            col1.add_key(k1);
+        }
+        for (s, attack) in &self.bad_clients {
+            let [k0, k1] = bad_key(s, attack);
+            col0.add_key(k0);
+            col1.add_key(k1);
+        }
+        col0.tree_init();
+        col1.tree_init();
+
+        let mut thresholds = Thresholds::new(self.threshold, self.order);
+        let mut outcome = Outcome {
+            heavy: vec![],
+            rejected: 0,
+            levels: vec![],
+            suspects: vec![],
+        };
+        let mut first = 0;
+        for level in 0..self.data_len - 1 {
+            // Each level's threshold is a fraction of the keys that
+            // have passed every sketch check so far.
+            let counts = [col0.tree_crawl(), col1.tree_crawl()];
+            let crawled = Crawled {
+                level,
+                live: col0.live_keys(),
+                snapshots: [col0.frontier_snapshot(), col1.frontier_snapshot()],
+                mac_keys: [col0.mac_keys(), col1.mac_keys()],
+            };
+            let (live, counts, suspects) = self.settle(&crawled, &dealer, &mut first, counts, |ok| {
+                col0.apply_sketch_results(ok);
+                col1.apply_sketch_results(ok);
+                [col0.tree_recount(), col1.tree_recount()]
+            });
+            let keep = thresholds.keep(level, live, &counts);
+            outcome.levels.push(report::level_stats(level, &counts, &keep, &suspects));
+            if !outcome.judge(self.policy, level, suspects) {
+                return outcome.finished(&col0);
+            }
+
+            col0.tree_prune(&keep);
+            col1.tree_prune(&keep);
+        }
+
+        let level = self.data_len - 1;
+        let counts = [col0.tree_crawl_last(), col1.tree_crawl_last()];
+        let crawled = Crawled {
+            level,
+            live: col0.live_keys(),
+            snapshots: [col0.frontier_snapshot_last(), col1.frontier_snapshot_last()],
+            mac_keys: [col0.mac_keys_last(), col1.mac_keys_last()],
+        };
+        let (live, counts, suspects) = self.settle(&crawled, &dealer, &mut first, counts, |ok| {
+            col0.apply_sketch_results(ok);
+            col1.apply_sketch_results(ok);
+            [col0.tree_recount_last(), col1.tree_recount_last()]
+        });
+        let keep = thresholds.keep(level, live, &counts);
+        outcome.levels.push(report::level_stats(level, &counts, &keep, &suspects));
+        if !outcome.judge(self.policy, level, suspects) {
+            return outcome.finished(&col0);
+        }
+
+        col0.tree_prune_last(&keep);
+        col1.tree_prune_last(&keep);
+
+        let mut shares = [col0.final_shares(), col1.final_shares()];
+        self.tamper_final(&keep, &mut shares);
+        outcome.heavy = KeyCollection::<T, U>::final_values(&shares[0], &shares[1]);
+        outcome.finished(&col0)
+    }
+
+    // Checks a crawled level as the leader does, and opens its counts.
+    // Keys that fail their sketch check are dropped with `reject`, which
+    // returns the servers' counts without them, and the level is checked
+    // again under a fresh seed, until every live key passes. Returns the
+    // number of keys left live, the counts, and the suspects.
+    fn settle<V: RpcField>(
+        &self,
+        crawled: &Crawled<V>,
+        dealer: &mpc::DealerKey,
+        first: &mut u64,
+        counts: [Vec<V>; 2],
+        mut reject: impl FnMut(&[bool]) -> [Vec<V>; 2],
+    ) -> (usize, Vec<V>, collect::Suspects) {
+        let nodes = counts[0].len();
+        let mut live = crawled.live.clone();
+        let mut checked = self.check_level(crawled, &live, dealer, first, counts);
+        let mut servers = checked.servers.clone();
+        while failed(&live, &checked.ok) {
+            let counts = reject(&checked.ok);
+            for (l, ok) in live.iter_mut().zip(&checked.ok) {
+                *l &= ok;
+            }
+            checked = self.check_level(crawled, &live, dealer, first, counts);
+            servers.extend(checked.servers.iter().copied());
+        }
+        servers.sort_unstable();
+        servers.dedup();
+
+        let live = live.iter().filter(|l| **l).count();
+        let [counts0, counts1] = checked.counts;
+        let (counts, suspects) = collect::open_counts(live, nodes, &counts0, &counts1, servers);
+        (live, counts, suspects)
+    }
+
+    // One round of the leader's checks on a level: the servers take
+    // freshly dealt triples, sum their shares of them with their counts
+    // over the `live` keys, and sketch every key under a fresh seed. The
+    // bad servers tamper on the way.
+    fn check_level<V: RpcField>(
+        &self,
+        crawled: &Crawled<V>,
+        live: &[bool],
+        dealer: &mpc::DealerKey,
+        first: &mut u64,
+        counts: [Vec<V>; 2],
+    ) -> Checked<V> {
+        let nkeys = live.len();
+        let nodes = counts[0].len();
+        let triples = mpc::deal_triples::<V>(dealer, *first, sketch::TRIPLES_PER_LEVEL * nkeys);
+        let [counts0, counts1] = counts;
+        let mut shares = [
+            collect::CountShares::new(counts0, *first, &triples[0], live),
+            collect::CountShares::new(counts1, *first, &triples[1], live),
+        ];
+        *first += triples[0].len() as u64;
+        self.tamper(crawled.level, &mut shares);
+
+        let seed = crate::prg::PrgSeed::random();
+        let mut sketches = [
+            crawled.snapshots[0].sketch(0, nkeys, &seed),
+            crawled.snapshots[1].sketch(0, nkeys, &seed),
+        ];
+        self.rig(crawled.level, &sketch::weights(&seed, nodes), &crawled.mac_keys, &mut sketches);
+        let (ok, masked) = check_sketches(&triples, &sketches, &crawled.mac_keys, live);
+        let masked = [&masked[0], &masked[1]];
+        let servers = collect::misbehaving_servers(dealer, &seed, nodes, live, [&shares[0], &shares[1]], masked);
+        let [shares0, shares1] = shares;
+        Checked {
+            counts: [shares0.counts, shares1.counts],
+            ok,
+            servers,
+        }
+    }
+    // Draws the inputs and runs one collection over them. Returns the
+    // clients drawn too, to compare the outcome against.
+    pub fn run<T: RpcField, U: RpcField>(&self) -> io::Result<(Population, Outcome<U>)> {
+        let pop = self.input.population(self.data_len)?;
+        let outcome = self.run_on::<T, U>(&pop.strings());
+        Ok((pop, outcome))
+    }
+
+    // How the outcome of a run over `pop` compares to the plaintext
+    // answer, and what the leader saw on the way.
+    pub fn report<U: RpcField>(&self, pop: &Population, outcome: &Outcome<U>) -> report::Report {
+        let strings = pop.strings();
+        let expected = answer(&strings, self.threshold, strings.len(), self.order);
+        let threshold = threshold_count(self.threshold, pop.len());
+        let output: Vec<Vec<bool>> = outcome.heavy.iter().map(|r| r.path.clone()).collect();
+        let nclients = pop.len() + self.bad_clients.len();
+        let clients = report::Clients {
+            submitted: nclients,
+            accepted: nclients,
+            rejected: outcome.rejected,
+        };
+        report::Report::new(outcome.levels.clone(), clients, &output, pop, &expected, threshold)
+    }
+
+    fn tamper<V: RpcField>(&self, level: usize, shares: &mut [collect::CountShares<V>; 2]) {
+        for a in &self.bad_servers {
+            if a.level == level && a.node < shares[a.server].counts.len() {
+                shares[a.server].counts[a.node].add(&V::from(a.by));
+            }
+        }
+    }
+
+    // A bad server that shifted a count at the last level shifts its
+    // final share of the node to match, if the node is kept, since the
+    // leader checks the output against the counts.
+    fn tamper_final<U: RpcField>(&self, keep: &[bool], shares: &mut [Vec<collect::Result<U>>; 2]) {
+        for a in &self.bad_servers {
+            if a.level == self.data_len - 1 && keep.get(a.node) == Some(&true) {
+                let i = keep[..a.node].iter().filter(|k| **k).count();
+                shares[a.server][i].value.add(&U::from(a.by));
+            }
+        }
+    }
+
+    // Each bad server with an accomplice moves its share of the
+    // accomplice's <r, x> by the weight of its node times its shift, so
+    // that its counts match its sketches again. Knowing the accomplice's
+    // key, it knows the other server's share too, and sets its shares of
+    // <r^2, x> and <r, kx> so that the sketch still opens as a valid one.
+    fn rig<V: RpcField>(
+        &self,
+        level: usize,
+        weights: &[V],
+        mac_keys: &[(Vec<V>, Vec<V>); 2],
+        sketches: &mut [Vec<sketch::SketchOutput<V>>; 2],
+    ) {
+        for a in &self.bad_servers {
+            let Some(i) = a.accomplice else { continue };
+            if a.level != level || a.node >= weights.len() || i >= sketches[0].len() {
+                continue;
+            }
+            let other = sketches[1 - a.server][i].clone();
+            let mine = &mut sketches[a.server][i];
+            let mut shift = weights[a.node].clone();
+            shift.mul(&V::from(a.by));
+            mine.r_x.add(&shift);
+
+            let mut r_x = mine.r_x.clone();
+            r_x.add(&other.r_x);
+            let mut key = mac_keys[0].0[i].clone();
+            key.add(&mac_keys[1].0[i]);
+
+            mine.r2_x = r_x.clone();
+            mine.r2_x.mul(&r_x);
+            mine.r2_x.sub(&other.r2_x);
+            mine.r_kx = key;
+            mine.r_kx.mul(&r_x);
+            mine.r_kx.sub(&other.r_kx);
+        }
+    }
+}
+
+// One level as the leader checks it: the keys live at the crawl, and
+// each server's snapshot of the level and shares of the keys' MAC keys.
+struct Crawled<V> {
+    level: usize,
+    live: Vec<bool>,
+    snapshots: [collect::FrontierSnapshot<V>; 2],
+    mac_keys: [(Vec<V>, Vec<V>); 2],
+}
+
+// What one round of checks on a level found: the counts that the servers
+// sent, whether each key passed its sketch check, and the servers whose
+// counts do not match their sketches.
+struct Checked<V> {
+    counts: [Vec<V>; 2],
+    ok: Vec<bool>,
+    servers: Vec<usize>,
+}
+
+// Whether some live key failed its sketch check.
+fn failed(live: &[bool], ok: &[bool]) -> bool {
+    live.iter().zip(ok).any(|(l, ok)| *l && !*ok)
+}
+
+fn bad_key<T: RpcField, U: RpcField>(path: &[bool], attack: &ClientAttack) -> [SketchDPFKey<T, U>; 2] {
+    match attack {
+        ClientAttack::Weight(w) => SketchDPFKey::gen(path, &vec![T::from(*w); path.len() - 1], &U::from(*w)),
+        ClientAttack::LastWeight(w) => SketchDPFKey::gen(path, &vec![T::from(1); path.len() - 1], &U::from(*w)),
+        ClientAttack::Mismatched => {
+            let [k0, _] = SketchDPFKey::gen_from_bits(path);
+            let [_, k1] = SketchDPFKey::gen_from_bits(path);
+            [k0, k1]
+        }
+    }
+}
+
+// Checks every key's sketch at the level last crawled, under a fresh
+// seed and with freshly dealt triples. Returns whether each key passed.
+pub fn check_keys<T: RpcField, U: RpcField>(col0: &KeyCollection<T, U>, col1: &KeyCollection<T, U>) -> Vec<bool> {
+    let nkeys = col0.crawl_len();
+    let seed = crate::prg::PrgSeed::random();
+    let sketches = [col0.frontier_snapshot().sketch(0, nkeys, &seed), col1.frontier_snapshot().sketch(0, nkeys, &seed)];
+    let triples = mpc::deal_triples(&mpc::DealerKey::random(), 0, sketch::TRIPLES_PER_LEVEL * nkeys);
+    check_sketches(&triples, &sketches, &[col0.mac_keys(), col1.mac_keys()], &col0.live_keys()).0
+}
+
+// Like check_keys(), for the last level.
+pub fn check_keys_last<T: RpcField, U: RpcField>(
+    col0: &KeyCollection<T, U>,
+    col1: &KeyCollection<T, U>,
+) -> Vec<bool> {
+    let nkeys = col0.crawl_len();
+    let seed = crate::prg::PrgSeed::random();
+    let sketches = [
+        col0.frontier_snapshot_last().sketch(0, nkeys, &seed),
+        col1.frontier_snapshot_last().sketch(0, nkeys, &seed),
+    ];
+    let triples = mpc::deal_triples(&mpc::DealerKey::random(), 0, sketch::TRIPLES_PER_LEVEL * nkeys);
+    check_sketches(&triples, &sketches, &[col0.mac_keys_last(), col1.mac_keys_last()], &col0.live_keys()).0
+}
+
+// Runs the sketch checks with `triples`. Returns whether each key passed,
+// and each server's first corrections summed over the `live` keys.
+fn check_sketches<V: RpcField>(
+    triples: &[Vec<mpc::TripleShare<V>>; 2],
+    sketches: &[Vec<sketch::SketchOutput<V>>; 2],
+    mac_keys: &[(Vec<V>, Vec<V>); 2],
+    live: &[bool],
+) -> (Vec<bool>, [V; 2]) {
+    let mul0 = mpc::ManyMulState::new(false, &triples[0], &mac_keys[0].0, &mac_keys[0].1, &sketches[0]);
+    let mul1 = mpc::ManyMulState::new(true, &triples[1], &mac_keys[1].0, &mac_keys[1].1, &sketches[1]);
+
+    let (cor0, cor1) = (mul0.cor_shares(), mul1.cor_shares());
+    let masked = [mpc::ManyMulState::masked_r_x(&cor0, live), mpc::ManyMulState::masked_r_x(&cor1, live)];
+    let cor = mpc::ManyMulState::cors(&cor0, &cor1);
+    (mpc::ManyMulState::verify(&mul0.out_shares(&cor), &mul1.out_shares(&cor)), masked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oracle_counts() {
+        let strings: Vec<Vec<bool>> = ["ab", "cd", "ab", "ef", "cd", "ab"]
+            .iter()
+            .map(|s| crate::string_to_bits(s))
+            .collect();
+        let heavy = oracle(&strings, 2);
+        assert_eq!(heavy.len(), 2);
+        assert_eq!(crate::bits_to_string(&heavy[0].0), "ab");
+        assert_eq!(heavy[0].1, 3);
+        assert_eq!(crate::bits_to_string(&heavy[1].0), "cd");
+        assert_eq!(heavy[1].1, 2);
+    }
+
+    #[test]
+    fn random_inputs() {
+        let input = Input::Zipf {
+            num_sites: 10,
+            exponent: 1.0,
+            nclients: 50,
+        };
+        let strings = input.population(24).unwrap().strings();
+        assert_eq!(strings.len(), 50);
+        assert!(strings.iter().all(|s| s.len() == 24));
+
+        let input = Input::Zipf {
+            num_sites: 0,
+            exponent: 1.0,
+            nclients: 50,
+        };
+        assert!(input.population(24).is_err());
+    }
+
+    #[test]
+    fn thresholds_within_prefix() {
+        use crate::fastfield::FE;
+        let counts = |v: &[u32]| -> Vec<FE> { v.iter().map(|&n| FE::from(n)).collect() };
+        assert_eq!(count_value(&FE::from(37u32), 100), 37);
+        assert_eq!(count_value(&FE::from(0u32), 100), 0);
+        assert_eq!(count_value(&FE::from(100u32), 100), 100);
+
+        // Twenty clients with two-bit prefixes 00 (12), 01 (6) and 11 (2),
+        // at a fifth of the clients.
+        let mut thresholds = Thresholds::new(0.2, CrawlOrder::Within(2));
+        assert_eq!(thresholds.keep(0, 20, &counts(&[18, 2])), [true, false]);
+        assert_eq!(thresholds.keep(1, 20, &counts(&[12, 6])), [true, true]);
+        assert_eq!(thresholds.prefixes, [12, 6]);
+
+        // Past the prefix, 12 clients need 2 and 6 need 1.
+        assert_eq!(thresholds.keep(2, 20, &counts(&[11, 1, 5, 1])), [true, false, true, true]);
+        assert_eq!(thresholds.prefixes, [12, 6, 6]);
+
+        let mut joint = Thresholds::new(0.2, CrawlOrder::Joint);
+        assert_eq!(joint.keep(2, 20, &counts(&[11, 1, 5, 1])), [true, false, true, false]);
+    }
+}
@@ -1,5 +1,5 @@
 use crate::dpf;
-use crate::mpc;
+use crate::prg;
 
 use serde::{Deserialize, Serialize};
 
@@ -18,9 +18,6 @@ pub struct SketchDPFKey<T, U> {
     pub mac_key_last: U,
     pub mac_key2_last: U,
     key: dpf::DPFKey<(T, T), (U, U)>,
-
-    pub triples: Vec<mpc::TripleShare<T>>,
-    pub triples_last: Vec<mpc::TripleShare<U>>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -71,6 +68,75 @@ where
     }
 }
 
+// Sketches one key's values at every node of a frontier. This depends
+// only on the values, not on the key they came from.
+pub fn sketch_values<V>(
+    vector_in: &[(V, V)],
+    rand_stream: &mut impl rand::Rng,
+) -> SketchOutput<V>
+where
+    V: crate::Share,
+{
+    let mut out: SketchOutput<V> = SketchOutput::zero();
+
+    out.rand1.from_rng(rand_stream);
+    out.rand2.from_rng(rand_stream);
+    out.rand3.from_rng(rand_stream);
+
+    for v in vector_in {
+        // Get r_i from PRG stream
+        let mut sketch_r = V::zero();
+        sketch_r.from_rng(rand_stream);
+
+        // Compute r_i^2
+        let mut sketch_r2 = sketch_r.clone();
+        sketch_r2.mul_lazy(&sketch_r);
+
+        // Compute
+        //          <r, x>
+        //          <r^2, x>
+        //          <r, k.x>
+
+        let (x, kx) = v;
+
+        let mut tmp0 = x.clone();
+        tmp0.mul_lazy(&sketch_r);
+
+        let mut tmp1 = x.clone();
+        tmp1.mul_lazy(&sketch_r2);
+
+        let mut tmp2 = kx.clone();
+        tmp2.mul_lazy(&sketch_r);
+
+        out.r_x.add_lazy(&tmp0);
+        out.r2_x.add_lazy(&tmp1);
+        out.r_kx.add_lazy(&tmp2);
+    }
+
+    out.reduce();
+    out
+}
+
+// The sketch randomness r_i at each of `nodes` nodes, as sketch_values()
+// draws it from a stream from `seed`.
+pub fn weights<V>(seed: &prg::PrgSeed, nodes: usize) -> Vec<V>
+where
+    V: crate::Share,
+{
+    let mut stream = seed.to_rng();
+    let mut skip = V::zero();
+    for _ in 0..3 {
+        skip.from_rng(&mut stream);
+    }
+    (0..nodes)
+        .map(|_| {
+            let mut r = V::zero();
+            r.from_rng(&mut stream);
+            r
+        })
+        .collect()
+}
+
 impl<T,U> SketchDPFKey<T,U>
 where
     T: crate::Share + std::fmt::Debug + std::cmp::PartialEq,
@@ -109,23 +175,6 @@ where
 
         let (dpf_key0, dpf_key1) = dpf::DPFKey::gen(alpha_bits, &values, &value_last_with_mac);
 
-        let mut triples0 = vec![];
-        let mut triples1 = vec![];
-        for _i in 0..TRIPLES_PER_LEVEL * (alpha_bits.len() - 1) {
-            let t = mpc::TripleShare::new();
-            triples0.push(t[0].clone());
-            triples1.push(t[1].clone());
-        }
-
-        let mut triples0_last = vec![];
-        let mut triples1_last = vec![];
-        for _i in 0..TRIPLES_PER_LEVEL {
-            let t = mpc::TripleShare::new();
-            triples0_last.push(t[0].clone());
-            triples1_last.push(t[1].clone());
-        }
-
-
         [
             SketchDPFKey {
                 mac_key: mac_key_sh0,
@@ -133,8 +182,6 @@ where
                 mac_key_last: mac_key_sh0_last,
                 mac_key2_last: mac_key2_sh0_last,
                 key: dpf_key0,
-                triples: triples0,
-                triples_last: triples0_last
             },
             SketchDPFKey {
                 mac_key: mac_key_sh1,
@@ -142,16 +189,18 @@ where
                 mac_key_last: mac_key_sh1_last,
                 mac_key2_last: mac_key2_sh1_last,
                 key: dpf_key1,
-                triples: triples1,
-                triples_last: triples1_last
             },
         ]
     }
 
     pub fn gen_from_str(s: &str) -> [SketchDPFKey<T,U>; 2] {
-        let bits = crate::string_to_bits(s);
+        SketchDPFKey::gen_from_bits(&crate::string_to_bits(s))
+    }
+
+    // Keys for a client that holds the string `bits`, with weight one.
+    pub fn gen_from_bits(bits: &[bool]) -> [SketchDPFKey<T,U>; 2] {
         let values = vec![T::one(); bits.len()-1];
-        SketchDPFKey::gen(&bits, &values, &U::one())
+        SketchDPFKey::gen(bits, &values, &U::one())
     }
 
     pub fn sketch_at(
@@ -159,44 +208,7 @@ where
         vector_in: &[(T, T)],
         rand_stream: &mut impl rand::Rng,
     ) -> SketchOutput<T> {
-        let mut out: SketchOutput<T> = SketchOutput::zero();
-
-        out.rand1.from_rng(rand_stream);
-        out.rand2.from_rng(rand_stream);
-        out.rand3.from_rng(rand_stream);
-
-        for v in vector_in {
-            // Get r_i from PRG stream
-            let mut sketch_r = T::zero();
-            sketch_r.from_rng(rand_stream);
-
-            // Compute r_i^2
-            let mut sketch_r2 = sketch_r.clone();
-            sketch_r2.mul_lazy(&sketch_r);
-
-            // Compute
-            //          <r, x>
-            //          <r^2, x>
-            //          <r, k.x> 
-
-            let (x, kx) = v;
-
-            let mut tmp0 = x.clone();
-            tmp0.mul_lazy(&sketch_r);
-
-            let mut tmp1 = x.clone();
-            tmp1.mul_lazy(&sketch_r2);
-
-            let mut tmp2 = kx.clone();
-            tmp2.mul_lazy(&sketch_r);
-
-            out.r_x.add_lazy(&tmp0);
-            out.r2_x.add_lazy(&tmp1);
-            out.r_kx.add_lazy(&tmp2);
-        }
-
-        out.reduce();
-        out
+        sketch_values(vector_in, rand_stream)
     }
 
     pub fn sketch_at_last(
@@ -204,47 +216,9 @@ where
         vector_in: &[(U, U)],
         rand_stream: &mut impl rand::Rng,
     ) -> SketchOutput<U> {
-        let mut out: SketchOutput<U> = SketchOutput::zero();
-
-        out.rand1.from_rng(rand_stream);
-        out.rand2.from_rng(rand_stream);
-        out.rand3.from_rng(rand_stream);
-
-        for v in vector_in {
-            // Get r_i from PRG stream
-            let mut sketch_r = U::zero();
-            sketch_r.from_rng(rand_stream);
-
-            // Compute r_i^2
-            let mut sketch_r2 = sketch_r.clone();
-            sketch_r2.mul_lazy(&sketch_r);
-
-            // Compute
-            //          <r, x>
-            //          <r^2, x>
-            //          <r, k.x>
-
-            let (x, kx) = v;
-
-            let mut tmp0 = x.clone();
-            tmp0.mul_lazy(&sketch_r);
-
-            let mut tmp1 = x.clone();
-            tmp1.mul_lazy(&sketch_r2);
-
-            let mut tmp2 = kx.clone();
-            tmp2.mul_lazy(&sketch_r);
-
-            out.r_x.add_lazy(&tmp0);
-            out.r2_x.add_lazy(&tmp1);
-            out.r_kx.add_lazy(&tmp2);
-        }
-
-        out.reduce();
-        out
+        sketch_values(vector_in, rand_stream)
     }
 
-
     pub fn eval(&self, idx: &[bool]) -> U {
         debug_assert!(idx.len() <= self.key.domain_size()+1);
         debug_assert!(!idx.is_empty());
@@ -265,6 +239,11 @@ where
     pub fn eval_init(&self) -> dpf::EvalState {
         self.key.eval_init()
     }
+
+    // Number of bits in the strings that this key covers.
+    pub fn data_len(&self) -> usize {
+        self.key.domain_size() + 1
+    }
 }
 
 #[cfg(test)]
@@ -297,6 +276,21 @@ mod tests {
         assert_eq!(b.r_kx, FieldElm::from(10));
     }
 
+    #[test]
+    fn weights_match_sketch() {
+        let seed = prg::PrgSeed::random();
+        let r = weights::<FieldElm>(&seed, 4);
+        for j in 0..4 {
+            let mut x = vec![(FieldElm::zero(), FieldElm::zero()); 4];
+            x[j] = (FieldElm::from(2u32), FieldElm::zero());
+            let out = sketch_values(&x, &mut seed.to_rng());
+
+            let mut want = r[j].clone();
+            want.mul(&FieldElm::from(2u32));
+            assert_eq!(out.r_x, want);
+        }
+    }
+
     #[test]
     fn mac_keys() {
         let nbits = 3;
@@ -311,9 +305,9 @@ mod tests {
         let mut mac = FieldElm::zero();
         let mut mac2 = FieldElm::zero();
 
-        for i in 0..2 {
-            mac.add(&keys[i].mac_key);
-            mac2.add(&keys[i].mac_key2);
+        for key in &keys {
+            mac.add(&key.mac_key);
+            mac2.add(&key.mac_key2);
         }
 
         println!("mac  = {:?}", mac);
@@ -336,9 +330,9 @@ mod tests {
         let mut mac = FieldElm::zero();
         let mut mac2 = FieldElm::zero();
 
-        for i in 0..2 {
-            mac.add(&keys[i].mac_key);
-            mac2.add(&keys[i].mac_key2);
+        for key in &keys {
+            mac.add(&key.mac_key);
+            mac2.add(&key.mac_key2);
         }
 
         for i in 0..(1 << nbits)-1 {
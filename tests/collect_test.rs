@@ -1,5 +1,4 @@
 use counttree::collect::*;
-use counttree::prg;
 use counttree::sketch::*;
 use counttree::*;
 
@@ -11,9 +10,8 @@ fn collect_test_eval() {
 
     let strlen = crate::string_to_bits(&client_strings[0]).len();
 
-    let seed = prg::PrgSeed::random();
-    let mut col0 = KeyCollection::new(&seed, strlen);
-    let mut col1 = KeyCollection::new(&seed, strlen);
+    let mut col0 = KeyCollection::new(strlen);
+    let mut col1 = KeyCollection::new(strlen);
 
     for cstr in &client_strings {
         let keys = SketchDPFKey::<FieldElm,FieldElm>::gen_from_str(&cstr);
@@ -32,7 +30,7 @@ fn collect_test_eval() {
         let vals1 = col1.tree_crawl();
 
         assert_eq!(vals0.len(), vals1.len());
-        let keep = KeyCollection::<FieldElm,FieldElm>::keep_values(nclients, &threshold, &vals0, &vals1);
+        let keep = KeyCollection::<FieldElm,FieldElm>::keep_values(&threshold, &open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]).0);
 
         col0.tree_prune(&keep);
         col1.tree_prune(&keep);
@@ -42,7 +40,7 @@ fn collect_test_eval() {
     let vals1 = col1.tree_crawl_last();
 
     assert_eq!(vals0.len(), vals1.len());
-    let keep = KeyCollection::<FieldElm,FieldElm>::keep_values_last(nclients, &threshold, &vals0, &vals1);
+    let keep = KeyCollection::<FieldElm,FieldElm>::keep_values_last(&threshold, &open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]).0);
 
     col0.tree_prune_last(&keep);
     col1.tree_prune_last(&keep);
@@ -66,105 +64,6 @@ fn collect_test_eval() {
     }
 }
 
-fn verify_sketches(
-    col0: &mut KeyCollection<FieldElm,fastfield::FE>,
-    col1: &mut KeyCollection<FieldElm,fastfield::FE>,
-    level: usize,
-    nkeys: usize
-) -> Vec<bool> {
-    println!("   frontier");
-    let sketch0 = col0.tree_sketch_frontier(0, nkeys);
-    let sketch1 = col1.tree_sketch_frontier(0, nkeys);
-    println!("   done");
-
-    println!("   mul");
-
-    let mut triples0 = vec![];
-    let mut mac0 = vec![];
-    let mut macp0 = vec![];
-
-    for key in &col0.keys {
-        triples0.push(key.1.triples.clone());
-        mac0.push(key.1.mac_key.clone());
-        macp0.push(key.1.mac_key2.clone());
-    }
-
-    let mut triples1 = vec![];
-    let mut mac1 = vec![];
-    let mut macp1 = vec![];
-
-    for key in &col1.keys {
-        triples1.push(key.1.triples.clone());
-        mac1.push(key.1.mac_key.clone());
-        macp1.push(key.1.mac_key2.clone());
-    }
-
-    let many_mul0 = mpc::ManyMulState::new(false, &triples0, &mac0, &macp0, &sketch0, level);
-    let many_mul1 = mpc::ManyMulState::new(true, &triples1, &mac1, &macp1, &sketch1, level);
-
-    let cor_shares0 = many_mul0.cor_shares();
-    let cor_shares1 = many_mul1.cor_shares();
-
-    let cor = mpc::ManyMulState::cors(&cor_shares0, &cor_shares1);
-
-    let out_shares0 = many_mul0.out_shares(&cor);
-    let out_shares1 = many_mul1.out_shares(&cor);
-
-    let out = mpc::ManyMulState::verify(&out_shares0, &out_shares1);
-    println!("   done");
-
-    out
-}
-
-fn verify_sketches_last(
-    col0: &mut KeyCollection<FieldElm,fastfield::FE>,
-    col1: &mut KeyCollection<FieldElm,fastfield::FE>,
-    nkeys: usize
-) -> Vec<bool> {
-    println!("   frontier");
-    let sketch0 = col0.tree_sketch_frontier_last(0, nkeys);
-    let sketch1 = col1.tree_sketch_frontier_last(0, nkeys);
-    println!("   done");
-
-    println!("   mul");
-
-    let mut triples0 = vec![];
-    let mut mac0 = vec![];
-    let mut macp0 = vec![];
-
-    for key in &col0.keys {
-        triples0.push(key.1.triples_last.clone());
-        mac0.push(key.1.mac_key_last.clone());
-        macp0.push(key.1.mac_key2_last.clone());
-    }
-
-    let mut triples1 = vec![];
-    let mut mac1 = vec![];
-    let mut macp1 = vec![];
-
-    for key in &col1.keys {
-        triples1.push(key.1.triples_last.clone());
-        mac1.push(key.1.mac_key_last.clone());
-        macp1.push(key.1.mac_key2_last.clone());
-    }
-
-    let many_mul0 = mpc::ManyMulState::new(false, &triples0, &mac0, &macp0, &sketch0, 0);
-    let many_mul1 = mpc::ManyMulState::new(true, &triples1, &mac1, &macp1, &sketch1, 0);
-
-    let cor_shares0 = many_mul0.cor_shares();
-    let cor_shares1 = many_mul1.cor_shares();
-
-    let cor = mpc::ManyMulState::cors(&cor_shares0, &cor_shares1);
-
-    let out_shares0 = many_mul0.out_shares(&cor);
-    let out_shares1 = many_mul1.out_shares(&cor);
-
-    let out = mpc::ManyMulState::verify(&out_shares0, &out_shares1);
-    println!("   done");
-
-    out
-}
-
 #[test]
 fn collect_test_eval_full() {
     let client_strings = [
@@ -177,9 +76,8 @@ fn collect_test_eval_full() {
     let nclients = 10;
     let strlen = crate::string_to_bits(&client_strings[0]).len();
 
-    let seed = prg::PrgSeed::random();
-    let mut col0 = KeyCollection::new(&seed, strlen);
-    let mut col1 = KeyCollection::new(&seed, strlen);
+    let mut col0 = KeyCollection::new(strlen);
+    let mut col1 = KeyCollection::new(strlen);
     // use cpuprofiler::PROFILER;
 
     let mut keys = vec![];
@@ -213,13 +111,13 @@ fn collect_test_eval_full() {
         println!("At level {:?} (size: {:?})", level, vals0.len());
 
         println!("...sketch");
-        for v in verify_sketches(&mut col0, &mut col1, level, nclients) {
+        for v in sim::check_keys(&col0, &col1) {
             assert!(v);
         }
         println!("...done");
 
         assert_eq!(vals0.len(), vals1.len());
-        let keep = KeyCollection::<FieldElm,fastfield::FE>::keep_values(nclients, &threshold, &vals0, &vals1);
+        let keep = KeyCollection::<FieldElm,fastfield::FE>::keep_values(&threshold, &open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]).0);
 
         col0.tree_prune(&keep);
         col1.tree_prune(&keep);
@@ -229,12 +127,12 @@ fn collect_test_eval_full() {
     let vals0 = col0.tree_crawl_last();
     let vals1 = col1.tree_crawl_last();
 
-    for v in verify_sketches_last(&mut col0, &mut col1, nclients) {
+    for v in sim::check_keys_last(&col0, &col1) {
         assert!(v);
     }
 
     assert_eq!(vals0.len(), vals1.len());
-    let keep = KeyCollection::<FieldElm,fastfield::FE>::keep_values_last(nclients, &threshold_last, &vals0, &vals1);
+    let keep = KeyCollection::<FieldElm,fastfield::FE>::keep_values_last(&threshold_last, &open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]).0);
 
     col0.tree_prune_last(&keep);
     col1.tree_prune_last(&keep);
@@ -249,3 +147,153 @@ fn collect_test_eval_full() {
     }
 }
 
+
+#[test]
+fn collect_test_eval_field127() {
+    let client_strings = [
+        "abdef", "abdef", "abdef", "ghijk", "gZijk", "gZ???", "  ?*g", "abdef", "gZ???", "gZ???",
+    ];
+
+    let nclients = client_strings.len();
+    let strlen = crate::string_to_bits(&client_strings[0]).len();
+
+    let mut col0 = KeyCollection::new(strlen);
+    let mut col1 = KeyCollection::new(strlen);
+
+    for cstr in &client_strings {
+        let keys = SketchDPFKey::<fastfield::FE,FieldElm127>::gen_from_str(cstr);
+        col0.add_key(keys[0].clone());
+        col1.add_key(keys[1].clone());
+    }
+
+    col0.tree_init();
+    col1.tree_init();
+
+    let threshold = fastfield::FE::new(2);
+    let threshold_last = FieldElm127::from(2);
+    for _ in 0..strlen-1 {
+        let vals0 = col0.tree_crawl();
+        let vals1 = col1.tree_crawl();
+
+        for v in sim::check_keys(&col0, &col1) {
+            assert!(v);
+        }
+
+        let keep = KeyCollection::<fastfield::FE,FieldElm127>::keep_values(&threshold, &open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]).0);
+
+        col0.tree_prune(&keep);
+        col1.tree_prune(&keep);
+    }
+
+    let vals0 = col0.tree_crawl_last();
+    let vals1 = col1.tree_crawl_last();
+
+    for v in sim::check_keys_last(&col0, &col1) {
+        assert!(v);
+    }
+
+    let keep = KeyCollection::<fastfield::FE,FieldElm127>::keep_values_last(&threshold_last, &open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]).0);
+
+    col0.tree_prune_last(&keep);
+    col1.tree_prune_last(&keep);
+
+    let s0 = col0.final_shares();
+    let s1 = col1.final_shares();
+
+    let results = KeyCollection::<fastfield::FE,FieldElm127>::final_values(&s0, &s1);
+    assert_eq!(results.len(), 2);
+    for res in &results {
+        let s = crate::bits_to_string(&res.path);
+        match &s[..] {
+            "abdef" => assert_eq!(res.value, FieldElm127::from(4)),
+            "gZ???" => assert_eq!(res.value, FieldElm127::from(3)),
+            _ => panic!("Unexpected string: '{:?}' = {:?}", s, res.value),
+        }
+    }
+}
+
+#[test]
+fn collect_test_replay() {
+    let client_strings = ["abc", "abc", "xyz", "xyz", "xyz"];
+
+    let nclients = client_strings.len();
+    let strlen = crate::string_to_bits(client_strings[0]).len();
+    let collection = 17;
+
+    let authority = auth::Authority::new(&auth::STAND_IN_KEY);
+    let verifier = auth::Verifier::new(&auth::STAND_IN_KEY, collection);
+
+    let mut col0 = KeyCollection::new(strlen);
+    let mut col1 = KeyCollection::new(strlen);
+
+    let mut reports = vec![];
+    for (i, cstr) in client_strings.iter().enumerate() {
+        let token = authority.issue(collection, &format!("client{}", i)).unwrap();
+        let keys = SketchDPFKey::<fastfield::FE,fastfield::FE>::gen_from_str(cstr);
+        reports.push((token, keys));
+    }
+
+    for (token, keys) in &reports {
+        assert_eq!(col0.add_report(&verifier, token, keys[0].clone()), Ingest::Accepted);
+        assert_eq!(col1.add_report(&verifier, token, keys[1].clone()), Ingest::Accepted);
+    }
+
+    // Client 0 replays its report, and then tries again with fresh keys
+    // under the same token. Neither counts.
+    let (token, keys) = &reports[0];
+    assert_eq!(col0.add_report(&verifier, token, keys[0].clone()), Ingest::Replay);
+    assert_eq!(col1.add_report(&verifier, token, keys[1].clone()), Ingest::Replay);
+    let keys = SketchDPFKey::gen_from_str("abc");
+    assert_eq!(col0.add_report(&verifier, token, keys[0].clone()), Ingest::Replay);
+    assert_eq!(col1.add_report(&verifier, token, keys[1].clone()), Ingest::Replay);
+
+    // It cannot get a second token, and tokens for other collections are
+    // no good here.
+    assert!(authority.issue(collection, "client0").is_none());
+    let other = authority.issue(collection + 1, "client0").unwrap();
+    assert_eq!(col0.add_report(&verifier, &other, keys[0].clone()), Ingest::BadToken);
+    assert_eq!(col1.add_report(&verifier, &other, keys[1].clone()), Ingest::BadToken);
+
+    assert_eq!(col0.reconcile(&[]), nclients);
+    assert_eq!(col1.reconcile(&[]), nclients);
+
+    // A replay after its key was reconciled is still caught, so the next
+    // epoch is empty.
+    let (token, keys) = &reports[0];
+    assert_eq!(col0.add_report(&verifier, token, keys[0].clone()), Ingest::Replay);
+    assert_eq!(col1.add_report(&verifier, token, keys[1].clone()), Ingest::Replay);
+    assert_eq!(col0.reconcile(&[]), 0);
+    assert_eq!(col1.reconcile(&[]), 0);
+
+    col0.tree_init();
+    col1.tree_init();
+    assert_eq!(col0.crawl_len(), nclients);
+
+    let threshold = fastfield::FE::new(2);
+    for _level in 0..strlen-1 {
+        let vals0 = col0.tree_crawl();
+        let vals1 = col1.tree_crawl();
+        let keep = KeyCollection::<fastfield::FE,fastfield::FE>::keep_values(&threshold, &open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]).0);
+
+        col0.tree_prune(&keep);
+        col1.tree_prune(&keep);
+    }
+
+    let vals0 = col0.tree_crawl_last();
+    let vals1 = col1.tree_crawl_last();
+    let keep = KeyCollection::<fastfield::FE,fastfield::FE>::keep_values_last(&threshold, &open_counts(nclients, vals0.len(), &vals0, &vals1, vec![]).0);
+
+    col0.tree_prune_last(&keep);
+    col1.tree_prune_last(&keep);
+
+    let results = KeyCollection::<fastfield::FE,fastfield::FE>::final_values(&col0.final_shares(), &col1.final_shares());
+    assert_eq!(results.len(), 2);
+    for res in &results {
+        let s = crate::bits_to_string(&res.path);
+        match &s[..] {
+            "abc" => assert_eq!(res.value, fastfield::FE::new(2)),
+            "xyz" => assert_eq!(res.value, fastfield::FE::new(3)),
+            _ => panic!("Unexpected string: '{:?}' = {:?}", s, res.value),
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use counttree::dpf::*;
 use counttree::*;
 
+use rand::Rng;
+
 #[test]
 fn dpf_complete() {
     let nbits = 5;
@@ -44,3 +46,199 @@ fn dpf_complete() {
         }
     }
 }
+
+const TRIALS: usize = 40;
+
+fn random_vec<T: prg::FromRng + Group>(len: usize) -> Vec<T> {
+    let mut rng = rand::thread_rng();
+    (0..len)
+        .map(|_| {
+            let mut v = T::zero();
+            // Zero values must also come out right.
+            if rng.gen_bool(0.8) {
+                v.randomize();
+            }
+            v
+        })
+        .collect()
+}
+
+fn random_bits(len: usize) -> Vec<bool> {
+    let mut rng = rand::thread_rng();
+    (0..len).map(|_| rng.gen::<bool>()).collect()
+}
+
+// Points at which to evaluate a DPF with point alpha: the whole domain
+// when it is small, otherwise alpha, its neighbours and some random
+// points.
+fn eval_points(alpha: &[bool]) -> Vec<Vec<bool>> {
+    let n = alpha.len();
+    if n <= 6 {
+        return (0..(1 << n)).map(|i| u32_to_bits(n as u8, i)).collect();
+    }
+
+    let mut out = vec![alpha.to_vec()];
+    for i in 0..n {
+        let mut p = alpha.to_vec();
+        p[i] = !p[i];
+        out.push(p);
+    }
+    for _ in 0..16 {
+        out.push(random_bits(n));
+    }
+    out
+}
+
+fn check_dpf<T, U>(alpha: &[bool], values: &[T], value_last: &U)
+where
+    T: prg::FromRng + Clone + Group + std::fmt::Debug + PartialEq,
+    U: prg::FromRng + Clone + Group + std::fmt::Debug + PartialEq,
+{
+    let (key0, key1) = DPFKey::gen(alpha, values, value_last);
+
+    for idx in eval_points(alpha) {
+        // Every prefix of idx yields the inner values along its path.
+        for j in 1..=idx.len() {
+            let eval0 = key0.eval(&idx[0..j]);
+            let eval1 = key1.eval(&idx[0..j]);
+            assert_eq!(eval0.0.len(), j - 1);
+
+            for i in 0..(j - 1) {
+                let mut sum = eval0.0[i].clone();
+                sum.add(&eval1.0[i]);
+
+                let want = if idx[0..=i] == alpha[0..=i] {
+                    values[i].clone()
+                } else {
+                    T::zero()
+                };
+                assert_eq!(want, sum, "alpha {:?}, idx {:?}, level {}", alpha, idx, i);
+            }
+        }
+
+        let eval0 = key0.eval(&idx);
+        let eval1 = key1.eval(&idx);
+        let mut sum = eval0.1;
+        sum.add(&eval1.1);
+
+        let want = if idx == alpha {
+            value_last.clone()
+        } else {
+            U::zero()
+        };
+        assert_eq!(want, sum, "alpha {:?}, idx {:?}, last level", alpha, idx);
+    }
+}
+
+fn check_dpf_random<T, U>()
+where
+    T: prg::FromRng + Clone + Group + std::fmt::Debug + PartialEq,
+    U: prg::FromRng + Clone + Group + std::fmt::Debug + PartialEq,
+{
+    let mut rng = rand::thread_rng();
+    for _ in 0..TRIALS {
+        let n = rng.gen_range(1, 13);
+        let alpha = random_bits(n);
+        let values = random_vec::<T>(n - 1);
+        let value_last = random_vec::<U>(1).pop().unwrap();
+
+        check_dpf(&alpha, &values, &value_last);
+    }
+}
+
+#[test]
+fn dpf_random_fe() {
+    check_dpf_random::<fastfield::FE, fastfield::FE>();
+}
+
+#[test]
+fn dpf_random_fieldelm() {
+    check_dpf_random::<FieldElm, FieldElm>();
+}
+
+#[test]
+fn dpf_random_mixed() {
+    check_dpf_random::<fastfield::FE, FieldElm>();
+    check_dpf_random::<fastfield::FE, FieldElm127>();
+}
+
+#[test]
+fn dpf_random_tuple() {
+    check_dpf_random::<(fastfield::FE, fastfield::FE), (FieldElm, FieldElm)>();
+    check_dpf_random::<(FieldElm, FieldElm), fastfield::FE>();
+}
+
+// Counts, for each bit of a single serialized key, how often it is set
+// over many keys for the same point alpha.
+fn key_bit_counts<T, U>(alpha: &[bool], samples: usize, key_idx: bool) -> Vec<usize>
+where
+    T: prg::FromRng + Clone + Group + std::fmt::Debug + serde::Serialize,
+    U: prg::FromRng + Clone + Group + std::fmt::Debug + serde::Serialize,
+{
+    let mut counts: Vec<usize> = vec![];
+    for _ in 0..samples {
+        let values = random_vec::<T>(alpha.len() - 1);
+        let value_last = random_vec::<U>(1).pop().unwrap();
+        let keys = DPFKey::gen(alpha, &values, &value_last);
+        let key = if key_idx { keys.1 } else { keys.0 };
+
+        let bytes = bincode::serialize(&key).unwrap();
+        if counts.is_empty() {
+            counts = vec![0; bytes.len() * 8];
+        }
+        assert_eq!(counts.len(), bytes.len() * 8);
+
+        for (i, byte) in bytes.iter().enumerate() {
+            for b in 0..8 {
+                counts[i * 8 + b] += ((byte >> b) & 1) as usize;
+            }
+        }
+    }
+    counts
+}
+
+// Checks that no bit of either key, correction words included, has a
+// distribution that depends on alpha. Both fields must serialize to a
+// fixed number of bytes.
+fn check_key_privacy<T, U>(alpha_a: &[bool], alpha_b: &[bool])
+where
+    T: prg::FromRng + Clone + Group + std::fmt::Debug + serde::Serialize,
+    U: prg::FromRng + Clone + Group + std::fmt::Debug + serde::Serialize,
+{
+    let samples = 1000;
+
+    for &key_idx in &[false, true] {
+        let counts_a = key_bit_counts::<T, U>(alpha_a, samples, key_idx);
+        let counts_b = key_bit_counts::<T, U>(alpha_b, samples, key_idx);
+        assert_eq!(counts_a.len(), counts_b.len());
+
+        for i in 0..counts_a.len() {
+            let pa = counts_a[i] as f64 / samples as f64;
+            let pb = counts_b[i] as f64 / samples as f64;
+
+            // Two-sample test on the proportions, at six standard
+            // deviations. Bits that are constant must agree exactly.
+            let p = (pa + pb) / 2.0;
+            let sigma = (2.0 * p * (1.0 - p) / samples as f64).sqrt();
+            assert!(
+                (pa - pb).abs() <= 6.0 * sigma,
+                "Key {} bit {} depends on alpha: {} vs {}",
+                key_idx as u8,
+                i,
+                pa,
+                pb
+            );
+        }
+    }
+}
+
+#[test]
+fn dpf_key_privacy() {
+    let n = 8;
+    check_key_privacy::<fastfield::FE, fastfield::FE>(&vec![false; n], &vec![true; n]);
+    check_key_privacy::<fastfield::FE, FieldElm127>(&random_bits(n), &random_bits(n));
+    check_key_privacy::<(fastfield::FE, fastfield::FE), fastfield::FE>(
+        &u32_to_bits(n as u8, 0b1010_1010),
+        &u32_to_bits(n as u8, 0b0101_0101),
+    );
+}
@@ -0,0 +1,204 @@
+use counttree::collect::*;
+use counttree::config::{FieldPair, FieldType};
+use counttree::rpc::*;
+use counttree::sketch::*;
+use counttree::*;
+
+use futures::try_join;
+
+type F = fastfield::FE;
+
+const FIELDS: FieldPair = FieldPair {
+    inner: FieldType::FE62,
+    last: FieldType::FE62,
+};
+
+// Starts both servers' HTTP front ends on localhost.
+fn start_servers(data_len: usize) -> [http::Client; 2] {
+    let mut clients = vec![];
+    for idx in 0..2 {
+        let server = server::CollectorServer::new(idx, envelope::KeyPair::random(), data_len, FIELDS);
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let (addr, serving) = http::bind(server, &addr, http::Api::Control, 16 << 20).unwrap();
+        tokio::spawn(serving);
+        clients.push(http::Client::new(addr));
+    }
+    [clients[0].clone(), clients[1].clone()]
+}
+
+// Sketches a level under a fresh seed, and checks every key's sketch and
+// each server's counts `shares` against its shares of the sketches.
+async fn verify_sketches(
+    c: &[http::Client; 2],
+    tag: Tag,
+    nclients: usize,
+    dealer: &mpc::DealerKey,
+    shares: &[CountShares<F>; 2],
+) {
+    let seed = prg::PrgSeed::random();
+    let req = TreeSketchFrontierRequest { tag, start: 0, end: nclients, seed: seed.clone() };
+    let (s0, s1) = try_join!(c[0].tree_sketch_frontier(req.clone()), c[1].tree_sketch_frontier(req)).unwrap();
+    let live = vec![true; nclients];
+    let masked = [&s0, &s1].map(|s| mpc::ManyMulState::<F>::masked_r_x(&s.decode(), &live));
+    let nodes = shares[0].counts.len();
+    let masked = [&masked[0], &masked[1]];
+    assert!(misbehaving_servers(dealer, &seed, nodes, &live, [&shares[0], &shares[1]], masked).is_empty());
+    let cor = mpc::ManyMulState::<F>::cors(&s0.decode(), &s1.decode());
+
+    let req = TreeOutSharesRequest { tag, cor: Payload::encode(&cor) };
+    let (o0, o1) = try_join!(c[0].tree_out_shares(req.clone()), c[1].tree_out_shares(req)).unwrap();
+    for v in mpc::ManyMulState::<F>::verify(&o0.decode(), &o1.decode()) {
+        assert!(v);
+    }
+}
+
+// Runs a whole collection over HTTP: dealing triples, client uploads
+// through a relay, and every leader-driven step.
+#[tokio::test(flavor = "multi_thread")]
+async fn http_collect() {
+    let client_strings = ["ab", "ab", "cd", "ab", "cd", "xy"];
+    let nclients = client_strings.len();
+    let data_len = 16;
+    let c = start_servers(data_len);
+
+    // Offline phase
+    let dealer = mpc::DealerKey::random();
+    let [t0, t1] = mpc::deal_triples::<F>(&dealer, 0, TRIPLES_PER_LEVEL * nclients * data_len);
+    let req0 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t0) };
+    let req1 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t1) };
+    try_join!(c[0].add_triples(req0), c[1].add_triples(req1)).unwrap();
+    let count = c[0].triple_count(TripleCountRequest { field: FieldType::FE62 }).await.unwrap();
+    assert_eq!(count.available as usize, TRIPLES_PER_LEVEL * nclients * data_len);
+
+    // Client uploads
+    let collection = 5;
+    let req = ResetRequest { fields: FIELDS, collection };
+    try_join!(c[0].reset(req.clone()), c[1].reset(req)).unwrap();
+    let (pk0, pk1) =
+        try_join!(c[0].public_key(PublicKeyRequest {}), c[1].public_key(PublicKeyRequest {})).unwrap();
+
+    let authority = auth::Authority::new(&auth::STAND_IN_KEY);
+    let (mut reports0, mut reports1) = (vec![], vec![]);
+    for (i, s) in client_strings.iter().enumerate() {
+        let token = authority.issue(collection, &format!("client{}", i)).unwrap();
+        let keys = SketchDPFKey::<F, F>::gen_from_str(s);
+        let plain0 = bincode::serialize(&keys[0]).unwrap();
+        let plain1 = bincode::serialize(&keys[1]).unwrap();
+        let sealed = envelope::SealedReport::seal(&[pk0.clone(), pk1.clone()], token, [&plain0, &plain1]);
+        let (r0, r1) = sealed.split();
+        reports0.push(r0);
+        reports1.push(r1);
+    }
+    let tag = Tag::new(collection, 0, 0);
+    try_join!(
+        c[0].add_keys(AddKeysRequest { tag, reports: reports0 }),
+        c[1].add_keys(AddKeysRequest { tag, reports: reports1 })
+    )
+    .unwrap();
+
+    let (ids0, ids1) =
+        try_join!(c[0].key_ids(KeyIdsRequest { tag }), c[1].key_ids(KeyIdsRequest { tag })).unwrap();
+    assert_eq!(ids0, ids1);
+    assert_eq!(ids0.len(), nclients);
+    let (n0, n1) = try_join!(
+        c[0].reconcile(ReconcileRequest { tag, drop: vec![] }),
+        c[1].reconcile(ReconcileRequest { tag, drop: vec![] })
+    )
+    .unwrap();
+    assert_eq!((n0, n1), (nclients, nclients));
+    try_join!(c[0].tree_init(TreeInitRequest::new(tag)), c[1].tree_init(TreeInitRequest::new(tag))).unwrap();
+
+    // Crawl
+    let threshold = F::from(2u32);
+    for level in 0..data_len - 1 {
+        let tag = Tag::new(collection, level, 0);
+        let (v0, v1) =
+            try_join!(c[0].tree_crawl(TreeCrawlRequest { tag }), c[1].tree_crawl(TreeCrawlRequest { tag })).unwrap();
+        let shares: [CountShares<F>; 2] = [v0.decode(), v1.decode()];
+        verify_sketches(&c, tag, nclients, &dealer, &shares).await;
+
+        let nodes = shares[0].counts.len();
+        let (counts, suspects) =
+            open_counts(nclients, nodes, &shares[0].counts, &shares[1].counts, vec![]);
+        assert!(suspects.is_empty());
+        let keep = KeyCollection::<F, F>::keep_values(&threshold, &counts);
+        let req = TreePruneRequest { tag, keep };
+        try_join!(c[0].tree_prune(req.clone()), c[1].tree_prune(req)).unwrap();
+    }
+
+    let tag = Tag::new(collection, data_len - 1, 0);
+    let (v0, v1) = try_join!(
+        c[0].tree_crawl_last(TreeCrawlLastRequest { tag }),
+        c[1].tree_crawl_last(TreeCrawlLastRequest { tag })
+    )
+    .unwrap();
+
+    let shares: [CountShares<F>; 2] = [v0.decode(), v1.decode()];
+    let req = TreeSketchFrontierLastRequest { tag, start: 0, end: nclients, seed: prg::PrgSeed::random() };
+    let (s0, s1) =
+        try_join!(c[0].tree_sketch_frontier_last(req.clone()), c[1].tree_sketch_frontier_last(req)).unwrap();
+    let cor = mpc::ManyMulState::<F>::cors(&s0.decode(), &s1.decode());
+    let req = TreeOutSharesLastRequest { tag, cor: Payload::encode(&cor) };
+    let (o0, o1) = try_join!(c[0].tree_out_shares_last(req.clone()), c[1].tree_out_shares_last(req)).unwrap();
+    for v in mpc::ManyMulState::<F>::verify(&o0.decode(), &o1.decode()) {
+        assert!(v);
+    }
+
+    let nodes = shares[0].counts.len();
+    let (counts, suspects) =
+        open_counts(nclients, nodes, &shares[0].counts, &shares[1].counts, vec![]);
+    assert!(suspects.is_empty());
+    let keep = KeyCollection::<F, F>::keep_values_last(&threshold, &counts);
+    let req = TreePruneLastRequest { tag, keep };
+    try_join!(c[0].tree_prune_last(req.clone()), c[1].tree_prune_last(req)).unwrap();
+
+    let (f0, f1) =
+        try_join!(c[0].final_shares(FinalSharesRequest { tag }), c[1].final_shares(FinalSharesRequest { tag })).unwrap();
+    let mut res = KeyCollection::<F, F>::final_values(&f0.decode::<Vec<Result<F>>>(), &f1.decode::<Vec<Result<F>>>());
+    res.sort_by_key(|r| bits_to_string(&r.path));
+
+    assert_eq!(res.len(), 2);
+    assert_eq!(bits_to_string(&res[0].path), "ab");
+    assert_eq!(res[0].value, F::from(3u32));
+    assert_eq!(bits_to_string(&res[1].path), "cd");
+    assert_eq!(res[1].value, F::from(2u32));
+}
+
+#[tokio::test]
+async fn http_bad_request() {
+    let c = start_servers(16);
+
+    // A body that is not a ResetRequest.
+    let raw = hyper::Client::new();
+    let uri = format!("http://{}/v1/reset", c[0].addr());
+    let resp = raw
+        .request(hyper::Request::post(uri).body(hyper::Body::from(vec![1u8])).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+
+    // The server still works afterwards.
+    let req = ResetRequest { fields: FIELDS, collection: 1 };
+    assert_eq!(c[0].reset(req).await.unwrap(), "Done");
+}
+
+// The upload front end serves only the calls that clients make, and
+// turns away bodies over its limit.
+#[tokio::test]
+async fn http_upload_api() {
+    let server = server::CollectorServer::new(0, envelope::KeyPair::random(), 16, FIELDS);
+    let (addr, serving) = http::bind(server, &"127.0.0.1:0".parse().unwrap(), http::Api::Upload, 1 << 10).unwrap();
+    tokio::spawn(serving);
+    let c = http::Client::new(addr);
+    c.public_key(PublicKeyRequest {}).await.unwrap();
+
+    let raw = hyper::Client::new();
+    let post = |method: &str, body: Vec<u8>| {
+        let uri = format!("http://{}/v1/{}", addr, method);
+        raw.request(hyper::Request::post(uri).body(hyper::Body::from(body)).unwrap())
+    };
+    let reset = bincode::serialize(&ResetRequest { fields: FIELDS, collection: 1 }).unwrap();
+    assert_eq!(post("reset", reset).await.unwrap().status(), hyper::StatusCode::NOT_FOUND);
+    let resp = post("add_keys", vec![0u8; 4 << 10]).await.unwrap();
+    assert_eq!(resp.status(), hyper::StatusCode::PAYLOAD_TOO_LARGE);
+}
@@ -0,0 +1,421 @@
+use counttree::collect::*;
+use counttree::config::{FieldPair, FieldType};
+use counttree::rpc::*;
+use counttree::sketch::*;
+use counttree::*;
+
+use futures::{future, prelude::*, try_join};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tarpc::server::{BaseChannel, Channel};
+use tokio::task::JoinHandle;
+
+type F = fastfield::FE;
+
+const FIELDS: FieldPair = FieldPair {
+    inner: FieldType::FE62,
+    last: FieldType::FE62,
+};
+
+const POLICY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_secs(10),
+    attempts: 5,
+};
+
+fn free_addr() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+// Serves `server` over tarpc on `addr` until the task is aborted, which
+// drops every connection.
+async fn serve(server: server::CollectorServer, addr: SocketAddr) -> JoinHandle<()> {
+    let incoming = rpc::listen(&addr, DEFAULT_MAX_MESSAGE_LEN).await.unwrap();
+    tokio::spawn(
+        incoming
+            .filter_map(|r| future::ready(r.ok()))
+            .map(BaseChannel::with_defaults)
+            .map(move |channel| channel.execute(server.clone().serve()))
+            .buffer_unordered(10)
+            .for_each(|_| async {}),
+    )
+}
+
+async fn start_servers(data_len: usize) -> [Client; 2] {
+    let mut clients = vec![];
+    for idx in 0..2 {
+        let addr = free_addr();
+        let server = server::CollectorServer::new(idx, envelope::KeyPair::random(), data_len, FIELDS);
+        serve(server, addr).await;
+        clients.push(Client::connect(addr, POLICY, DEFAULT_MAX_MESSAGE_LEN).await.unwrap());
+    }
+    [clients[0].clone(), clients[1].clone()]
+}
+
+// Starts the collection and sends the keys of clients who hold
+// `client_strings`.
+async fn upload(c: &[Client; 2], collection: u64, client_strings: &[&str]) {
+    let req = ResetRequest { fields: FIELDS, collection };
+    try_join!(c[0].reset(req.clone()), c[1].reset(req)).unwrap();
+    send_keys(c, Tag::new(collection, 0, 0), 0, client_strings).await;
+}
+
+// Sends the keys of clients numbered from `first`, who hold
+// `client_strings`.
+async fn send_keys(c: &[Client; 2], tag: Tag, first: usize, client_strings: &[&str]) {
+    let (pk0, pk1) = try_join!(c[0].public_key(PublicKeyRequest {}), c[1].public_key(PublicKeyRequest {})).unwrap();
+
+    let authority = auth::Authority::new(&auth::STAND_IN_KEY);
+    let (mut reports0, mut reports1) = (vec![], vec![]);
+    for (i, s) in client_strings.iter().enumerate() {
+        let token = authority.issue(tag.collection, &format!("client{}", first + i)).unwrap();
+        let keys = SketchDPFKey::<F, F>::gen_from_str(s);
+        let plain0 = bincode::serialize(&keys[0]).unwrap();
+        let plain1 = bincode::serialize(&keys[1]).unwrap();
+        let (r0, r1) = envelope::SealedReport::seal(&[pk0.clone(), pk1.clone()], token, [&plain0, &plain1]).split();
+        reports0.push(r0);
+        reports1.push(r1);
+    }
+
+    // Each upload is sent twice, as by a relay whose first try timed out.
+    for _ in 0..2 {
+        let req0 = AddKeysRequest { tag, reports: reports0.clone() };
+        let req1 = AddKeysRequest { tag, reports: reports1.clone() };
+        try_join!(c[0].add_keys(req0), c[1].add_keys(req1)).unwrap();
+    }
+}
+
+// The counts in a server's reply to a crawl.
+fn counts_of(reply: &Payload) -> Vec<F> {
+    reply.decode::<CountShares<F>>().counts
+}
+
+// Every tagged call is made twice, and the servers answer the second one
+// without running it again.
+#[tokio::test(flavor = "multi_thread")]
+async fn repeated_calls() {
+    let client_strings = ["ab", "ab", "cd", "ab"];
+    let nclients = client_strings.len();
+    let data_len = 16;
+    let c = start_servers(data_len).await;
+
+    let dealer = mpc::DealerKey::random();
+    let [t0, t1] = mpc::deal_triples::<F>(&dealer, 0, TRIPLES_PER_LEVEL * nclients * data_len);
+    for _ in 0..2 {
+        let req0 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t0) };
+        let req1 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t1) };
+        try_join!(c[0].add_triples(req0), c[1].add_triples(req1)).unwrap();
+    }
+    let count = c[0].triple_count(TripleCountRequest { field: FieldType::FE62 }).await.unwrap();
+    assert_eq!(count.available as usize, TRIPLES_PER_LEVEL * nclients * data_len);
+
+    let collection = 9;
+    upload(&c, collection, &client_strings).await;
+
+    let tag = Tag::new(collection, 0, 0);
+    for _ in 0..2 {
+        let (ids0, ids1) =
+            try_join!(c[0].key_ids(KeyIdsRequest { tag }), c[1].key_ids(KeyIdsRequest { tag })).unwrap();
+        assert_eq!(ids0, ids1);
+        assert_eq!(ids0.len(), nclients);
+
+        let req = ReconcileRequest { tag, drop: vec![] };
+        let (n0, n1) = try_join!(c[0].reconcile(req.clone()), c[1].reconcile(req)).unwrap();
+        assert_eq!((n0, n1), (nclients, nclients));
+        try_join!(c[0].tree_init(TreeInitRequest::new(tag)), c[1].tree_init(TreeInitRequest::new(tag))).unwrap();
+    }
+
+    let threshold = F::from(2u32);
+    for level in 0..data_len - 1 {
+        let tag = Tag::new(collection, level, 0);
+        let req = TreeCrawlRequest { tag };
+        let (v0, v1) = try_join!(c[0].tree_crawl(req.clone()), c[1].tree_crawl(req.clone())).unwrap();
+        let again = c[0].tree_crawl(req).await.unwrap();
+        assert_eq!(v0.as_bytes(), again.as_bytes());
+        let shares: [CountShares<F>; 2] = [v0.decode(), v1.decode()];
+        let nodes = shares[0].counts.len();
+
+        let seed = prg::PrgSeed::random();
+        let req = TreeSketchFrontierRequest { tag, start: 0, end: nclients, seed: seed.clone() };
+        let (s0, s1) =
+            try_join!(c[0].tree_sketch_frontier(req.clone()), c[1].tree_sketch_frontier(req.clone())).unwrap();
+        assert_eq!(s0.as_bytes(), c[0].tree_sketch_frontier(req).await.unwrap().as_bytes());
+        let live = vec![true; nclients];
+        let masked = [&s0, &s1].map(|s| mpc::ManyMulState::<F>::masked_r_x(&s.decode(), &live));
+        let masked = [&masked[0], &masked[1]];
+        assert!(misbehaving_servers(&dealer, &seed, nodes, &live, [&shares[0], &shares[1]], masked).is_empty());
+        let cor = mpc::ManyMulState::<F>::cors(&s0.decode(), &s1.decode());
+
+        let req = TreeOutSharesRequest { tag, cor: Payload::encode(&cor) };
+        for _ in 0..2 {
+            let (o0, o1) = try_join!(c[0].tree_out_shares(req.clone()), c[1].tree_out_shares(req.clone())).unwrap();
+            for v in mpc::ManyMulState::<F>::verify(&o0.decode(), &o1.decode()) {
+                assert!(v);
+            }
+        }
+
+        let (counts, suspects) =
+            open_counts(nclients, nodes, &shares[0].counts, &shares[1].counts, vec![]);
+        assert!(suspects.is_empty());
+        let keep = KeyCollection::<F, F>::keep_values(&threshold, &counts);
+        let req = TreePruneRequest { tag, keep };
+        for _ in 0..2 {
+            try_join!(c[0].tree_prune(req.clone()), c[1].tree_prune(req.clone())).unwrap();
+        }
+    }
+
+    let tag = Tag::new(collection, data_len - 1, 0);
+    let req = TreeCrawlLastRequest { tag };
+    let (v0, v1) = try_join!(c[0].tree_crawl_last(req.clone()), c[1].tree_crawl_last(req.clone())).unwrap();
+    assert_eq!(v1.as_bytes(), c[1].tree_crawl_last(req).await.unwrap().as_bytes());
+
+    let shares: [CountShares<F>; 2] = [v0.decode(), v1.decode()];
+    let req = TreeSketchFrontierLastRequest { tag, start: 0, end: nclients, seed: prg::PrgSeed::random() };
+    let (s0, s1) =
+        try_join!(c[0].tree_sketch_frontier_last(req.clone()), c[1].tree_sketch_frontier_last(req)).unwrap();
+    let cor = mpc::ManyMulState::<F>::cors(&s0.decode(), &s1.decode());
+    let req = TreeOutSharesLastRequest { tag, cor: Payload::encode(&cor) };
+    for _ in 0..2 {
+        let (o0, o1) =
+            try_join!(c[0].tree_out_shares_last(req.clone()), c[1].tree_out_shares_last(req.clone())).unwrap();
+        for v in mpc::ManyMulState::<F>::verify(&o0.decode(), &o1.decode()) {
+            assert!(v);
+        }
+    }
+
+    let nodes = shares[0].counts.len();
+    let (counts, suspects) =
+        open_counts(nclients, nodes, &shares[0].counts, &shares[1].counts, vec![]);
+    assert!(suspects.is_empty());
+    let keep = KeyCollection::<F, F>::keep_values_last(&threshold, &counts);
+    let req = TreePruneLastRequest { tag, keep };
+    for _ in 0..2 {
+        try_join!(c[0].tree_prune_last(req.clone()), c[1].tree_prune_last(req.clone())).unwrap();
+    }
+
+    let req = FinalSharesRequest { tag };
+    let (f0, f1) = try_join!(c[0].final_shares(req.clone()), c[1].final_shares(req)).unwrap();
+    let res = KeyCollection::<F, F>::final_values(&f0.decode::<Vec<Result<F>>>(), &f1.decode::<Vec<Result<F>>>());
+    assert_eq!(res.len(), 1);
+    assert_eq!(bits_to_string(&res[0].path), "ab");
+    assert_eq!(res[0].value, F::from(3u32));
+}
+
+// Keys that fail a sketch check stop counting from the next crawl on, or
+// at the level already crawled once it is counted again, and both servers
+// report the clients left.
+#[tokio::test(flavor = "multi_thread")]
+async fn rejected_keys() {
+    let nclients = 3;
+    let c = start_servers(16).await;
+    // The recount takes triples for the level's second sketch.
+    let [t0, t1] = mpc::deal_triples::<F>(&mpc::DealerKey::random(), 0, TRIPLES_PER_LEVEL * nclients * 3);
+    let req0 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t0) };
+    let req1 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t1) };
+    try_join!(c[0].add_triples(req0), c[1].add_triples(req1)).unwrap();
+
+    let collection = 4;
+    upload(&c, collection, &["ab"; 3]).await;
+    let tag = Tag::new(collection, 0, 0);
+    try_join!(c[0].key_ids(KeyIdsRequest { tag }), c[1].key_ids(KeyIdsRequest { tag })).unwrap();
+    let req = ReconcileRequest { tag, drop: vec![] };
+    try_join!(c[0].reconcile(req.clone()), c[1].reconcile(req)).unwrap();
+    try_join!(c[0].tree_init(TreeInitRequest::new(tag)), c[1].tree_init(TreeInitRequest::new(tag))).unwrap();
+
+    let req = TreeCrawlRequest { tag };
+    try_join!(c[0].tree_crawl(req.clone()), c[1].tree_crawl(req)).unwrap();
+    let req = TreeRejectRequest { tag, keys: vec![0] };
+    for _ in 0..2 {
+        let live = try_join!(c[0].tree_reject(req.clone()), c[1].tree_reject(req.clone())).unwrap();
+        assert_eq!(live, (2, 2));
+    }
+    let req = TreeRecountRequest { tag };
+    let (v0, v1) = try_join!(c[0].tree_recount(req.clone()), c[1].tree_recount(req)).unwrap();
+    let (counts, suspects) = open_counts(2, 2, &counts_of(&v0), &counts_of(&v1), vec![]);
+    assert!(suspects.is_empty());
+    assert!(counts.contains(&F::from(2u32)));
+    let req = TreePruneRequest { tag, keep: vec![true, true] };
+    try_join!(c[0].tree_prune(req.clone()), c[1].tree_prune(req)).unwrap();
+
+    let req = TreeCrawlRequest { tag: Tag::new(collection, 1, 0) };
+    let (v0, v1) = try_join!(c[0].tree_crawl(req.clone()), c[1].tree_crawl(req)).unwrap();
+    let (counts, suspects) = open_counts(2, 4, &counts_of(&v0), &counts_of(&v1), vec![]);
+    assert!(suspects.is_empty());
+    assert!(counts.contains(&F::from(2u32)));
+}
+
+// Calls out of order or with bad payloads are refused, and the server
+// goes on with the collection.
+#[tokio::test(flavor = "multi_thread")]
+async fn refused_calls() {
+    let nclients = 2;
+    let c = start_servers(16).await;
+    let collection = 7;
+    upload(&c, collection, &["ab"; 2]).await;
+    let tag = Tag::new(collection, 0, 0);
+    try_join!(c[0].key_ids(KeyIdsRequest { tag }), c[1].key_ids(KeyIdsRequest { tag })).unwrap();
+    let req = ReconcileRequest { tag, drop: vec![] };
+    try_join!(c[0].reconcile(req.clone()), c[1].reconcile(req)).unwrap();
+    try_join!(c[0].tree_init(TreeInitRequest::new(tag)), c[1].tree_init(TreeInitRequest::new(tag))).unwrap();
+
+    let crawl = TreeCrawlRequest { tag };
+    let err = c[0].tree_crawl(crawl.clone()).await.unwrap_err();
+    assert!(err.to_string().contains("Out of Beaver triples"), "{}", err);
+
+    let [t0, t1] = mpc::deal_triples::<F>(&mpc::DealerKey::random(), 0, TRIPLES_PER_LEVEL * nclients);
+    let req0 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t0) };
+    let req1 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t1) };
+    try_join!(c[0].add_triples(req0), c[1].add_triples(req1)).unwrap();
+
+    let err = c[0].tree_crawl(TreeCrawlRequest { tag: Tag::new(collection, 1, 0) }).await.unwrap_err();
+    assert!(err.to_string().contains("out of order"), "{}", err);
+    let (v0, v1) = try_join!(c[0].tree_crawl(crawl.clone()), c[1].tree_crawl(crawl)).unwrap();
+
+    let seed = prg::PrgSeed::random();
+    let next = Tag::new(collection, 1, 0);
+    let sketch = TreeSketchFrontierRequest { tag: next, start: 0, end: nclients, seed: seed.clone() };
+    assert!(c[0].tree_sketch_frontier(sketch).await.is_err());
+    let sketch = TreeSketchFrontierRequest { tag, start: 0, end: nclients + 1, seed: seed.clone() };
+    assert!(c[0].tree_sketch_frontier(sketch).await.is_err());
+    let sketch = TreeSketchFrontierRequest { tag, start: 0, end: nclients, seed };
+    let (s0, s1) = try_join!(c[0].tree_sketch_frontier(sketch.clone()), c[1].tree_sketch_frontier(sketch)).unwrap();
+    let cor = mpc::ManyMulState::<F>::cors(&s0.decode(), &s1.decode());
+
+    let bad = [Payload::from_bytes(vec![0xff; 3]), Payload::encode(&mpc::ManyMulState::<F>::zero().cor_shares())];
+    for cor in bad {
+        let err = c[0].tree_out_shares(TreeOutSharesRequest { tag, cor }).await.unwrap_err();
+        assert!(err.to_string().contains("batch 0"), "{}", err);
+    }
+    let unknown = TreeOutSharesRequest { tag: Tag::new(collection, 0, 5), cor: Payload::encode(&cor) };
+    assert!(c[0].tree_out_shares(unknown).await.is_err());
+    let req = TreeOutSharesRequest { tag, cor: Payload::encode(&cor) };
+    let (o0, o1) = try_join!(c[0].tree_out_shares(req.clone()), c[1].tree_out_shares(req)).unwrap();
+    assert!(mpc::ManyMulState::<F>::verify(&o0.decode(), &o1.decode()).into_iter().all(|v| v));
+
+    let (counts, suspects) = open_counts(nclients, 2, &counts_of(&v0), &counts_of(&v1), vec![]);
+    assert!(suspects.is_empty());
+    assert!(counts.contains(&F::from(2u32)));
+}
+
+// Keys that do not decode, or that are for strings of another length,
+// are dropped, and the server goes on taking keys.
+#[tokio::test(flavor = "multi_thread")]
+async fn malformed_keys() {
+    let c = start_servers(16).await;
+    let collection = 6;
+    upload(&c, collection, &["ab"; 2]).await;
+
+    let (pk0, pk1) = try_join!(c[0].public_key(PublicKeyRequest {}), c[1].public_key(PublicKeyRequest {})).unwrap();
+    let authority = auth::Authority::new(&auth::STAND_IN_KEY);
+    let long = SketchDPFKey::<F, F>::gen_from_str("abc");
+    let plains = [vec![0xff; 40], bincode::serialize(&long[0]).unwrap()];
+    let (mut reports0, mut reports1) = (vec![], vec![]);
+    for (i, plain) in plains.iter().enumerate() {
+        let token = authority.issue(collection, &format!("bad{}", i)).unwrap();
+        let (r0, r1) = envelope::SealedReport::seal(&[pk0.clone(), pk1.clone()], token, [plain, plain]).split();
+        reports0.push(r0);
+        reports1.push(r1);
+    }
+    let tag = Tag::new(collection, 0, 1);
+    let req0 = AddKeysRequest { tag, reports: reports0 };
+    let req1 = AddKeysRequest { tag, reports: reports1 };
+    try_join!(c[0].add_keys(req0), c[1].add_keys(req1)).unwrap();
+
+    send_keys(&c, Tag::new(collection, 0, 2), 2, &["cd"]).await;
+    let tag = Tag::new(collection, 0, 0);
+    let (ids0, ids1) = try_join!(c[0].key_ids(KeyIdsRequest { tag }), c[1].key_ids(KeyIdsRequest { tag })).unwrap();
+    assert_eq!(ids0, ids1);
+    assert_eq!(ids0.len(), 3);
+}
+
+// Anyone may call add_keys, so an empty batch sent ahead with the tag of
+// a real one must not stand in for it.
+#[tokio::test(flavor = "multi_thread")]
+async fn add_keys_not_cached() {
+    let c = start_servers(16).await;
+    let collection = 8;
+    upload(&c, collection, &[]).await;
+
+    let tag = Tag::new(collection, 0, 1);
+    let empty = AddKeysRequest { tag, reports: vec![] };
+    try_join!(c[0].add_keys(empty.clone()), c[1].add_keys(empty)).unwrap();
+    send_keys(&c, tag, 0, &["ab", "cd"]).await;
+
+    let tag = Tag::new(collection, 0, 0);
+    let (ids0, ids1) = try_join!(c[0].key_ids(KeyIdsRequest { tag }), c[1].key_ids(KeyIdsRequest { tag })).unwrap();
+    assert_eq!(ids0, ids1);
+    assert_eq!(ids0.len(), 2);
+}
+
+// Keys uploaded in a later round make up a new epoch, which can be
+// crawled alone or together with the epochs before it.
+#[tokio::test(flavor = "multi_thread")]
+async fn epochs() {
+    let c = start_servers(16).await;
+    let [t0, t1] = mpc::deal_triples::<F>(&mpc::DealerKey::random(), 0, TRIPLES_PER_LEVEL * 8);
+    let req0 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t0) };
+    let req1 = AddTriplesRequest { field: FieldType::FE62, first: 0, triples: Payload::encode(&t1) };
+    try_join!(c[0].add_triples(req0), c[1].add_triples(req1)).unwrap();
+
+    let collection = 5;
+    upload(&c, collection, &["ab"; 2]).await;
+    for (round, n) in [(0, 2), (1, 3)] {
+        let tag = Tag::new(collection, 0, 0).in_round(round);
+        if round > 0 {
+            send_keys(&c, tag, 2, &["cd"; 3]).await;
+        }
+        try_join!(c[0].key_ids(KeyIdsRequest { tag }), c[1].key_ids(KeyIdsRequest { tag })).unwrap();
+        let req = ReconcileRequest { tag, drop: vec![] };
+        assert_eq!(try_join!(c[0].reconcile(req.clone()), c[1].reconcile(req)).unwrap(), (n, n));
+    }
+
+    let tag = Tag::new(collection, 0, 0).in_round(1);
+    let req = TreeInitRequest { tag, epochs: Some(1..2), prefixes: vec![vec![]] };
+    let keys = try_join!(c[0].tree_init(req.clone()), c[1].tree_init(req)).unwrap();
+    assert_eq!(keys.0, CrawlKeys { keys: 3, dead: vec![] });
+
+    // A merge that starts below "c" reads both epochs from level 8.
+    let tag = Tag::new(collection, 0, 0).in_round(2);
+    let req = TreeInitRequest { tag, epochs: Some(0..2), prefixes: vec![string_to_bits("c")] };
+    let keys = try_join!(c[0].tree_init(req.clone()), c[1].tree_init(req)).unwrap();
+    assert_eq!(keys.0, CrawlKeys { keys: 5, dead: vec![] });
+    let req = TreeCrawlRequest { tag: Tag::new(collection, 8, 0).in_round(2) };
+    let (v0, v1) = try_join!(c[0].tree_crawl(req.clone()), c[1].tree_crawl(req)).unwrap();
+    let (counts, suspects) = open_counts(5, 2, &counts_of(&v0), &counts_of(&v1), vec![]);
+    assert!(suspects.is_empty());
+    assert_eq!(counts, [F::from(3u32), F::zero()]);
+}
+
+// A call that fails because the connection dropped goes through once the
+// server is back.
+#[tokio::test]
+async fn reconnect() {
+    let addr = free_addr();
+    let server = server::CollectorServer::new(0, envelope::KeyPair::random(), 16, FIELDS);
+    let serving = serve(server.clone(), addr).await;
+    let client = Client::connect(addr, POLICY, DEFAULT_MAX_MESSAGE_LEN).await.unwrap();
+    let req = ResetRequest { fields: FIELDS, collection: 3 };
+    assert_eq!(client.reset(req).await.unwrap(), "Done");
+
+    serving.abort();
+    assert!(serving.await.unwrap_err().is_cancelled());
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        serve(server, addr).await;
+    });
+
+    let tag = Tag::new(3, 0, 0);
+    assert!(client.key_ids(KeyIdsRequest { tag }).await.unwrap().is_empty());
+}
+
+// A server that restarted has lost the collection, and says so.
+#[tokio::test]
+async fn wrong_collection() {
+    let addr = free_addr();
+    let server = server::CollectorServer::new(0, envelope::KeyPair::random(), 16, FIELDS);
+    serve(server, addr).await;
+    let client = Client::connect(addr, POLICY, DEFAULT_MAX_MESSAGE_LEN).await.unwrap();
+
+    let err = client.tree_init(TreeInitRequest::new(Tag::new(3, 0, 0))).await.unwrap_err();
+    assert!(err.to_string().contains("collection 3"), "{}", err);
+}
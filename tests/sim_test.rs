@@ -0,0 +1,388 @@
+use counttree::fastfield::FE;
+use counttree::input::Source;
+use counttree::sim::*;
+use counttree::*;
+
+use std::fs;
+
+fn new_sim(input: Input) -> Sim {
+    Sim {
+        data_len: 16,
+        threshold: 0.1,
+        input,
+        bad_clients: vec![],
+        bad_servers: vec![],
+        policy: Default::default(),
+        order: Default::default(),
+    }
+}
+
+#[test]
+fn zipf_matches_oracle() {
+    let sim = new_sim(Input::Zipf {
+        num_sites: 10,
+        exponent: 1.0,
+        nclients: 200,
+    });
+    let (pop, outcome) = sim.run::<FE, FieldElm255>().unwrap();
+    let strings = pop.strings();
+    let expected = sim.expected(&strings);
+    assert!(!expected.is_empty());
+    assert!(outcome.matches(&expected));
+    assert_eq!(outcome.rejected, 0);
+    assert!(outcome.suspects.is_empty());
+}
+
+#[test]
+fn uniform_matches_oracle() {
+    let sim = new_sim(Input::Uniform {
+        num_sites: 5,
+        nclients: 100,
+    });
+    let (pop, outcome) = sim.run::<FieldElm127, FieldElm127>().unwrap();
+    let strings = pop.strings();
+    assert!(outcome.matches(&sim.expected(&strings)));
+}
+
+#[test]
+fn file_input() {
+    let dir = std::env::temp_dir();
+    let lines = dir.join(format!("counttree-sim-{}.txt", std::process::id()));
+    let counts = dir.join(format!("counttree-sim-{}.csv", std::process::id()));
+
+    // Longer strings are cut to data_len bits, so "abx" counts as "ab".
+    fs::write(&lines, "ab\ncd\nab\nabx\nef\ncde\n").unwrap();
+    let sim = Sim {
+        threshold: 0.4,
+        ..new_sim(Input::File(Source::Lines(lines.clone())))
+    };
+    let (pop, outcome) = sim.run::<FE, FE>().unwrap();
+    let strings = pop.strings();
+    assert_eq!(strings.len(), 6);
+    assert_eq!(outcome.heavy.len(), 2);
+    assert_eq!(input::decode(&outcome.heavy[0].path), "ab");
+    assert_eq!(outcome.heavy[0].value, FE::from(3u32));
+    assert_eq!(input::decode(&outcome.heavy[1].path), "cd");
+    assert!(outcome.matches(&sim.expected(&strings)));
+
+    // Shorter ones are padded, and decode without the padding.
+    fs::write(&counts, "a,5\nb,1\n\"c,\",4\n").unwrap();
+    let sim = Sim {
+        threshold: 0.3,
+        ..new_sim(Input::File(Source::Counts(counts.clone())))
+    };
+    let (pop, outcome) = sim.run::<FE, FE>().unwrap();
+    let strings = pop.strings();
+    assert_eq!(strings.len(), 10);
+    let heavy: Vec<String> = outcome.heavy.iter().map(|r| input::decode(&r.path)).collect();
+    assert_eq!(heavy, ["a", "c,"]);
+    assert!(outcome.matches(&sim.expected(&strings)));
+
+    fs::remove_file(&lines).unwrap();
+    fs::remove_file(&counts).unwrap();
+}
+
+// Under the first-field order, each country's URLs need only a fraction
+// of that country's clients, so a small country's URLs come out too.
+#[test]
+fn tuples_within_first_field() {
+    let file = std::env::temp_dir().join(format!("counttree-tuples-{}.csv", std::process::id()));
+    fs::write(&file, "a,x,6\na,y,4\nb,u,2\nb,v,2\nb,w,2\nb,z,2\nc,x,2\n").unwrap();
+    let fields: Vec<input::Field> = ["country", "url"]
+        .iter()
+        .map(|name| input::Field {
+            name: name.to_string(),
+            bytes: 1,
+        })
+        .collect();
+    let sim = Sim {
+        threshold: 0.2,
+        ..new_sim(Input::Tuples(Source::Counts(file.clone()), fields.clone()))
+    };
+    let tuples = |outcome: &Outcome<FE>| -> Vec<String> {
+        let mut tuples: Vec<String> =
+            outcome.heavy.iter().map(|r| input::decode_tuple(&r.path, &fields).concat()).collect();
+        tuples.sort();
+        tuples
+    };
+
+    // Jointly, a pair needs 4 of the 20 clients.
+    let (pop, outcome) = sim.run::<FE, FE>().unwrap();
+    assert_eq!(tuples(&outcome), ["ax", "ay"]);
+    assert!(outcome.matches(&sim.expected(&pop.strings())));
+
+    // Countries still need 4 clients, which leaves out "c". Then "a"'s
+    // URLs need 2 of its 10 clients, and "b"'s 1 of its 8.
+    let sim = Sim {
+        order: CrawlOrder::Within(8),
+        ..sim
+    };
+    let (pop, outcome) = sim.run::<FE, FE>().unwrap();
+    assert_eq!(tuples(&outcome), ["ax", "ay", "bu", "bv", "bw", "bz"]);
+    assert!(outcome.matches(&sim.expected(&pop.strings())));
+    let report = sim.report(&pop, &outcome);
+    assert_eq!((report.found, report.expected, report.correct), (6, 6, 6));
+    fs::remove_file(&file).unwrap();
+}
+
+// Strings that are cut to fit count towards their prefix, and the report
+// says how many heavy ones there were and what else the leader saw.
+#[test]
+fn report() {
+    let file = std::env::temp_dir().join(format!("counttree-report-{}.txt", std::process::id()));
+    fs::write(&file, "home\nhome\nhome\nhomer\nnews\nnews\nnewt\nxy\n").unwrap();
+    let sim = Sim {
+        threshold: 0.25,
+        ..new_sim(Input::File(Source::Lines(file.clone())))
+    };
+    let (pop, outcome) = sim.run::<FE, FE>().unwrap();
+    let heavy: Vec<String> = outcome.heavy.iter().map(|r| input::decode(&r.path)).collect();
+    assert_eq!(heavy, ["ho", "ne"]);
+
+    let report = sim.report(&pop, &outcome);
+    assert_eq!((report.found, report.expected, report.correct), (2, 2, 2));
+    assert_eq!(report.cut_heavy, 2);
+    assert_eq!(report.levels.len(), sim.data_len);
+    assert_eq!(report.levels[0].frontier, 2);
+    assert_eq!(report.levels[sim.data_len - 1].heavy, 2);
+
+    // Only "xy" strays from the heavy prefixes, at its fifth bit.
+    assert_eq!(report.revealed_prefixes(), 1);
+    assert_eq!(report.levels[4].light, 1);
+    fs::remove_file(&file).unwrap();
+}
+
+// Malformed keys fail the sketch check and do not count.
+#[test]
+fn bad_clients_rejected() {
+    let mut sim = new_sim(Input::Zipf {
+        num_sites: 4,
+        exponent: 1.0,
+        nclients: 40,
+    });
+    let target = string_to_bits("zz");
+    sim.bad_clients = vec![
+        (target.clone(), ClientAttack::Weight(2)),
+        (target.clone(), ClientAttack::Weight(3)),
+    ];
+    let (pop, outcome) = sim.run::<FE, FE>().unwrap();
+    let strings = pop.strings();
+    assert_eq!(outcome.rejected, 2);
+    assert!(outcome.matches(&sim.expected(&strings)));
+    assert!(outcome.heavy.iter().all(|r| r.path != target));
+}
+
+// Once the sketch checks catch the bad clients, the threshold is a
+// fraction of the honest clients alone. Counting the bad clients in would
+// put "ab" below it.
+#[test]
+fn threshold_follows_live_clients() {
+    let mut sim = new_sim(Input::Uniform {
+        num_sites: 1,
+        nclients: 0,
+    });
+    sim.threshold = 0.2;
+    let mut strings = vec![string_to_bits("ab"); 3];
+    strings.extend(vec![string_to_bits("cd"); 7]);
+
+    // The target shares no first bit with the honest strings, so the
+    // inflated counts stay within the number of clients.
+    let mut target = string_to_bits("ab");
+    target[0] = !target[0];
+    sim.bad_clients = vec![(target, ClientAttack::Weight(2)); 10];
+
+    let outcome = sim.run_on::<FE, FE>(&strings);
+    assert_eq!(outcome.rejected, 10);
+    assert!(outcome.suspects.is_empty());
+    let heavy: Vec<String> = outcome.heavy.iter().map(|r| input::decode(&r.path)).collect();
+    assert_eq!(heavy, ["ab", "cd"]);
+    assert!(outcome.matches(&sim.expected(&strings)));
+}
+
+// A server can shift its share of the count at any node of its crawl
+// output, but not its shares of the level's sketches to match, so the
+// leader names it and stops with no output.
+#[test]
+fn bad_server_detected() {
+    let mut sim = new_sim(Input::Uniform {
+        num_sites: 1,
+        nclients: 0,
+    });
+    let strings: Vec<Vec<bool>> = ["ab", "ab", "ab", "cd"].iter().map(|s| string_to_bits(s)).collect();
+    sim.threshold = 0.5;
+
+    // At the last level, node 0 is "ab" and node 1 its sibling, which
+    // no client holds.
+    let last = sim.data_len - 1;
+    sim.bad_servers = vec![ServerAttack {
+        server: 1,
+        level: last,
+        node: 1,
+        by: 2,
+        accomplice: None,
+    }];
+    let outcome = sim.run_on::<FE, FE>(&strings);
+    assert_eq!(outcome.suspects.len(), 1);
+    let (level, suspects) = &outcome.suspects[0];
+    assert_eq!((*level, &suspects.servers[..]), (last, &[1][..]));
+    assert_eq!(suspects.blame(), "server 1's counts do not match its shares of the sketches");
+    assert_eq!(suspects.to_string(), format!("all {} nodes are suspect", suspects.frontier));
+    assert!(outcome.heavy.is_empty());
+    assert_eq!(outcome.levels.len(), last + 1);
+
+    // Likewise at an inner level, over the other field. Every node of
+    // the level is then suspect.
+    sim.bad_servers = vec![ServerAttack {
+        server: 0,
+        level: 3,
+        node: 0,
+        by: 100,
+        accomplice: None,
+    }];
+    let outcome = sim.run_on::<FieldElm127, FE>(&strings);
+    assert_eq!(outcome.suspects[0].0, 3);
+    assert_eq!(outcome.suspects[0].1.servers, [0]);
+    assert_eq!(outcome.levels[3].suspect, outcome.levels[3].frontier);
+    assert!(outcome.heavy.is_empty());
+
+    sim.bad_servers.clear();
+    let outcome = sim.run_on::<FE, FE>(&strings);
+    assert!(outcome.suspects.is_empty());
+    assert!(outcome.matches(&sim.expected(&strings)));
+}
+
+// A server that colludes with a client can move its share of the
+// client's sketch to cover a shifted count, and make the sketch open as a
+// valid one, so no check names it. Only a shift past the number of live
+// clients shows. A client whose key has been rejected cannot cover for
+// it.
+#[test]
+fn colluding_server() {
+    let mut sim = new_sim(Input::Uniform {
+        num_sites: 1,
+        nclients: 0,
+    });
+    let strings: Vec<Vec<bool>> = ["ab", "ab", "ab", "cd", "cd"].iter().map(|s| string_to_bits(s)).collect();
+    sim.threshold = 0.3;
+
+    // At the last level, node 0 is "ab" and node 2 is "cd".
+    let last = sim.data_len - 1;
+    let mut attack = ServerAttack {
+        server: 1,
+        level: last,
+        node: 2,
+        by: 1,
+        accomplice: Some(0),
+    };
+    sim.bad_servers = vec![attack.clone()];
+    let outcome = sim.run_on::<FE, FE>(&strings);
+    assert!(outcome.suspects.is_empty());
+    assert_eq!(outcome.rejected, 0);
+    let counts: Vec<(String, FE)> = outcome.heavy.iter().map(|r| (input::decode(&r.path), r.value)).collect();
+    assert_eq!(counts, [("ab".to_string(), FE::from(3u32)), ("cd".to_string(), FE::from(3u32))]);
+
+    attack.by = 10;
+    sim.bad_servers = vec![attack.clone()];
+    let outcome = sim.run_on::<FE, FE>(&strings);
+    let (level, suspects) = &outcome.suspects[0];
+    assert_eq!((*level, &suspects.over_count[..]), (last, &[2][..]));
+    assert!(suspects.servers.is_empty());
+    assert!(suspects.blame().contains("colludes with a client"));
+    assert!(outcome.heavy.is_empty());
+
+    // The accomplice's halves do not match, so its key is rejected at the
+    // first level.
+    sim.bad_clients = vec![(string_to_bits("zz"), ClientAttack::Mismatched)];
+    attack.by = 1;
+    attack.accomplice = Some(strings.len());
+    sim.bad_servers = vec![attack];
+    let outcome = sim.run_on::<FE, FE>(&strings);
+    assert_eq!(outcome.rejected, 1);
+    assert_eq!(outcome.suspects[0].1.servers, [1]);
+    assert!(outcome.heavy.is_empty());
+}
+
+// A client whose key halves do not match spoils every count at the first
+// level. Its sketch check fails, so the leader drops it and counts the
+// level again before judging, and the honest heavy hitters come out.
+#[test]
+fn mismatched_client_dropped() {
+    let mut sim = new_sim(Input::Uniform {
+        num_sites: 2,
+        nclients: 20,
+    });
+    sim.bad_clients = vec![(string_to_bits("zz"), ClientAttack::Mismatched)];
+    let (pop, outcome) = sim.run::<FE, FE>().unwrap();
+    assert_eq!(outcome.rejected, 1);
+    assert!(outcome.suspects.is_empty());
+    let expected = sim.expected(&pop.strings());
+    assert!(!expected.is_empty());
+    assert!(outcome.matches(&expected));
+    assert!(outcome.levels.iter().all(|l| l.suspect == 0));
+}
+
+// A key that passes every inner level but claims a weight at the last is
+// caught by the last level's check, which the leader waits for before it
+// opens the output counts.
+#[test]
+fn last_level_rejects_before_output() {
+    let mut sim = new_sim(Input::Uniform {
+        num_sites: 1,
+        nclients: 0,
+    });
+    sim.threshold = 0.3;
+    let strings: Vec<Vec<bool>> = ["ab", "ab", "ab", "cd", "cd"].iter().map(|s| string_to_bits(s)).collect();
+    sim.bad_clients = vec![(string_to_bits("cd"), ClientAttack::LastWeight(3))];
+
+    let outcome = sim.run_on::<FE, FieldElm255>(&strings);
+    assert_eq!(outcome.rejected, 1);
+    assert!(outcome.suspects.is_empty());
+    assert!(outcome.matches(&sim.expected(&strings)));
+}
+
+// A client that inflates a count past the number of clients is dropped by
+// its sketch check. A server that does the same to the same node, and
+// covers for it with an accomplice's sketch, is not, so under quarantine
+// the crawl drops the node and finds the heavy hitters elsewhere.
+#[test]
+fn quarantine_drops_suspect_nodes() {
+    let mut sim = new_sim(Input::Uniform {
+        num_sites: 1,
+        nclients: 0,
+    });
+    sim.threshold = 0.3;
+    sim.policy = collect::Policy::Quarantine;
+    let strings: Vec<Vec<bool>> = ["ab", "ab", "ab", "cd", "cd"].iter().map(|s| string_to_bits(s)).collect();
+
+    // The target shares no first bit with the honest strings.
+    let mut target = string_to_bits("ab");
+    target[0] = !target[0];
+    let node = target[0] as usize;
+    sim.bad_clients = vec![(target, ClientAttack::Weight(1000))];
+    let outcome = sim.run_on::<FE, FE>(&strings);
+    assert_eq!(outcome.rejected, 1);
+    assert!(outcome.suspects.is_empty());
+    assert!(outcome.matches(&sim.expected(&strings)));
+
+    sim.bad_servers = vec![ServerAttack {
+        server: 0,
+        level: 0,
+        node,
+        by: 1000,
+        accomplice: Some(0),
+    }];
+    let outcome = sim.run_on::<FE, FE>(&strings);
+    assert_eq!(outcome.rejected, 1);
+    assert_eq!(outcome.suspects.len(), 1);
+    assert_eq!(outcome.suspects[0].0, 0);
+    assert!(outcome.suspects[0].1.servers.is_empty());
+    assert_eq!(outcome.suspects[0].1.over_count, [node]);
+    assert!(outcome.matches(&sim.expected(&strings)));
+
+    let pop = input::Population {
+        sites: vec![string_to_bits("ab"), string_to_bits("cd")],
+        clients: vec![0, 0, 0, 1, 1],
+        cut: Default::default(),
+    };
+    assert_eq!(sim.report(&pop, &outcome).suspect_nodes(), 1);
+}